@@ -0,0 +1,50 @@
+//! Runs before the sign's firmware: swaps in an update that `ota` marked,
+//! or swaps the previous image back when an update wasn't confirmed, then
+//! jumps to the ACTIVE partition. The watchdog runs from here on, fed on
+//! every flash operation during a swap and by `watchdog::run` once the
+//! firmware is up, so a new image that hangs is restarted and rolled back.
+
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use cortex_m_rt::{entry, exception};
+use embassy_boot_rp::{BootLoader, BootLoaderConfig, WatchdogFlash};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+
+/// `FLASH_SIZE` in src/config.rs
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+#[entry]
+fn main() -> ! {
+    let p = embassy_rp::init(Default::default());
+
+    // `TIMEOUT` in src/watchdog.rs
+    let flash = WatchdogFlash::<FLASH_SIZE>::start(p.FLASH, p.WATCHDOG, Duration::from_secs(8));
+    let flash: Mutex<NoopRawMutex, _> = Mutex::new(RefCell::new(flash));
+
+    let config = BootLoaderConfig::from_linkerfile_blocking(&flash);
+    let active_offset = config.active.offset();
+    let bootloader: BootLoader = BootLoader::prepare(config);
+
+    unsafe { bootloader.load(embassy_rp::flash::FLASH_BASE as u32 + active_offset) }
+}
+
+#[no_mangle]
+#[cfg_attr(target_os = "none", link_section = ".HardFault.user")]
+unsafe extern "C" fn HardFault() {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[exception]
+unsafe fn DefaultHandler(_: i16) -> ! {
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    cortex_m::peripheral::SCB::sys_reset();
+}
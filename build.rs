@@ -7,11 +7,19 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also reads the sign definition from `sign.toml` (or the file named
+//! by `SIGN_TOML`), checks it, and writes it out as the `CONFIG` constant
+//! included by `src/sign.rs`.
 
+use serde::Deserialize;
 use std::env;
-use std::fs::File;
+use std::fmt::Write as _;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::process;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -33,4 +41,1099 @@ fn main() {
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+
+    let path = env::var("SIGN_TOML").unwrap_or_else(|_| String::from("sign.toml"));
+    println!("cargo:rerun-if-env-changed=SIGN_TOML");
+    println!("cargo:rerun-if-changed={path}");
+    // keep the WiFi credentials out of a checked in sign.toml
+    println!("cargo:rerun-if-env-changed=WIFI_SSID");
+    println!("cargo:rerun-if-env-changed=WIFI_PASSWORD");
+    // read by older versions, before sign.toml
+    for (old, key) in [("BUS_STOP", "stop"), ("MBTA_PROXY_IP", "mbta.proxy_ip")] {
+        println!("cargo:rerun-if-env-changed={old}");
+        if env::var_os(old).is_some() {
+            println!("cargo:warning={old} is no longer read, set `{key}` in {path} instead");
+        }
+    }
+
+    let sign = match read_sign(Path::new(&path)) {
+        Ok(sign) => sign,
+        Err(errors) => {
+            eprintln!("error: {path} is not a valid sign definition:");
+            for error in errors {
+                eprintln!("  {error}");
+            }
+            process::exit(1);
+        }
+    };
+    fs::write(out.join("sign.rs"), generate(&sign)).unwrap();
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Sign {
+    #[serde(default = "default_time_zone")]
+    time_zone: String,
+    #[serde(default)]
+    stop: String,
+    #[serde(default)]
+    routes: Vec<Route>,
+    #[serde(default)]
+    profiles: Vec<Profile>,
+    #[serde(default)]
+    wifi: Wifi,
+    #[serde(default)]
+    network: Addresses,
+    #[serde(default)]
+    colors: Colors,
+    #[serde(default)]
+    schedule: Schedule,
+    #[serde(default)]
+    mbta: Mbta,
+    forward_proxy: Option<ForwardProxy>,
+    mqtt: Option<Mqtt>,
+    ota: Option<Ota>,
+    #[serde(default)]
+    syslog: Syslog,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Route {
+    id: String,
+    direction: Option<u8>,
+    stop: Option<String>,
+    tag: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    name: String,
+    from: String,
+    to: String,
+    routes: Vec<Route>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Wifi {
+    ssid: String,
+    password: String,
+    setup_ssid: String,
+    networks: Vec<Network>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Network {
+    ssid: String,
+    #[serde(default)]
+    password: String,
+    #[serde(default = "default_security")]
+    security: String,
+    #[serde(default)]
+    priority: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Addresses {
+    /// static IPv4 address and prefix length, eg "192.168.1.50/24", none
+    /// for DHCP
+    address: Option<String>,
+    gateway: Option<String>,
+    dns: Vec<String>,
+    ipv6: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Colors {
+    route: String,
+    label: String,
+    value: String,
+    error: String,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Schedule {
+    latitude: f64,
+    longitude: f64,
+    brightness: u8,
+    dim_brightness: u8,
+    holidays: Vec<String>,
+    weekday: Vec<Window>,
+    weekend: Vec<Window>,
+    holiday: Vec<Window>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct Window {
+    from: String,
+    to: String,
+    mode: String,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Mbta {
+    /// "proxy" or "direct", no default so every sign says how it fetches
+    mode: String,
+    api_host: String,
+    proxy_ip: String,
+    proxy_hostname: String,
+    tls_pins: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ForwardProxy {
+    host: String,
+    #[serde(default = "default_proxy_port")]
+    port: u16,
+    #[serde(default)]
+    connect: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Mqtt {
+    host: String,
+    #[serde(default = "default_mqtt_port")]
+    port: u16,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    password: String,
+    #[serde(default = "default_client_id")]
+    client_id: String,
+    #[serde(default = "default_client_id")]
+    topic: String,
+    #[serde(default = "default_discovery_prefix")]
+    discovery_prefix: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Ota {
+    /// hex ed25519 key that images must be signed with
+    public_key: String,
+    /// seconds a new image has to prove itself before it is rolled back
+    #[serde(default = "default_confirm_within")]
+    confirm_within: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Syslog {
+    host: String,
+    port: u16,
+    level: String,
+    hostname: String,
+}
+
+fn default_time_zone() -> String {
+    String::from("America/New_York")
+}
+
+fn default_security() -> String {
+    String::from("wpa2")
+}
+
+fn default_proxy_port() -> u16 {
+    3128
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    String::from("bus-sign")
+}
+
+fn default_discovery_prefix() -> String {
+    String::from("homeassistant")
+}
+
+fn default_confirm_within() -> u32 {
+    600
+}
+
+impl Default for Wifi {
+    fn default() -> Self {
+        Wifi {
+            ssid: String::new(),
+            password: String::new(),
+            setup_ssid: String::from("BUS-SIGN"),
+            networks: Vec::new(),
+        }
+    }
+}
+
+impl Default for Addresses {
+    fn default() -> Self {
+        Addresses {
+            address: None,
+            gateway: None,
+            dns: Vec::new(),
+            ipv6: true,
+        }
+    }
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Colors {
+            route: String::from("cyan"),
+            label: String::from("yellow"),
+            value: String::from("white"),
+            error: String::from("red"),
+        }
+    }
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        let night = vec![Window {
+            from: String::from("18:00"),
+            to: String::from("06:00"),
+            mode: String::from("off"),
+        }];
+        Schedule {
+            latitude: 42.36,
+            longitude: -71.06,
+            brightness: 100,
+            dim_brightness: 20,
+            holidays: Vec::new(),
+            weekday: night.clone(),
+            weekend: night.clone(),
+            holiday: night,
+        }
+    }
+}
+
+impl Default for Mbta {
+    fn default() -> Self {
+        Mbta {
+            mode: String::new(),
+            api_host: String::from("api-v3.mbta.com"),
+            proxy_ip: String::new(),
+            proxy_hostname: String::from("proxy.local"),
+            tls_pins: Vec::new(),
+        }
+    }
+}
+
+impl Default for Syslog {
+    fn default() -> Self {
+        Syslog {
+            host: String::new(),
+            port: 514,
+            level: String::from("info"),
+            hostname: String::from("bus-sign"),
+        }
+    }
+}
+
+/// Parse and check the sign definition, collecting every problem found
+fn read_sign(path: &Path) -> Result<Sign, Vec<String>> {
+    let text = fs::read_to_string(path).map_err(|e| vec![format!("can't read it: {e}")])?;
+    let mut sign: Sign = toml::from_str(&text).map_err(|e| vec![e.to_string()])?;
+
+    if let Ok(ssid) = env::var("WIFI_SSID") {
+        sign.wifi.ssid = ssid;
+    }
+    if let Ok(password) = env::var("WIFI_PASSWORD") {
+        sign.wifi.password = password;
+    }
+
+    let mut errors = Vec::new();
+    let mut check = |ok: bool, error: String| {
+        if !ok {
+            errors.push(error);
+        }
+    };
+
+    // limits match the fields of `Config` in src/config.rs
+    check(
+        sign.wifi.ssid.len() <= 32,
+        format!(
+            "wifi.ssid: {} bytes is longer than the 32 allowed",
+            sign.wifi.ssid.len()
+        ),
+    );
+    check(
+        sign.wifi.password.is_empty() || wpa_password_valid(&sign.wifi.password),
+        String::from(
+            "wifi.password: a WPA2 passphrase is 8 to 63 characters, or a key 64 hex digits",
+        ),
+    );
+    check(
+        !sign.wifi.setup_ssid.is_empty() && sign.wifi.setup_ssid.len() <= 32,
+        String::from("wifi.setup_ssid: must be 1 to 32 bytes"),
+    );
+    check(
+        sign.wifi.networks.len() <= MAX_NETWORKS,
+        format!("wifi.networks: at most {MAX_NETWORKS} networks can be listed"),
+    );
+    for (index, network) in sign.wifi.networks.iter().enumerate() {
+        check(
+            (1..=32).contains(&network.ssid.len()),
+            format!("wifi.networks[{index}].ssid: must be 1 to 32 bytes"),
+        );
+        check(
+            !sign.wifi.networks[..index]
+                .iter()
+                .any(|other| other.ssid == network.ssid),
+            format!("wifi.networks[{index}]: {:?} is listed twice", network.ssid),
+        );
+        match parse_security(&network.security) {
+            Some("Open") => check(
+                network.password.is_empty(),
+                format!("wifi.networks[{index}].password: an open network has no password"),
+            ),
+            Some(_) => check(
+                wpa_password_valid(&network.password),
+                format!(
+                    "wifi.networks[{index}].password: a WPA passphrase is 8 to 63 characters, \
+                     or a key 64 hex digits"
+                ),
+            ),
+            None => check(
+                false,
+                format!(
+                    "wifi.networks[{index}].security: {:?} must be \"open\", \"wpa2\" or \"wpa3\"",
+                    network.security
+                ),
+            ),
+        }
+    }
+
+    match &sign.network.address {
+        Some(address) => check(
+            parse_cidr(address).is_some(),
+            format!("network.address: {address:?} is not an IPv4 address and prefix, eg \"192.168.1.50/24\""),
+        ),
+        None => check(
+            sign.network.gateway.is_none() && sign.network.dns.is_empty(),
+            String::from("network: gateway and dns need a static address, DHCP provides them"),
+        ),
+    }
+    if let Some(gateway) = &sign.network.gateway {
+        check(
+            gateway.parse::<Ipv4Addr>().is_ok(),
+            format!("network.gateway: {gateway:?} is not an IPv4 address"),
+        );
+    }
+    check(
+        sign.network.dns.len() <= MAX_DNS_SERVERS,
+        format!("network.dns: at most {MAX_DNS_SERVERS} servers can be listed"),
+    );
+    for (index, server) in sign.network.dns.iter().enumerate() {
+        check(
+            server.parse::<Ipv4Addr>().is_ok(),
+            format!("network.dns[{index}]: {server:?} is not an IPv4 address"),
+        );
+    }
+
+    check(
+        sign.stop.is_empty() || (sign.stop.len() <= 16 && is_id(&sign.stop)),
+        format!(
+            "stop: {:?} is not an MBTA stop ID, eg \"2580\" or \"place-davis\" \
+             (letters, digits, '-' or '_', at most 16)",
+            sign.stop
+        ),
+    );
+
+    check_routes("routes", &sign.routes, &mut check);
+    check(
+        !sign.stop.is_empty()
+            || sign
+                .routes
+                .iter()
+                .chain(sign.profiles.iter().flat_map(|profile| &profile.routes))
+                .all(|route| route.stop.is_some()),
+        String::from("stop: needed unless every route, profiles included, has its own `stop`"),
+    );
+    for (index, profile) in sign.profiles.iter().enumerate() {
+        let name = &profile.name;
+        check(
+            !name.is_empty() && name.len() <= 12 && name.is_ascii(),
+            format!("profiles[{index}].name: {name:?} must be 1 to 12 ASCII characters"),
+        );
+        check(
+            !sign.profiles[..index]
+                .iter()
+                .any(|other| other.name == *name),
+            format!("profiles[{index}]: {name:?} is listed twice"),
+        );
+        for (field, edge) in [("from", &profile.from), ("to", &profile.to)] {
+            check(
+                parse_edge(edge).is_some(),
+                format!(
+                    "profiles[{index}].{field}: {edge:?} is not a time, \"sunrise\" or \"sunset\""
+                ),
+            );
+        }
+        check_routes(
+            &format!("profiles[{index}].routes"),
+            &profile.routes,
+            &mut check,
+        );
+    }
+    // stored as "87,88/1@place-davis#D" in `Config::routes`
+    let routes_len = sign
+        .routes
+        .iter()
+        .map(|route| {
+            route.id.len()
+                + 1
+                + if route.direction.is_some() { 2 } else { 0 }
+                + route.stop.as_ref().map_or(0, |stop| stop.len() + 1)
+                + route.tag.as_ref().map_or(0, |tag| tag.len() + 1)
+        })
+        .sum::<usize>();
+    check(
+        routes_len <= 193,
+        String::from("routes: too long, the routes add up to more than 192 characters"),
+    );
+
+    for (name, color) in [
+        ("route", &sign.colors.route),
+        ("label", &sign.colors.label),
+        ("value", &sign.colors.value),
+        ("error", &sign.colors.error),
+    ] {
+        check(
+            parse_color(color).is_some(),
+            format!("colors.{name}: {color:?} is not a color name or \"#rrggbb\""),
+        );
+    }
+
+    check(
+        (-90.0..=90.0).contains(&sign.schedule.latitude),
+        String::from("schedule.latitude: must be between -90 and 90 degrees"),
+    );
+    check(
+        (-180.0..=180.0).contains(&sign.schedule.longitude),
+        String::from("schedule.longitude: must be between -180 and 180 degrees, east positive"),
+    );
+    check(
+        sign.schedule.dim_brightness <= sign.schedule.brightness,
+        String::from("schedule.dim_brightness: must not be brighter than brightness"),
+    );
+    for (profile, windows) in [
+        ("weekday", &sign.schedule.weekday),
+        ("weekend", &sign.schedule.weekend),
+        ("holiday", &sign.schedule.holiday),
+    ] {
+        for (index, window) in windows.iter().enumerate() {
+            for (name, time) in [("from", &window.from), ("to", &window.to)] {
+                check(
+                    parse_edge(time).is_some(),
+                    format!(
+                        "schedule.{profile}[{index}].{name}: {time:?} is not a 24 hour \"HH:MM\" \
+                         time or \"sunrise\"/\"sunset\" with an optional +/- minutes"
+                    ),
+                );
+            }
+            check(
+                parse_mode(&window.mode).is_some(),
+                format!(
+                    "schedule.{profile}[{index}].mode: {:?} must be \"on\", \"dim\" or \"off\"",
+                    window.mode
+                ),
+            );
+        }
+    }
+    for (index, holiday) in sign.schedule.holidays.iter().enumerate() {
+        check(
+            parse_holiday(holiday).is_some(),
+            format!(
+                "schedule.holidays[{index}]: {holiday:?} is not a \"MM-DD\" or \"YYYY-MM-DD\" date"
+            ),
+        );
+    }
+
+    check(
+        sign.time_zone.contains('/')
+            && sign
+                .time_zone
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "/_+-".contains(c)),
+        format!(
+            "time_zone: {:?} is not an IANA zone name like \"America/New_York\"",
+            sign.time_zone
+        ),
+    );
+
+    match parse_api_mode(&sign.mbta.mode) {
+        // an empty `proxy_ip` is discovered at boot
+        Some("Proxy") => {}
+        Some(_) => check(
+            !sign.mbta.tls_pins.is_empty()
+                || sign
+                    .forward_proxy
+                    .as_ref()
+                    .is_some_and(|proxy| !proxy.connect),
+            String::from(
+                "mbta.tls_pins: direct mode needs at least one pin, see README, unless a \
+                 [forward_proxy] without `connect` makes the HTTPS connection",
+            ),
+        ),
+        None => check(
+            false,
+            format!(
+                "mbta.mode: {:?} must be \"proxy\" for mbta-proxy.py or \"direct\" for HTTPS \
+                 to api_host",
+                sign.mbta.mode
+            ),
+        ),
+    }
+    check(
+        !sign.mbta.api_host.is_empty() && sign.mbta.api_host.len() <= 64,
+        String::from("mbta.api_host: must be 1 to 64 characters"),
+    );
+    check(
+        sign.mbta.proxy_ip.len() <= 64,
+        String::from("mbta.proxy_ip: longer than the 64 characters allowed"),
+    );
+    check(
+        sign.mbta.proxy_hostname.ends_with(".local"),
+        format!(
+            "mbta.proxy_hostname: {:?} must end in .local to be resolved with mDNS",
+            sign.mbta.proxy_hostname
+        ),
+    );
+    check(
+        sign.mbta.tls_pins.len() <= 4,
+        String::from("mbta.tls_pins: more than the 4 pins the sign checks"),
+    );
+    for (index, pin) in sign.mbta.tls_pins.iter().enumerate() {
+        check(
+            pin.len() == 64 && pin.chars().all(|c| c.is_ascii_hexdigit()),
+            format!("mbta.tls_pins[{index}]: not a hex SHA-256 hash (64 hex digits)"),
+        );
+    }
+
+    if let Some(proxy) = &sign.forward_proxy {
+        check(
+            !proxy.host.is_empty(),
+            String::from("forward_proxy.host: must not be empty"),
+        );
+        check(
+            proxy.port != 0,
+            String::from("forward_proxy.port: must not be 0"),
+        );
+    }
+
+    if let Some(mqtt) = &sign.mqtt {
+        check(
+            !mqtt.host.is_empty(),
+            String::from("mqtt.host: must not be empty"),
+        );
+        check(mqtt.port != 0, String::from("mqtt.port: must not be 0"));
+        check(
+            mqtt.password.is_empty() || !mqtt.username.is_empty(),
+            String::from("mqtt.password: needs a username"),
+        );
+        // also the Home Assistant node ID, the limits are `MAX_CLIENT_ID_LEN`
+        // and `MAX_TOPIC_LEN` in protocol/src/mqtt.rs
+        check(
+            (1..=23).contains(&mqtt.client_id.len()) && is_id(&mqtt.client_id),
+            format!(
+                "mqtt.client_id: {:?} must be 1 to 23 letters, digits, '-' or '_'",
+                mqtt.client_id
+            ),
+        );
+        for (name, topic) in [
+            ("topic", &mqtt.topic),
+            ("discovery_prefix", &mqtt.discovery_prefix),
+        ] {
+            check(
+                (1..=32).contains(&topic.len())
+                    && !topic.contains(['+', '#'])
+                    && !topic.starts_with('/')
+                    && !topic.ends_with('/'),
+                format!(
+                    "mqtt.{name}: {topic:?} must be 1 to 32 characters without wildcards \
+                     or a leading or trailing '/'"
+                ),
+            );
+        }
+    }
+
+    if let Some(ota) = &sign.ota {
+        check(
+            parse_hex::<32>(&ota.public_key).is_some(),
+            String::from("ota.public_key: not a hex ed25519 public key (64 hex digits)"),
+        );
+        check(
+            (60..=3600).contains(&ota.confirm_within),
+            format!(
+                "ota.confirm_within: {} must be 60 to 3600 seconds",
+                ota.confirm_within
+            ),
+        );
+    }
+
+    // `HOST_SIZE` in src/syslog.rs
+    check(
+        sign.syslog.host.len() <= 64,
+        String::from("syslog.host: longer than 64 characters"),
+    );
+    check(
+        sign.syslog.port != 0,
+        String::from("syslog.port: must not be 0"),
+    );
+    check(
+        parse_level(&sign.syslog.level).is_some(),
+        format!(
+            "syslog.level: {:?} is not off, error, warn, info, debug or trace",
+            sign.syslog.level
+        ),
+    );
+    check(
+        (1..=48).contains(&sign.syslog.hostname.len())
+            && sign.syslog.hostname.chars().all(|c| c.is_ascii_graphic()),
+        format!(
+            "syslog.hostname: {:?} must be 1 to 48 characters without spaces",
+            sign.syslog.hostname
+        ),
+    );
+
+    if errors.is_empty() {
+        Ok(sign)
+    } else {
+        Err(errors)
+    }
+}
+
+/// `MAX_ROUTES` in src/config.rs
+const MAX_ROUTES: usize = 8;
+
+/// `MAX_NETWORKS` in src/wifi.rs
+const MAX_NETWORKS: usize = 8;
+
+/// `StaticConfigV4::dns_servers` holds three
+const MAX_DNS_SERVERS: usize = 3;
+
+/// `LevelFilter` variant named by a level like "warn"
+fn parse_level(value: &str) -> Option<&'static str> {
+    Some(match value.to_ascii_lowercase().as_str() {
+        "off" => "Off",
+        "error" => "Error",
+        "warn" => "Warn",
+        "info" => "Info",
+        "debug" => "Debug",
+        "trace" => "Trace",
+        _ => return None,
+    })
+}
+
+/// `config::password_valid` in src/config.rs
+fn wpa_password_valid(password: &str) -> bool {
+    (8..=63).contains(&password.len())
+        || (password.len() == 64 && password.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// MBTA stop and route IDs
+fn is_id(value: &str) -> bool {
+    value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn parse_color(value: &str) -> Option<(u8, u8, u8)> {
+    let rgb = match value.to_ascii_lowercase().as_str() {
+        "black" => (0, 0, 0),
+        "white" => (255, 255, 255),
+        "red" => (255, 0, 0),
+        "green" => (0, 255, 0),
+        "blue" => (0, 0, 255),
+        "yellow" => (255, 255, 0),
+        "cyan" => (0, 255, 255),
+        "magenta" => (255, 0, 255),
+        "orange" => (255, 165, 0),
+        hex => {
+            let hex = hex.strip_prefix('#')?;
+            if hex.len() != 6 {
+                return None;
+            }
+            let channel = |index: usize| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok();
+            (channel(0)?, channel(2)?, channel(4)?)
+        }
+    };
+    Some(rgb)
+}
+
+fn parse_time(value: &str) -> Option<(u8, u8)> {
+    let (hour, minute) = value.split_once(':')?;
+    if hour.len() != 2 || minute.len() != 2 {
+        return None;
+    }
+    let hour: u8 = hour.parse().ok()?;
+    let minute: u8 = minute.parse().ok()?;
+    (hour < 24 && minute < 60).then_some((hour, minute))
+}
+
+/// Check one list of routes, `name` is where it is in the sign definition
+fn check_routes(name: &str, routes: &[Route], check: &mut impl FnMut(bool, String)) {
+    check(
+        !routes.is_empty(),
+        format!("{name}: at least one route with an id is required"),
+    );
+    check(
+        routes.len() <= MAX_ROUTES,
+        format!(
+            "{name}: {} routes listed, the sign can watch at most {MAX_ROUTES}",
+            routes.len()
+        ),
+    );
+    for (index, route) in routes.iter().enumerate() {
+        let id = &route.id;
+        check(
+            !id.is_empty() && is_id(id),
+            format!("{name}[{index}].id: {id:?} is not an MBTA route ID, eg \"87\" or \"Red\""),
+        );
+        check(
+            matches!(route.direction, None | Some(0) | Some(1)),
+            format!("{name}[{index}].direction: must be 0 or 1"),
+        );
+        if let Some(stop) = &route.stop {
+            check(
+                !stop.is_empty() && stop.len() <= 16 && is_id(stop),
+                format!("{name}[{index}].stop: {stop:?} is not an MBTA stop ID"),
+            );
+        }
+        if let Some(tag) = &route.tag {
+            check(
+                (1..=3).contains(&tag.len()) && tag.chars().all(|c| c.is_ascii_alphanumeric()),
+                format!("{name}[{index}].tag: {tag:?} must be 1 to 3 letters or numbers"),
+            );
+        }
+        check(
+            !routes[..index].iter().any(|other| {
+                other.id == route.id
+                    && other.direction == route.direction
+                    && other.stop == route.stop
+            }),
+            format!("{name}[{index}]: {id:?} is listed twice"),
+        );
+    }
+}
+
+/// "HH:MM", or "sunrise"/"sunset" optionally followed by "+30" or "-15"
+/// minutes, as the `crate::schedule::Edge` it becomes
+fn parse_edge(value: &str) -> Option<String> {
+    if let Some((hour, minute)) = parse_time(value) {
+        return Some(format!(
+            "crate::schedule::Edge::At(crate::schedule::TimeOfDay {{ hour: {hour}, minute: {minute} }})"
+        ));
+    }
+    let (variant, offset) = if let Some(offset) = value.strip_prefix("sunrise") {
+        ("Sunrise", offset)
+    } else {
+        ("Sunset", value.strip_prefix("sunset")?)
+    };
+    // digits only, `parse` would also take a second sign
+    let minutes = |digits: &str| {
+        (!digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| digits.parse::<i16>().ok())
+            .flatten()
+    };
+    let offset = if offset.is_empty() {
+        0
+    } else if let Some(digits) = offset.strip_prefix('+') {
+        minutes(digits)?
+    } else {
+        -minutes(offset.strip_prefix('-')?)?
+    };
+    (offset.abs() <= 12 * 60).then(|| format!("crate::schedule::Edge::{variant}({offset})"))
+}
+
+/// Address and prefix length from "192.168.1.50/24"
+fn parse_cidr(value: &str) -> Option<(Ipv4Addr, u8)> {
+    let (address, prefix_len) = value.split_once('/')?;
+    let prefix_len = prefix_len.parse().ok().filter(|&len| len <= 32)?;
+    Some((address.parse().ok()?, prefix_len))
+}
+
+fn parse_hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != 2 * N || !value.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[2 * index..2 * index + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn parse_security(value: &str) -> Option<&'static str> {
+    match value {
+        "open" => Some("Open"),
+        "wpa2" => Some("Wpa2"),
+        "wpa3" => Some("Wpa3"),
+        _ => None,
+    }
+}
+
+fn parse_api_mode(value: &str) -> Option<&'static str> {
+    match value {
+        "proxy" => Some("Proxy"),
+        "direct" => Some("Direct"),
+        _ => None,
+    }
+}
+
+fn parse_mode(value: &str) -> Option<&'static str> {
+    match value {
+        "on" => Some("On"),
+        "dim" => Some("Dim"),
+        "off" => Some("Off"),
+        _ => None,
+    }
+}
+
+/// `(year, month, day)` from "MM-DD" or "YYYY-MM-DD"
+fn parse_holiday(value: &str) -> Option<(Option<u16>, u8, u8)> {
+    let (year, month_day) = match value.len() {
+        5 => (None, value),
+        10 if value.as_bytes()[4] == b'-' => (Some(value[..4].parse().ok()?), &value[5..]),
+        _ => return None,
+    };
+    let (month, day) = month_day.split_once('-')?;
+    let month: u8 = month.parse().ok()?;
+    let day: u8 = day.parse().ok()?;
+    ((1..=12).contains(&month) && (1..=31).contains(&day)).then_some((year, month, day))
+}
+
+/// Rust source for `CONFIG`, the strings are written with `{:?}` so they
+/// come out as escaped literals
+fn generate(sign: &Sign) -> String {
+    let color = |value: &str| {
+        let (r, g, b) = parse_color(value).unwrap();
+        format!("Rgb888::new({r}, {g}, {b})")
+    };
+    let routes = |routes: &[Route]| {
+        let mut code = String::from("&[\n");
+        for route in routes {
+            writeln!(
+                code,
+                "            RouteSettings {{ id: {:?}, direction: {:?}, stop: {:?}, tag: {:?} }},",
+                route.id,
+                route.direction,
+                route.stop.as_deref(),
+                route.tag.as_deref()
+            )
+            .unwrap();
+        }
+        code.push_str("        ]");
+        code
+    };
+    let windows = |windows: &[Window]| {
+        let mut code = String::from("&[\n");
+        for window in windows {
+            writeln!(
+                code,
+                "            crate::schedule::Window {{ from: {}, to: {}, mode: crate::schedule::Mode::{} }},",
+                parse_edge(&window.from).unwrap(),
+                parse_edge(&window.to).unwrap(),
+                parse_mode(&window.mode).unwrap()
+            )
+            .unwrap();
+        }
+        code.push_str("        ]");
+        code
+    };
+
+    let mut code = String::from("// generated by build.rs from the sign definition\n\n");
+    writeln!(code, "pub const CONFIG: SignConfig = SignConfig {{").unwrap();
+    writeln!(code, "    wifi: WifiSettings {{").unwrap();
+    writeln!(code, "        ssid: {:?},", sign.wifi.ssid).unwrap();
+    writeln!(code, "        password: {:?},", sign.wifi.password).unwrap();
+    writeln!(code, "        setup_ssid: {:?},", sign.wifi.setup_ssid).unwrap();
+    writeln!(code, "        networks: &[").unwrap();
+    for network in &sign.wifi.networks {
+        writeln!(
+            code,
+            "            Network {{ ssid: {:?}, password: {:?}, security: crate::wifi::Security::{}, priority: {} }},",
+            network.ssid,
+            network.password,
+            parse_security(&network.security).unwrap(),
+            network.priority
+        )
+        .unwrap();
+    }
+    writeln!(code, "        ],").unwrap();
+    writeln!(code, "    }},").unwrap();
+    writeln!(code, "    network: NetworkSettings {{").unwrap();
+    match &sign.network.address {
+        Some(address) => {
+            let (address, prefix_len) = parse_cidr(address).unwrap();
+            let octets = |address: &str| address.parse::<Ipv4Addr>().unwrap().octets();
+            let gateway = sign.network.gateway.as_deref().map(octets);
+            let dns = sign
+                .network
+                .dns
+                .iter()
+                .map(|server| octets(server))
+                .collect::<Vec<_>>();
+            writeln!(
+                code,
+                "        ipv4: Some(StaticIpv4 {{ address: {:?}, prefix_len: {prefix_len}, gateway: {gateway:?}, dns: &{dns:?} }}),",
+                address.octets()
+            )
+            .unwrap();
+        }
+        None => writeln!(code, "        ipv4: None,").unwrap(),
+    }
+    writeln!(code, "        ipv6: {},", sign.network.ipv6).unwrap();
+    writeln!(code, "    }},").unwrap();
+    writeln!(code, "    stop: {:?},", sign.stop).unwrap();
+    writeln!(code, "    routes: {},", routes(&sign.routes)).unwrap();
+    writeln!(code, "    profiles: &[").unwrap();
+    for profile in &sign.profiles {
+        writeln!(code, "        ProfileSettings {{").unwrap();
+        writeln!(code, "            name: {:?},", profile.name).unwrap();
+        writeln!(
+            code,
+            "            from: {},",
+            parse_edge(&profile.from).unwrap()
+        )
+        .unwrap();
+        writeln!(
+            code,
+            "            to: {},",
+            parse_edge(&profile.to).unwrap()
+        )
+        .unwrap();
+        writeln!(code, "            routes: {},", routes(&profile.routes)).unwrap();
+        writeln!(code, "        }},").unwrap();
+    }
+    writeln!(code, "    ],").unwrap();
+    writeln!(code, "    colors: Colors {{").unwrap();
+    writeln!(code, "        route: {},", color(&sign.colors.route)).unwrap();
+    writeln!(code, "        label: {},", color(&sign.colors.label)).unwrap();
+    writeln!(code, "        value: {},", color(&sign.colors.value)).unwrap();
+    writeln!(code, "        error: {},", color(&sign.colors.error)).unwrap();
+    writeln!(code, "    }},").unwrap();
+    writeln!(code, "    schedule: Schedule {{").unwrap();
+    writeln!(code, "        latitude: {:?},", sign.schedule.latitude).unwrap();
+    writeln!(code, "        longitude: {:?},", sign.schedule.longitude).unwrap();
+    writeln!(code, "        brightness: {},", sign.schedule.brightness).unwrap();
+    writeln!(
+        code,
+        "        dim_brightness: {},",
+        sign.schedule.dim_brightness
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "        weekday: {},",
+        windows(&sign.schedule.weekday)
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "        weekend: {},",
+        windows(&sign.schedule.weekend)
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "        holiday: {},",
+        windows(&sign.schedule.holiday)
+    )
+    .unwrap();
+    writeln!(code, "        holidays: &[").unwrap();
+    for holiday in &sign.schedule.holidays {
+        let (year, month, day) = parse_holiday(holiday).unwrap();
+        writeln!(
+            code,
+            "            crate::schedule::Holiday {{ year: {year:?}, month: {month}, day: {day} }},"
+        )
+        .unwrap();
+    }
+    writeln!(code, "        ],").unwrap();
+    writeln!(code, "    }},").unwrap();
+    writeln!(code, "    time_zone: {:?},", sign.time_zone).unwrap();
+    writeln!(code, "    mbta: MbtaSettings {{").unwrap();
+    writeln!(
+        code,
+        "        mode: ApiMode::{},",
+        parse_api_mode(&sign.mbta.mode).unwrap()
+    )
+    .unwrap();
+    writeln!(code, "        api_host: {:?},", sign.mbta.api_host).unwrap();
+    writeln!(code, "        proxy_ip: {:?},", sign.mbta.proxy_ip).unwrap();
+    writeln!(
+        code,
+        "        proxy_hostname: {:?},",
+        sign.mbta.proxy_hostname
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "        tls_pins: {:?},",
+        sign.mbta.tls_pins.join(",")
+    )
+    .unwrap();
+    match &sign.forward_proxy {
+        Some(proxy) => writeln!(
+            code,
+            "        forward_proxy: Some(ForwardProxy {{ host: {:?}, port: {}, tunnel: {} }}),",
+            proxy.host, proxy.port, proxy.connect
+        ),
+        None => writeln!(code, "        forward_proxy: None,"),
+    }
+    .unwrap();
+    writeln!(code, "    }},").unwrap();
+    match &sign.mqtt {
+        Some(mqtt) => {
+            writeln!(code, "    mqtt: Some(MqttSettings {{").unwrap();
+            writeln!(code, "        host: {:?},", mqtt.host).unwrap();
+            writeln!(code, "        port: {},", mqtt.port).unwrap();
+            writeln!(code, "        username: {:?},", mqtt.username).unwrap();
+            writeln!(code, "        password: {:?},", mqtt.password).unwrap();
+            writeln!(code, "        client_id: {:?},", mqtt.client_id).unwrap();
+            writeln!(code, "        topic: {:?},", mqtt.topic).unwrap();
+            writeln!(
+                code,
+                "        discovery_prefix: {:?},",
+                mqtt.discovery_prefix
+            )
+            .unwrap();
+            writeln!(code, "    }}),").unwrap();
+        }
+        None => writeln!(code, "    mqtt: None,").unwrap(),
+    }
+    match &sign.ota {
+        Some(ota) => writeln!(
+            code,
+            "    ota: Some(OtaSettings {{ public_key: {:?}, confirm_within: {} }}),",
+            parse_hex::<32>(&ota.public_key).unwrap(),
+            ota.confirm_within
+        ),
+        None => writeln!(code, "    ota: None,"),
+    }
+    .unwrap();
+    writeln!(code, "    syslog: SyslogSettings {{").unwrap();
+    writeln!(code, "        host: {:?},", sign.syslog.host).unwrap();
+    writeln!(code, "        port: {},", sign.syslog.port).unwrap();
+    writeln!(
+        code,
+        "        level: LevelFilter::{},",
+        parse_level(&sign.syslog.level).unwrap()
+    )
+    .unwrap();
+    writeln!(code, "        hostname: {:?},", sign.syslog.hostname).unwrap();
+    writeln!(code, "    }},").unwrap();
+    writeln!(code, "}};").unwrap();
+    code
 }
@@ -1,8 +1,14 @@
 import json
+import socket
 from http.server import BaseHTTPRequestHandler, HTTPServer
 
 import requests
 
+try:
+    from zeroconf import ServiceInfo, Zeroconf
+except ImportError:
+    Zeroconf = None
+
 
 class MBTAProxyHandler(BaseHTTPRequestHandler):
     def do_GET(self):
@@ -25,11 +31,38 @@ class MBTAProxyHandler(BaseHTTPRequestHandler):
             self.send_error(500, f"Error fetching from MBTA API: {e}")
 
 
+def advertise(port):
+    """Advertise the proxy as _mbta-proxy._tcp so the sign can find it"""
+    if Zeroconf is None:
+        print("zeroconf not installed, not advertising over mDNS")
+        return None
+
+    hostname = socket.gethostname()
+    address = socket.gethostbyname(hostname)
+    info = ServiceInfo(
+        "_mbta-proxy._tcp.local.",
+        f"{hostname}._mbta-proxy._tcp.local.",
+        addresses=[socket.inet_aton(address)],
+        port=port,
+        server=f"{hostname}.local.",
+    )
+    zeroconf = Zeroconf()
+    zeroconf.register_service(info)
+    print(f"Advertising _mbta-proxy._tcp at {address}:{port}")
+    return zeroconf
+
+
 def run(server_class=HTTPServer, handler_class=MBTAProxyHandler, port=80):
     server_address = ("", port)
     httpd = server_class(server_address, handler_class)
+    zeroconf = advertise(port)
     print(f"Starting server on port {port}...")
-    httpd.serve_forever()
+    try:
+        httpd.serve_forever()
+    finally:
+        if zeroconf is not None:
+            zeroconf.unregister_all_services()
+            zeroconf.close()
 
 
 if __name__ == "__main__":
@@ -1,5 +1,25 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
-    RAM   : ORIGIN = 0x20000000, LENGTH = 264K
+    /* 0x10000100 to 0x10006000 holds the bootloader, see bootloader/ */
+    BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+    /* the ACTIVE partition the sign runs from */
+    FLASH : ORIGIN = 0x10007000, LENGTH = 960K
+    /* an update is written here, one sector larger for the swap */
+    DFU : ORIGIN = 0x100F7000, LENGTH = 964K
+    /* the last two 4K sectors hold the stored config, see src/config.rs */
+    RAM   : ORIGIN = 0x20000000, LENGTH = 264K - 0x300
+    /* the last panic, kept across the restart, see src/crash.rs. The boot
+       ROM copies boot2 to the 256 bytes above it while starting up. */
+    PANIC : ORIGIN = 0x20041D00, LENGTH = 0x200
 }
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_active_start = ORIGIN(FLASH) - ORIGIN(BOOT2);
+__bootloader_active_end = ORIGIN(FLASH) + LENGTH(FLASH) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
+
+__panic_start = ORIGIN(PANIC);
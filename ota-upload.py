@@ -0,0 +1,68 @@
+import argparse
+import hashlib
+import sys
+import urllib.error
+import urllib.request
+
+from cryptography.hazmat.primitives import serialization
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+
+
+def generate(key_path):
+    """Write a new signing key and print the public key for sign.toml"""
+    key = Ed25519PrivateKey.generate()
+    with open(key_path, "wb") as f:
+        f.write(
+            key.private_bytes(
+                serialization.Encoding.PEM,
+                serialization.PrivateFormat.PKCS8,
+                serialization.NoEncryption(),
+            )
+        )
+    public = key.public_key().public_bytes(
+        serialization.Encoding.Raw, serialization.PublicFormat.Raw
+    )
+    print(f'[ota]\npublic_key = "{public.hex()}"')
+
+
+def upload(key_path, image_path, host):
+    """Sign the image and post it to the sign, which restarts into it"""
+    with open(key_path, "rb") as f:
+        key = serialization.load_pem_private_key(f.read(), password=None)
+    with open(image_path, "rb") as f:
+        image = f.read()
+
+    # embassy-boot checks the signature over the image's SHA-512 digest
+    signature = key.sign(hashlib.sha512(image).digest())
+    request = urllib.request.Request(
+        f"http://{host}/firmware",
+        data=image,
+        headers={
+            "Content-Type": "application/octet-stream",
+            "X-Signature": signature.hex(),
+        },
+    )
+    print(f"Uploading {len(image)} bytes to {host}...")
+    try:
+        with urllib.request.urlopen(request, timeout=120) as response:
+            print(response.read().decode())
+    except urllib.error.HTTPError as e:
+        print(f"{e.code}: {e.read().decode()}")
+        sys.exit(1)
+
+
+if __name__ == "__main__":
+    parser = argparse.ArgumentParser(description="Sign and upload bus-sign firmware")
+    parser.add_argument("key", help="PEM ed25519 private key")
+    parser.add_argument("image", nargs="?", help="raw binary, from cargo objcopy")
+    parser.add_argument("host", nargs="?", help="address of the sign")
+    parser.add_argument(
+        "--generate", action="store_true", help="write a new key to KEY instead"
+    )
+    args = parser.parse_args()
+    if args.generate:
+        generate(args.key)
+    elif args.image and args.host:
+        upload(args.key, args.image, args.host)
+    else:
+        parser.error("IMAGE and HOST are needed to upload")
@@ -0,0 +1,876 @@
+//! Small HTTP/1.1 API on the local network: `GET /status` reports the sign
+//! as JSON, `GET /metrics` for Prometheus, `POST /brightness`, `POST /mode`
+//! and `POST /refresh` control it and `POST /firmware` takes a signed
+//! firmware image, written to flash as it arrives.
+//! One request per connection. Like the console it works through a trait,
+//! so the parsing and routing can run against a fake on the host.
+
+use core::fmt::{self, Write as _};
+use embedded_io_async::{Read, Write};
+use serde::Serialize;
+
+pub const PORT: u16 = 80;
+
+/// Largest request, headers and body, that is read
+pub const REQUEST_SIZE: usize = 1024;
+
+/// Largest body sent back, the metrics being the longest
+pub const RESPONSE_SIZE: usize = 3072;
+
+const JSON: &str = "application/json";
+/// Prometheus text exposition format
+const METRICS: &str = "text/plain; version=0.0.4";
+
+/// Firmware is written in blocks of one flash sector
+pub const FIRMWARE_BLOCK: usize = 4096;
+
+/// Most routes reported, `MAX_ROUTES` in the firmware's src/config.rs
+const MAX_ROUTES: usize = 8;
+
+/// Longest panic message, `MESSAGE_SIZE` in the firmware's src/crash.rs
+const PANIC_MESSAGE_SIZE: usize = 428;
+
+/// What the display does during a schedule window, later variants win
+/// where windows overlap
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub enum Mode {
+    On,
+    /// shown at the dimmed brightness, still fetching
+    Dim,
+    /// display off and no fetching
+    Off,
+}
+
+/// What `GET /status` reports
+#[derive(Serialize)]
+pub struct Status {
+    /// seconds since boot
+    pub uptime: u64,
+    /// network joined and its signal strength when it was picked, in dBm
+    pub ssid: heapless::String<32>,
+    pub rssi: Option<i16>,
+    pub time: TimeStatus,
+    /// outcome of the most recent fetch, `None` before the first
+    pub last_fetch: Option<FetchStatus>,
+    /// rows of the active profile, in display order
+    pub routes: heapless::Vec<RouteStatus, MAX_ROUTES>,
+    /// the panic that restarted the sign, if that's why it restarted
+    pub last_panic: Option<PanicStatus>,
+}
+
+#[derive(Serialize)]
+pub struct TimeStatus {
+    /// the clock was set from the network
+    pub synced: bool,
+    /// local time, eg "2024-06-12T08:30:00"
+    pub local: Option<heapless::String<19>>,
+    /// minutes local time is ahead of UTC
+    pub utc_offset: i32,
+}
+
+#[derive(Serialize)]
+pub struct PanicStatus {
+    /// source file and line, eg "src/rtc.rs:51"
+    pub location: heapless::String<72>,
+    pub message: heapless::String<PANIC_MESSAGE_SIZE>,
+    /// seconds after boot it happened
+    pub uptime: u32,
+}
+
+#[derive(Serialize, Copy, Clone)]
+pub struct FetchStatus {
+    pub stop: &'static str,
+    pub ok: bool,
+    /// short code also shown on the display
+    pub error: Option<&'static str>,
+    pub seconds_ago: u64,
+}
+
+#[derive(Serialize, Copy, Clone, PartialEq)]
+pub struct RouteStatus {
+    pub route: &'static str,
+    pub direction: Option<u8>,
+    pub stop: &'static str,
+    /// minutes until the next bus, `None` when there is none or nothing
+    /// was fetched yet
+    pub minutes: Option<u8>,
+    pub error: Option<&'static str>,
+}
+
+/// What the API can ask of the sign
+#[allow(async_fn_in_trait)]
+pub trait Sign {
+    async fn status(&mut self) -> Status;
+    /// Display brightness until the schedule next changes it
+    async fn set_brightness(&mut self, brightness: u8);
+    /// Hold `mode` until the schedule next changes, `None` to follow the
+    /// schedule again
+    fn set_mode(&mut self, mode: Option<Mode>);
+    /// Fetch every route now
+    fn refresh(&mut self);
+    /// Metrics in the Prometheus text format
+    fn metrics(&mut self, out: &mut impl fmt::Write) -> fmt::Result;
+    /// Largest firmware image, `None` when updates are turned off
+    fn firmware_capacity(&self) -> Option<u32>;
+    /// Write a `FIRMWARE_BLOCK` of a new image at `offset`, the last one
+    /// padded
+    async fn write_firmware(&mut self, offset: u32, block: &[u8]) -> Result<(), UpdateError>;
+    /// Check the `size` bytes written against `signature` and switch to
+    /// the image on the next boot
+    async fn finish_firmware(&mut self, size: u32, signature: &[u8; 64])
+        -> Result<(), UpdateError>;
+}
+
+/// Why a firmware image wasn't taken
+#[derive(Debug, PartialEq)]
+pub enum UpdateError {
+    /// the running image is still on trial
+    Busy,
+    BadSignature,
+    Flash,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Method {
+    Get,
+    Post,
+    Other,
+}
+
+/// Start line and the headers the API looks at
+#[derive(Debug, PartialEq)]
+pub struct Head<'a> {
+    pub method: Method,
+    /// without the query string
+    pub path: &'a str,
+    pub content_length: usize,
+    /// hex ed25519 signature of a firmware image
+    pub signature: Option<&'a str>,
+    /// up to and including the blank line
+    pub len: usize,
+}
+
+/// Start line and body of a request
+#[derive(Debug, PartialEq)]
+pub struct Request<'a> {
+    pub method: Method,
+    /// without the query string
+    pub path: &'a str,
+    pub body: &'a str,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// the headers or the body haven't all arrived yet
+    Incomplete,
+    Malformed,
+}
+
+impl<'a> Head<'a> {
+    /// The head of a request, whether or not its body has arrived
+    pub fn parse(buffer: &'a [u8]) -> Result<Self, ParseError> {
+        let head_end = buffer
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or(ParseError::Incomplete)?;
+        let head = core::str::from_utf8(&buffer[..head_end]).map_err(|_| ParseError::Malformed)?;
+        let mut lines = head.split("\r\n");
+
+        let mut start = lines.next().unwrap_or("").split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (start.next(), start.next(), start.next(), start.next())
+        else {
+            return Err(ParseError::Malformed);
+        };
+        if !version.starts_with("HTTP/1.") || !target.starts_with('/') {
+            return Err(ParseError::Malformed);
+        }
+        let method = match method {
+            "GET" => Method::Get,
+            "POST" => Method::Post,
+            _ => Method::Other,
+        };
+        let path = target.split_once('?').map_or(target, |(path, _query)| path);
+
+        let mut content_length = 0;
+        let mut signature = None;
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or(ParseError::Malformed)?;
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| ParseError::Malformed)?;
+            } else if name.eq_ignore_ascii_case("x-signature") {
+                signature = Some(value.trim());
+            }
+        }
+
+        Ok(Head {
+            method,
+            path,
+            content_length,
+            signature,
+            len: head_end + 4,
+        })
+    }
+}
+
+impl<'a> Request<'a> {
+    pub fn parse(buffer: &'a [u8]) -> Result<Self, ParseError> {
+        let head = Head::parse(buffer)?;
+        let body = &buffer[head.len..];
+        if body.len() < head.content_length {
+            return Err(ParseError::Incomplete);
+        }
+        let body = core::str::from_utf8(&body[..head.content_length])
+            .map_err(|_| ParseError::Malformed)?;
+        Ok(Request {
+            method: head.method,
+            path: head.path,
+            body,
+        })
+    }
+}
+
+/// A request the API understands
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Status,
+    Metrics,
+    Brightness(u8),
+    /// `None` for "auto", back to the schedule
+    Mode(Option<Mode>),
+    Refresh,
+}
+
+/// Why a request can't be served
+#[derive(Debug, PartialEq)]
+pub enum RouteError {
+    NotFound,
+    MethodNotAllowed,
+    /// the body isn't a value the endpoint takes
+    BadValue,
+}
+
+impl RouteError {
+    fn status(&self) -> u16 {
+        match self {
+            RouteError::NotFound => 404,
+            RouteError::MethodNotAllowed => 405,
+            RouteError::BadValue => 400,
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            RouteError::NotFound => "not found",
+            RouteError::MethodNotAllowed => "method not allowed",
+            RouteError::BadValue => "bad value",
+        }
+    }
+}
+
+impl Action {
+    /// The action for `request`. Values are sent as the plain body, eg
+    /// `40` for the brightness or `dim` for the mode.
+    pub fn route(request: &Request) -> Result<Self, RouteError> {
+        let method = match request.path {
+            "/status" | "/metrics" => Method::Get,
+            "/brightness" | "/mode" | "/refresh" => Method::Post,
+            _ => return Err(RouteError::NotFound),
+        };
+        if request.method != method {
+            return Err(RouteError::MethodNotAllowed);
+        }
+
+        let value = request.body.trim();
+        Ok(match request.path {
+            "/status" => Action::Status,
+            "/metrics" => Action::Metrics,
+            "/brightness" => Action::Brightness(value.parse().map_err(|_| RouteError::BadValue)?),
+            "/mode" => Action::Mode(match value {
+                "on" => Some(Mode::On),
+                "dim" => Some(Mode::Dim),
+                "off" => Some(Mode::Off),
+                "auto" => None,
+                _ => return Err(RouteError::BadValue),
+            }),
+            _ => Action::Refresh,
+        })
+    }
+}
+
+/// Answer one request, writing the body to `body` and returning the
+/// status code and content type
+pub async fn handle<S: Sign>(
+    request: &Request<'_>,
+    sign: &mut S,
+    body: &mut heapless::Vec<u8, RESPONSE_SIZE>,
+) -> (u16, &'static str) {
+    body.clear();
+    let action = match Action::route(request) {
+        Ok(action) => action,
+        Err(e) => {
+            error_body(body, e.message());
+            return (e.status(), JSON);
+        }
+    };
+
+    match action {
+        Action::Status => {
+            let status = sign.status().await;
+            body.resize_default(RESPONSE_SIZE).ok();
+            match serde_json_core::to_slice(&status, &mut body[..]) {
+                Ok(len) => body.truncate(len),
+                Err(_) => {
+                    error_body(body, "status too large");
+                    return (500, JSON);
+                }
+            }
+        }
+        Action::Metrics => {
+            if sign.metrics(&mut BodyWriter(body)).is_err() {
+                error_body(body, "metrics too large");
+                return (500, JSON);
+            }
+            return (200, METRICS);
+        }
+        Action::Brightness(brightness) => {
+            sign.set_brightness(brightness).await;
+            body.extend_from_slice(b"{\"ok\":true}").ok();
+        }
+        Action::Mode(mode) => {
+            sign.set_mode(mode);
+            body.extend_from_slice(b"{\"ok\":true}").ok();
+        }
+        Action::Refresh => {
+            sign.refresh();
+            body.extend_from_slice(b"{\"ok\":true}").ok();
+        }
+    }
+    (200, JSON)
+}
+
+/// Text formatted straight into the response body
+struct BodyWriter<'a>(&'a mut heapless::Vec<u8, RESPONSE_SIZE>);
+
+impl fmt::Write for BodyWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0
+            .extend_from_slice(s.as_bytes())
+            .map_err(|_| fmt::Error)
+    }
+}
+
+fn error_body(body: &mut heapless::Vec<u8, RESPONSE_SIZE>, message: &str) {
+    body.clear();
+    body.extend_from_slice(b"{\"error\":\"").ok();
+    body.extend_from_slice(message.as_bytes()).ok();
+    body.extend_from_slice(b"\"}").ok();
+}
+
+/// Take a firmware image of `size` bytes as the body of `POST /firmware`,
+/// `received` being the part read along with the head. `None` when the
+/// connection closed early.
+async fn receive_firmware<C: Read, S: Sign>(
+    connection: &mut C,
+    sign: &mut S,
+    size: usize,
+    signature: Option<[u8; 64]>,
+    received: &[u8],
+    body: &mut heapless::Vec<u8, RESPONSE_SIZE>,
+) -> Result<Option<(u16, &'static str)>, C::Error> {
+    let (status, message) = match (sign.firmware_capacity(), signature) {
+        (None, _) => (404, "firmware updates are off"),
+        (Some(_), None) => (400, "missing or bad X-Signature"),
+        (Some(_), _) if size == 0 => (411, "missing Content-Length"),
+        (Some(capacity), _) if size > capacity as usize => (413, "image too large"),
+        _ => (200, ""),
+    };
+    if status != 200 {
+        error_body(body, message);
+        return Ok(Some((status, JSON)));
+    }
+
+    let mut block = [0xff; FIRMWARE_BLOCK];
+    let mut filled = received.len().min(size);
+    block[..filled].copy_from_slice(&received[..filled]);
+    let mut offset = 0;
+    while offset < size {
+        let wanted = (size - offset).min(FIRMWARE_BLOCK);
+        while filled < wanted {
+            let read = connection.read(&mut block[filled..wanted]).await?;
+            if read == 0 {
+                // closed before the whole image arrived
+                return Ok(None);
+            }
+            filled += read;
+        }
+        block[filled..].fill(0xff);
+        if let Err(e) = sign.write_firmware(offset as u32, &block).await {
+            return Ok(Some(update_error(body, e)));
+        }
+        offset += filled;
+        filled = 0;
+    }
+
+    if let Err(e) = sign.finish_firmware(size as u32, &signature.unwrap()).await {
+        return Ok(Some(update_error(body, e)));
+    }
+    body.extend_from_slice(b"{\"ok\":true}").ok();
+    Ok(Some((200, JSON)))
+}
+
+fn update_error(
+    body: &mut heapless::Vec<u8, RESPONSE_SIZE>,
+    e: UpdateError,
+) -> (u16, &'static str) {
+    let (status, message) = match e {
+        UpdateError::Busy => (409, "the running firmware isn't confirmed yet"),
+        UpdateError::BadSignature => (400, "bad signature"),
+        UpdateError::Flash => (500, "flash write failed"),
+    };
+    error_body(body, message);
+    (status, JSON)
+}
+
+/// 128 hex digits
+fn parse_signature(hex: &str) -> Option<[u8; 64]> {
+    if hex.len() != 128 || !hex.is_ascii() {
+        return None;
+    }
+    let mut signature = [0; 64];
+    for (index, byte) in signature.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * index..2 * index + 2], 16).ok()?;
+    }
+    Some(signature)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Read one request from `connection` and answer it
+pub async fn serve<C: Read + Write, S: Sign>(
+    connection: &mut C,
+    sign: &mut S,
+) -> Result<(), C::Error> {
+    let mut request = [0; REQUEST_SIZE];
+    let mut len = 0;
+    let mut body = heapless::Vec::<u8, RESPONSE_SIZE>::new();
+
+    let (status, content_type) = loop {
+        if len == request.len() {
+            error_body(&mut body, "request too large");
+            break (413, JSON);
+        }
+        let read = connection.read(&mut request[len..]).await?;
+        if read == 0 {
+            // closed before a whole request arrived
+            return Ok(());
+        }
+        len += read;
+
+        // an image doesn't fit in `request`, it's written as it arrives
+        if let Ok(head) = Head::parse(&request[..len]) {
+            if head.method == Method::Post && head.path == "/firmware" {
+                let signature = head.signature.and_then(parse_signature);
+                let received = &request[head.len..len];
+                let size = head.content_length;
+                match receive_firmware(connection, sign, size, signature, received, &mut body)
+                    .await?
+                {
+                    Some(response) => break response,
+                    None => return Ok(()),
+                }
+            }
+        }
+
+        match Request::parse(&request[..len]) {
+            Ok(request) => break handle(&request, sign, &mut body).await,
+            Err(ParseError::Incomplete) => continue,
+            Err(ParseError::Malformed) => {
+                error_body(&mut body, "malformed request");
+                break (400, JSON);
+            }
+        }
+    };
+
+    let mut head = heapless::String::<128>::new();
+    write!(
+        head,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason(status),
+        content_type,
+        body.len()
+    )
+    .ok();
+    connection.write_all(head.as_bytes()).await?;
+    connection.write_all(&body).await?;
+    connection.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+    use embedded_io_async::{ErrorKind, ErrorType};
+    use std::string::String;
+    use std::vec::Vec;
+
+    #[derive(Default)]
+    struct FakeSign {
+        brightness: Option<u8>,
+        mode: Option<Option<Mode>>,
+        refreshes: usize,
+        capacity: Option<u32>,
+        image: Vec<u8>,
+        finished: Option<(u32, [u8; 64])>,
+    }
+
+    impl Sign for FakeSign {
+        async fn status(&mut self) -> Status {
+            Status {
+                uptime: 42,
+                ssid: "home".try_into().unwrap(),
+                rssi: Some(-61),
+                time: TimeStatus {
+                    synced: true,
+                    local: Some("2024-06-12T08:30:00".try_into().unwrap()),
+                    utc_offset: -240,
+                },
+                last_fetch: Some(FetchStatus {
+                    stop: "place-davis",
+                    ok: false,
+                    error: Some("HTTP"),
+                    seconds_ago: 5,
+                }),
+                routes: [RouteStatus {
+                    route: "87",
+                    direction: Some(1),
+                    stop: "place-davis",
+                    minutes: Some(4),
+                    error: None,
+                }]
+                .into_iter()
+                .collect(),
+                last_panic: None,
+            }
+        }
+
+        async fn set_brightness(&mut self, brightness: u8) {
+            self.brightness = Some(brightness);
+        }
+
+        fn set_mode(&mut self, mode: Option<Mode>) {
+            self.mode = Some(mode);
+        }
+
+        fn refresh(&mut self) {
+            self.refreshes += 1;
+        }
+
+        fn metrics(&mut self, out: &mut impl fmt::Write) -> fmt::Result {
+            out.write_str("# TYPE sign_fetches_total counter\nsign_fetches_total 3\n")
+        }
+
+        fn firmware_capacity(&self) -> Option<u32> {
+            self.capacity
+        }
+
+        async fn write_firmware(&mut self, offset: u32, block: &[u8]) -> Result<(), UpdateError> {
+            assert_eq!(offset as usize, self.image.len());
+            assert_eq!(block.len(), FIRMWARE_BLOCK);
+            self.image.extend_from_slice(block);
+            Ok(())
+        }
+
+        async fn finish_firmware(
+            &mut self,
+            size: u32,
+            signature: &[u8; 64],
+        ) -> Result<(), UpdateError> {
+            if signature[0] != 0xab {
+                return Err(UpdateError::BadSignature);
+            }
+            self.finished = Some((size, *signature));
+            Ok(())
+        }
+    }
+
+    fn request(method: Method, path: &'static str, body: &'static str) -> Request<'static> {
+        Request { method, path, body }
+    }
+
+    fn respond(sign: &mut FakeSign, request: &Request) -> (u16, &'static str, String) {
+        let mut body = heapless::Vec::new();
+        let (status, content_type) = block_on(handle(request, sign, &mut body));
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn requests_parse() {
+        assert_eq!(
+            Request::parse(b"GET /status?pretty HTTP/1.1\r\nHost: sign\r\n\r\n"),
+            Ok(request(Method::Get, "/status", ""))
+        );
+        assert_eq!(
+            Request::parse(b"POST /brightness HTTP/1.0\r\ncontent-LENGTH: 2\r\n\r\n40"),
+            Ok(request(Method::Post, "/brightness", "40"))
+        );
+        assert_eq!(
+            Request::parse(b"DELETE /mode HTTP/1.1\r\n\r\n").map(|request| request.method),
+            Ok(Method::Other)
+        );
+        assert_eq!(
+            Request::parse(b"GET /status HTTP/1.1\r\nHost: sign\r\n"),
+            Err(ParseError::Incomplete)
+        );
+        assert_eq!(
+            Request::parse(b"POST /mode HTTP/1.1\r\nContent-Length: 4\r\n\r\ndi"),
+            Err(ParseError::Incomplete)
+        );
+        assert_eq!(
+            Request::parse(b"GET /status HTTP/2\r\n\r\n"),
+            Err(ParseError::Malformed)
+        );
+        assert_eq!(
+            Request::parse(b"GET status HTTP/1.1\r\n\r\n"),
+            Err(ParseError::Malformed)
+        );
+        assert_eq!(
+            Request::parse(b"GET /status HTTP/1.1\r\nno colon\r\n\r\n"),
+            Err(ParseError::Malformed)
+        );
+        assert_eq!(
+            Request::parse(b"POST /mode HTTP/1.1\r\nContent-Length: lots\r\n\r\n"),
+            Err(ParseError::Malformed)
+        );
+
+        let head = Head::parse(b"POST /firmware HTTP/1.1\r\nX-Signature:  ab12 \r\n\r\n\x7f");
+        assert_eq!(
+            head.map(|head| (head.signature, head.len)),
+            Ok((Some("ab12"), 48))
+        );
+    }
+
+    #[test]
+    fn actions_route() {
+        let route = |method, path, body| Action::route(&request(method, path, body));
+        assert_eq!(route(Method::Get, "/status", ""), Ok(Action::Status));
+        assert_eq!(route(Method::Get, "/metrics", ""), Ok(Action::Metrics));
+        assert_eq!(
+            route(Method::Post, "/brightness", " 40\n"),
+            Ok(Action::Brightness(40))
+        );
+        assert_eq!(
+            route(Method::Post, "/brightness", "300"),
+            Err(RouteError::BadValue)
+        );
+        assert_eq!(
+            route(Method::Post, "/mode", "dim"),
+            Ok(Action::Mode(Some(Mode::Dim)))
+        );
+        assert_eq!(route(Method::Post, "/mode", "auto"), Ok(Action::Mode(None)));
+        assert_eq!(
+            route(Method::Post, "/mode", "loud"),
+            Err(RouteError::BadValue)
+        );
+        assert_eq!(route(Method::Post, "/refresh", ""), Ok(Action::Refresh));
+        assert_eq!(
+            route(Method::Post, "/status", ""),
+            Err(RouteError::MethodNotAllowed)
+        );
+        assert_eq!(
+            route(Method::Get, "/mode", ""),
+            Err(RouteError::MethodNotAllowed)
+        );
+        assert_eq!(route(Method::Get, "/", ""), Err(RouteError::NotFound));
+    }
+
+    #[test]
+    fn status_is_json() {
+        let (status, content_type, body) = respond(
+            &mut FakeSign::default(),
+            &request(Method::Get, "/status", ""),
+        );
+        assert_eq!((status, content_type), (200, JSON));
+        assert_eq!(
+            body,
+            concat!(
+                r#"{"uptime":42,"ssid":"home","rssi":-61,"#,
+                r#""time":{"synced":true,"local":"2024-06-12T08:30:00","utc_offset":-240},"#,
+                r#""last_fetch":{"stop":"place-davis","ok":false,"error":"HTTP","seconds_ago":5},"#,
+                r#""routes":[{"route":"87","direction":1,"stop":"place-davis","minutes":4,"error":null}],"#,
+                r#""last_panic":null}"#
+            )
+        );
+    }
+
+    #[test]
+    fn actions_reach_the_sign() {
+        let mut sign = FakeSign::default();
+        let ok = (200, JSON, String::from(r#"{"ok":true}"#));
+        assert_eq!(
+            respond(&mut sign, &request(Method::Post, "/brightness", "40")),
+            ok
+        );
+        assert_eq!(sign.brightness, Some(40));
+        assert_eq!(
+            respond(&mut sign, &request(Method::Post, "/mode", "off")),
+            ok
+        );
+        assert_eq!(sign.mode, Some(Some(Mode::Off)));
+        assert_eq!(
+            respond(&mut sign, &request(Method::Post, "/refresh", "")),
+            ok
+        );
+        assert_eq!(sign.refreshes, 1);
+
+        let (status, content_type, body) =
+            respond(&mut sign, &request(Method::Get, "/metrics", ""));
+        assert_eq!((status, content_type), (200, METRICS));
+        assert!(body.ends_with("sign_fetches_total 3\n"), "{body}");
+
+        assert_eq!(
+            respond(&mut sign, &request(Method::Post, "/mode", "loud")),
+            (400, JSON, String::from(r#"{"error":"bad value"}"#))
+        );
+        assert_eq!(
+            respond(&mut sign, &request(Method::Get, "/refresh", "")),
+            (405, JSON, String::from(r#"{"error":"method not allowed"}"#))
+        );
+        assert_eq!(
+            respond(&mut sign, &request(Method::Get, "/favicon.ico", "")),
+            (404, JSON, String::from(r#"{"error":"not found"}"#))
+        );
+    }
+
+    /// Connection handing over `input` a few bytes at a time and recording
+    /// the response
+    struct FakeConnection {
+        input: Vec<u8>,
+        chunk: usize,
+        output: Vec<u8>,
+    }
+
+    impl FakeConnection {
+        fn new(input: &[u8]) -> Self {
+            FakeConnection {
+                input: input.to_vec(),
+                chunk: 700,
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl ErrorType for FakeConnection {
+        type Error = ErrorKind;
+    }
+
+    impl Read for FakeConnection {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            let len = buf.len().min(self.input.len()).min(self.chunk);
+            buf[..len].copy_from_slice(&self.input[..len]);
+            self.input.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for FakeConnection {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn serve_one(sign: &mut FakeSign, input: &[u8]) -> String {
+        let mut connection = FakeConnection::new(input);
+        block_on(serve(&mut connection, sign)).unwrap();
+        String::from_utf8(connection.output).unwrap()
+    }
+
+    fn firmware_request(image: &[u8], signature: &str) -> Vec<u8> {
+        let mut request = std::format!(
+            "POST /firmware HTTP/1.1\r\nContent-Length: {}\r\nX-Signature: {}\r\n\r\n",
+            image.len(),
+            signature
+        )
+        .into_bytes();
+        request.extend_from_slice(image);
+        request
+    }
+
+    #[test]
+    fn serve_answers_in_http() {
+        let mut sign = FakeSign::default();
+        assert_eq!(
+            serve_one(
+                &mut sign,
+                b"POST /brightness HTTP/1.1\r\nContent-Length: 3\r\n\r\n100"
+            ),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 11\r\n\
+             Connection: close\r\n\r\n{\"ok\":true}"
+        );
+        assert_eq!(sign.brightness, Some(100));
+
+        assert!(serve_one(&mut sign, b"GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 "));
+        assert!(serve_one(&mut sign, b"hello\r\n\r\n").starts_with("HTTP/1.1 400 "));
+        assert!(serve_one(&mut sign, &[b'x'; REQUEST_SIZE]).starts_with("HTTP/1.1 413 "));
+        // closed half way through the headers
+        assert_eq!(serve_one(&mut sign, b"GET /status HTTP/1.1\r\n"), "");
+    }
+
+    #[test]
+    fn firmware_is_written_in_blocks() {
+        let image: Vec<u8> = (0..FIRMWARE_BLOCK + 100).map(|i| i as u8).collect();
+        let signature = "ab".repeat(64);
+        let mut sign = FakeSign {
+            capacity: Some(16 * 1024),
+            ..Default::default()
+        };
+        let response = serve_one(&mut sign, &firmware_request(&image, &signature));
+        assert!(response.starts_with("HTTP/1.1 200 "), "{response}");
+        assert_eq!(sign.image.len(), 2 * FIRMWARE_BLOCK);
+        assert_eq!(sign.image[..image.len()], image[..]);
+        assert!(sign.image[image.len()..].iter().all(|&byte| byte == 0xff));
+        assert_eq!(sign.finished, Some((image.len() as u32, [0xab; 64])));
+    }
+
+    #[test]
+    fn firmware_refused() {
+        let image = [0; 100];
+        let mut sign = FakeSign::default();
+        let response = serve_one(&mut sign, &firmware_request(&image, &"ab".repeat(64)));
+        assert!(response.ends_with(r#"{"error":"firmware updates are off"}"#));
+
+        sign.capacity = Some(64);
+        let response = serve_one(&mut sign, &firmware_request(&image, &"ab".repeat(64)));
+        assert!(response.starts_with("HTTP/1.1 413 "), "{response}");
+        sign.capacity = Some(4096);
+        let response = serve_one(&mut sign, &firmware_request(&image, "ab12"));
+        assert!(response.starts_with("HTTP/1.1 400 "), "{response}");
+        let response = serve_one(&mut sign, &firmware_request(&image, &"cd".repeat(64)));
+        assert!(
+            response.ends_with(r#"{"error":"bad signature"}"#),
+            "{response}"
+        );
+        assert_eq!(sign.finished, None);
+        // closed before the whole image arrived
+        let request = firmware_request(&image, &"ab".repeat(64));
+        assert_eq!(serve_one(&mut sign, &request[..request.len() - 1]), "");
+    }
+}
@@ -0,0 +1,646 @@
+use core::fmt::Write as _;
+use core::str::FromStr;
+use embedded_io_async::{Read, Write};
+use log::LevelFilter;
+
+/// Longest line the console accepts
+pub const LINE_SIZE: usize = 96;
+
+/// Text produced by one command
+pub type Output = heapless::String<1024>;
+
+const HELP: &str = "\
+status                  uptime and network
+config get [key]        show the stored config, or one key
+config set <key> <val>  store a new value, applied on reboot
+wifi scan               list nearby networks
+fetch now               refresh every route
+time                    current time from the RTC
+reboot                  restart the sign
+log level [level]       show or set off/error/warn/info/debug/trace
+log syslog [server|off] show or set host[:port] to send the log to
+log syslog level <lvl>  send only entries at lvl and below";
+
+/// What the console can ask of the sign, so the console itself can run
+/// against a fake on the host
+#[allow(async_fn_in_trait)]
+pub trait Sign {
+    type Config: Settings;
+
+    /// Uptime and network summary
+    async fn status(&mut self, out: &mut Output);
+    /// The stored configuration, including unapplied changes
+    async fn config(&mut self) -> Self::Config;
+    async fn save_config(&mut self, config: &Self::Config) -> Result<(), SetError>;
+    /// One line per network found
+    async fn scan_wifi(&mut self, out: &mut Output);
+    /// Wake every route to fetch predictions immediately
+    fn fetch_now(&mut self);
+    /// The current time, or that the clock isn't set yet
+    async fn time(&mut self, out: &mut Output);
+    fn reboot(&mut self) -> !;
+    /// Where the log is sent by syslog, and at what level
+    async fn syslog(&mut self, out: &mut Output);
+    /// Send the log to `host` and `port`, or stop with `None`. False when
+    /// the host name is too long.
+    async fn set_syslog(&mut self, target: Option<(&str, u16)>) -> bool;
+    fn set_syslog_level(&mut self, level: LevelFilter);
+}
+
+/// Stored settings, read and written by key with `config get` and `set`
+pub trait Settings {
+    /// Keys accepted by `get` and `set`, in the order they are listed
+    const KEYS: &'static [&'static str];
+
+    fn get(&self, key: &str) -> Option<&str>;
+    fn set(&mut self, key: &str, value: &str) -> Result<(), SetError>;
+}
+
+/// Why `config set` didn't store a value
+#[derive(Debug, PartialEq)]
+pub enum SetError {
+    UnknownKey,
+    TooLarge,
+    Invalid,
+    /// writing to flash failed
+    Save,
+}
+
+/// A parsed console command
+#[derive(Debug, PartialEq)]
+pub enum Command<'a> {
+    Help,
+    Status,
+    ConfigGet(Option<&'a str>),
+    ConfigSet(&'a str, &'a str),
+    WifiScan,
+    FetchNow,
+    Time,
+    Reboot,
+    LogLevel(Option<LevelFilter>),
+    Syslog(SyslogCommand<'a>),
+}
+
+/// `log syslog` and what follows it
+#[derive(Debug, PartialEq)]
+pub enum SyslogCommand<'a> {
+    Show,
+    Off,
+    Target(&'a str, u16),
+    Level(LevelFilter),
+}
+
+/// Port syslog servers listen on unless told otherwise
+const SYSLOG_PORT: u16 = 514;
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    Empty,
+    Unknown,
+    MissingArgument,
+    BadLevel,
+    BadTarget,
+}
+
+impl ParseError {
+    fn message(&self) -> &'static str {
+        match self {
+            ParseError::Empty => "",
+            ParseError::Unknown => "unknown command, try help",
+            ParseError::MissingArgument => "missing argument, try help",
+            ParseError::BadLevel => "level is one of off, error, warn, info, debug, trace",
+            ParseError::BadTarget => "server is host, host:port or [ipv6]:port",
+        }
+    }
+}
+
+impl<'a> Command<'a> {
+    pub fn parse(line: &'a str) -> Result<Self, ParseError> {
+        let mut words = line.split_whitespace();
+        let command = match (words.next(), words.next()) {
+            (None, _) => return Err(ParseError::Empty),
+            (Some("help" | "?"), None) => Command::Help,
+            (Some("status"), None) => Command::Status,
+            (Some("config"), Some("get")) => Command::ConfigGet(words.next()),
+            (Some("config"), Some("set")) => {
+                // the value is the rest of the line, so SSIDs can have spaces
+                let rest = line.trim_start()["config".len()..].trim_start()["set".len()..].trim();
+                let (key, value) = match rest.split_once(char::is_whitespace) {
+                    Some((key, value)) => (key, value.trim()),
+                    None if !rest.is_empty() => (rest, ""),
+                    None => return Err(ParseError::MissingArgument),
+                };
+                return Ok(Command::ConfigSet(key, value));
+            }
+            (Some("config"), _) => return Err(ParseError::MissingArgument),
+            (Some("wifi"), Some("scan")) => Command::WifiScan,
+            (Some("fetch"), Some("now")) => Command::FetchNow,
+            (Some("time"), None) => Command::Time,
+            (Some("reboot"), None) => Command::Reboot,
+            (Some("log"), Some("level")) => match words.next() {
+                Some(level) => Command::LogLevel(Some(
+                    LevelFilter::from_str(level).map_err(|_| ParseError::BadLevel)?,
+                )),
+                None => Command::LogLevel(None),
+            },
+            (Some("log"), Some("syslog")) => Command::Syslog(match words.next() {
+                None => SyslogCommand::Show,
+                Some("off") => SyslogCommand::Off,
+                Some("level") => SyslogCommand::Level(
+                    LevelFilter::from_str(words.next().ok_or(ParseError::MissingArgument)?)
+                        .map_err(|_| ParseError::BadLevel)?,
+                ),
+                Some(target) => {
+                    let (host, port) = parse_target(target).ok_or(ParseError::BadTarget)?;
+                    SyslogCommand::Target(host, port)
+                }
+            }),
+            _ => return Err(ParseError::Unknown),
+        };
+        match words.next() {
+            Some(_) => Err(ParseError::Unknown),
+            None => Ok(command),
+        }
+    }
+}
+
+/// Split `host`, `host:port` or `[ipv6]:port`, a bare IPv6 address is all
+/// host
+fn parse_target(target: &str) -> Option<(&str, u16)> {
+    let (host, port) = if let Some(rest) = target.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        match rest {
+            "" => (host, None),
+            _ => (host, Some(rest.strip_prefix(':')?)),
+        }
+    } else {
+        match target.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (target, None),
+        }
+    };
+    let port = match port {
+        Some(port) => port.parse().ok().filter(|port| *port != 0)?,
+        None => SYSLOG_PORT,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host, port))
+}
+
+/// What the terminal should do after a byte is fed to the editor
+#[derive(Debug, PartialEq)]
+pub enum Feed {
+    Ignored,
+    /// echo the character back
+    Echo(u8),
+    /// rub out the last character
+    Erase,
+    /// the line was discarded
+    Cancel,
+    /// `LineEditor::line` holds a complete line
+    Line,
+}
+
+/// Collects typed characters into a line, handling backspace, Ctrl-C and
+/// Ctrl-U, and CR, LF or CRLF line endings
+pub struct LineEditor<const N: usize> {
+    line: heapless::String<N>,
+    /// a CR ended the last line, skip the LF that may follow it
+    after_cr: bool,
+}
+
+impl<const N: usize> LineEditor<N> {
+    pub fn new() -> Self {
+        LineEditor {
+            line: heapless::String::new(),
+            after_cr: false,
+        }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Feed {
+        let after_cr = core::mem::replace(&mut self.after_cr, false);
+        match byte {
+            b'\r' => {
+                self.after_cr = true;
+                Feed::Line
+            }
+            b'\n' if after_cr => Feed::Ignored,
+            b'\n' => Feed::Line,
+            0x08 | 0x7f => match self.line.pop() {
+                Some(_) => Feed::Erase,
+                None => Feed::Ignored,
+            },
+            0x03 | 0x15 => {
+                self.line.clear();
+                Feed::Cancel
+            }
+            0x20..=0x7e => match self.line.push(byte as char) {
+                Ok(()) => Feed::Echo(byte),
+                Err(()) => Feed::Ignored,
+            },
+            _ => Feed::Ignored,
+        }
+    }
+
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    pub fn clear(&mut self) {
+        self.line.clear();
+    }
+}
+
+impl<const N: usize> Default for LineEditor<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serve the console on `port` until it closes
+pub async fn run<P: Read + Write, S: Sign>(port: &mut P, sign: &mut S) -> Result<(), P::Error> {
+    let mut editor = LineEditor::<LINE_SIZE>::new();
+    let mut input = [0; 64];
+    port.write_all(b"\r\nbus-sign console, type help\r\n> ")
+        .await?;
+
+    loop {
+        let len = port.read(&mut input).await?;
+        if len == 0 {
+            return Ok(());
+        }
+
+        for &byte in &input[..len] {
+            match editor.feed(byte) {
+                Feed::Ignored => {}
+                Feed::Echo(byte) => port.write_all(&[byte]).await?,
+                Feed::Erase => port.write_all(b"\x08 \x08").await?,
+                Feed::Cancel => port.write_all(b"^C\r\n> ").await?,
+                Feed::Line => {
+                    port.write_all(b"\r\n").await?;
+                    let mut out = Output::new();
+                    execute(editor.line(), sign, &mut out).await;
+                    for line in out.lines() {
+                        port.write_all(line.as_bytes()).await?;
+                        port.write_all(b"\r\n").await?;
+                    }
+                    port.write_all(b"> ").await?;
+                    editor.clear();
+                }
+            }
+        }
+    }
+}
+
+/// Run one command line, output that doesn't fit in `out` is dropped
+pub async fn execute<S: Sign>(line: &str, sign: &mut S, out: &mut Output) {
+    let command = match Command::parse(line) {
+        Ok(command) => command,
+        Err(ParseError::Empty) => return,
+        Err(e) => {
+            writeln!(out, "{}", e.message()).ok();
+            return;
+        }
+    };
+
+    match command {
+        Command::Help => {
+            writeln!(out, "{}", HELP).ok();
+        }
+        Command::Status => sign.status(out).await,
+        Command::ConfigGet(key) => {
+            let config = sign.config().await;
+            for &name in S::Config::KEYS {
+                if key.is_some_and(|key| key != name) {
+                    continue;
+                }
+                let value = config.get(name).unwrap_or("");
+                if name == "wifi_password" && !value.is_empty() {
+                    writeln!(out, "{} = ********", name).ok();
+                } else {
+                    writeln!(out, "{} = {}", name, value).ok();
+                }
+            }
+            if key.is_some_and(|key| config.get(key).is_none()) {
+                writeln!(out, "unknown key, one of {:?}", S::Config::KEYS).ok();
+            }
+        }
+        Command::ConfigSet(key, value) => {
+            let mut config = sign.config().await;
+            let result = match config.set(key, value) {
+                Ok(()) => sign.save_config(&config).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => writeln!(out, "saved, reboot to apply").ok(),
+                Err(SetError::UnknownKey) => {
+                    writeln!(out, "unknown key, one of {:?}", S::Config::KEYS).ok()
+                }
+                Err(SetError::TooLarge) => writeln!(out, "value too long").ok(),
+                Err(SetError::Invalid) => writeln!(out, "invalid value").ok(),
+                Err(SetError::Save) => writeln!(out, "failed to save").ok(),
+            };
+        }
+        Command::WifiScan => sign.scan_wifi(out).await,
+        Command::FetchNow => {
+            sign.fetch_now();
+            writeln!(out, "fetching").ok();
+        }
+        Command::Time => sign.time(out).await,
+        Command::Reboot => sign.reboot(),
+        Command::LogLevel(Some(level)) => {
+            log::set_max_level(level);
+            writeln!(out, "log level {}", level).ok();
+        }
+        Command::LogLevel(None) => {
+            writeln!(out, "log level {}", log::max_level()).ok();
+        }
+        Command::Syslog(SyslogCommand::Show) => sign.syslog(out).await,
+        Command::Syslog(SyslogCommand::Off) => {
+            sign.set_syslog(None).await;
+            writeln!(out, "syslog off").ok();
+        }
+        Command::Syslog(SyslogCommand::Target(host, port)) => {
+            if sign.set_syslog(Some((host, port))).await {
+                sign.syslog(out).await;
+            } else {
+                writeln!(out, "host name too long").ok();
+            }
+        }
+        Command::Syslog(SyslogCommand::Level(level)) => {
+            sign.set_syslog_level(level);
+            writeln!(out, "syslog level {}", level).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+    use embedded_io_async::{ErrorKind, ErrorType};
+    use std::string::String;
+    use std::vec::Vec;
+
+    #[derive(Clone, Default)]
+    struct FakeConfig {
+        ssid: String,
+        password: String,
+    }
+
+    impl Settings for FakeConfig {
+        const KEYS: &'static [&'static str] = &["wifi_ssid", "wifi_password"];
+
+        fn get(&self, key: &str) -> Option<&str> {
+            match key {
+                "wifi_ssid" => Some(&self.ssid),
+                "wifi_password" => Some(&self.password),
+                _ => None,
+            }
+        }
+
+        fn set(&mut self, key: &str, value: &str) -> Result<(), SetError> {
+            let field = match key {
+                "wifi_ssid" => &mut self.ssid,
+                "wifi_password" => &mut self.password,
+                _ => return Err(SetError::UnknownKey),
+            };
+            if value.len() > 32 {
+                return Err(SetError::TooLarge);
+            }
+            *field = value.into();
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeSign {
+        config: FakeConfig,
+        fetches: usize,
+        syslog: Option<(String, u16)>,
+    }
+
+    impl Sign for FakeSign {
+        type Config = FakeConfig;
+
+        async fn status(&mut self, out: &mut Output) {
+            writeln!(out, "fine").ok();
+        }
+
+        async fn config(&mut self) -> FakeConfig {
+            self.config.clone()
+        }
+
+        async fn save_config(&mut self, config: &FakeConfig) -> Result<(), SetError> {
+            self.config = config.clone();
+            Ok(())
+        }
+
+        async fn scan_wifi(&mut self, out: &mut Output) {
+            writeln!(out, " -60 dBm  home").ok();
+        }
+
+        fn fetch_now(&mut self) {
+            self.fetches += 1;
+        }
+
+        async fn time(&mut self, out: &mut Output) {
+            writeln!(out, "clock not set yet").ok();
+        }
+
+        fn reboot(&mut self) -> ! {
+            panic!("rebooted");
+        }
+
+        async fn syslog(&mut self, out: &mut Output) {
+            match &self.syslog {
+                Some((host, port)) => writeln!(out, "syslog to {}:{}", host, port).ok(),
+                None => writeln!(out, "syslog off").ok(),
+            };
+        }
+
+        async fn set_syslog(&mut self, target: Option<(&str, u16)>) -> bool {
+            self.syslog = target.map(|(host, port)| (host.into(), port));
+            true
+        }
+
+        fn set_syslog_level(&mut self, _level: LevelFilter) {}
+    }
+
+    fn run_line(sign: &mut FakeSign, line: &str) -> Output {
+        let mut out = Output::new();
+        block_on(execute(line, sign, &mut out));
+        out
+    }
+
+    #[test]
+    fn editor_handles_line_endings_and_control_keys() {
+        let mut editor = LineEditor::<4>::new();
+        assert_eq!(editor.feed(b'a'), Feed::Echo(b'a'));
+        assert_eq!(editor.feed(b'b'), Feed::Echo(b'b'));
+        assert_eq!(editor.feed(0x7f), Feed::Erase);
+        assert_eq!(editor.feed(0x1b), Feed::Ignored);
+        assert_eq!(editor.feed(b'\r'), Feed::Line);
+        assert_eq!(editor.line(), "a");
+        editor.clear();
+        assert_eq!(editor.feed(b'\n'), Feed::Ignored);
+        assert_eq!(editor.feed(b'\n'), Feed::Line);
+        assert_eq!(editor.feed(0x08), Feed::Ignored);
+
+        for byte in *b"wxyz" {
+            editor.feed(byte);
+        }
+        assert_eq!(editor.feed(b'!'), Feed::Ignored);
+        assert_eq!(editor.line(), "wxyz");
+        assert_eq!(editor.feed(0x03), Feed::Cancel);
+        assert_eq!(editor.line(), "");
+    }
+
+    #[test]
+    fn commands_parse() {
+        assert_eq!(Command::parse("  "), Err(ParseError::Empty));
+        assert_eq!(Command::parse("?"), Ok(Command::Help));
+        assert_eq!(Command::parse("status now"), Err(ParseError::Unknown));
+        assert_eq!(Command::parse("config get"), Ok(Command::ConfigGet(None)));
+        assert_eq!(
+            Command::parse("config get routes"),
+            Ok(Command::ConfigGet(Some("routes")))
+        );
+        assert_eq!(
+            Command::parse(" config  set wifi_ssid  My Home Wi-Fi "),
+            Ok(Command::ConfigSet("wifi_ssid", "My Home Wi-Fi"))
+        );
+        assert_eq!(
+            Command::parse("config set proxy_ip"),
+            Ok(Command::ConfigSet("proxy_ip", ""))
+        );
+        assert_eq!(
+            Command::parse("config set"),
+            Err(ParseError::MissingArgument)
+        );
+        assert_eq!(Command::parse("config"), Err(ParseError::MissingArgument));
+        assert_eq!(
+            Command::parse("log level debug"),
+            Ok(Command::LogLevel(Some(LevelFilter::Debug)))
+        );
+        assert_eq!(Command::parse("log level loud"), Err(ParseError::BadLevel));
+        assert_eq!(
+            Command::parse("log syslog level warn"),
+            Ok(Command::Syslog(SyslogCommand::Level(LevelFilter::Warn)))
+        );
+        assert_eq!(Command::parse("wifi"), Err(ParseError::Unknown));
+    }
+
+    #[test]
+    fn syslog_targets_parse() {
+        let target = |line| match Command::parse(line) {
+            Ok(Command::Syslog(SyslogCommand::Target(host, port))) => Some((host, port)),
+            _ => None,
+        };
+        assert_eq!(target("log syslog logs.lan"), Some(("logs.lan", 514)));
+        assert_eq!(target("log syslog 10.0.0.2:1514"), Some(("10.0.0.2", 1514)));
+        assert_eq!(target("log syslog fd00::2"), Some(("fd00::2", 514)));
+        assert_eq!(target("log syslog [fd00::2]:1514"), Some(("fd00::2", 1514)));
+        assert_eq!(target("log syslog [fd00::2]"), Some(("fd00::2", 514)));
+        assert_eq!(
+            Command::parse("log syslog logs.lan:0"),
+            Err(ParseError::BadTarget)
+        );
+        assert_eq!(
+            Command::parse("log syslog :514"),
+            Err(ParseError::BadTarget)
+        );
+        assert_eq!(
+            Command::parse("log syslog off"),
+            Ok(Command::Syslog(SyslogCommand::Off))
+        );
+    }
+
+    #[test]
+    fn config_set_and_get() {
+        let mut sign = FakeSign::default();
+        assert_eq!(
+            run_line(&mut sign, "config set wifi_ssid Home Net"),
+            "saved, reboot to apply\n"
+        );
+        run_line(&mut sign, "config set wifi_password hunter22");
+        assert_eq!(sign.config.password, "hunter22");
+        assert_eq!(
+            run_line(&mut sign, "config get"),
+            "wifi_ssid = Home Net\nwifi_password = ********\n"
+        );
+        assert_eq!(
+            run_line(&mut sign, "config get wifi_ssid"),
+            "wifi_ssid = Home Net\n"
+        );
+        assert_eq!(
+            run_line(&mut sign, "config get color"),
+            "unknown key, one of [\"wifi_ssid\", \"wifi_password\"]\n"
+        );
+        assert_eq!(
+            run_line(
+                &mut sign,
+                &std::format!("config set wifi_ssid {}", "x".repeat(33))
+            ),
+            "value too long\n"
+        );
+        assert_eq!(sign.config.ssid, "Home Net");
+    }
+
+    #[test]
+    fn commands_reach_the_sign() {
+        let mut sign = FakeSign::default();
+        assert_eq!(run_line(&mut sign, "fetch now"), "fetching\n");
+        assert_eq!(sign.fetches, 1);
+        assert_eq!(run_line(&mut sign, "time"), "clock not set yet\n");
+        assert_eq!(
+            run_line(&mut sign, "log syslog logs.lan:1514"),
+            "syslog to logs.lan:1514\n"
+        );
+        assert_eq!(run_line(&mut sign, "log syslog off"), "syslog off\n");
+        assert_eq!(sign.syslog, None);
+        assert_eq!(run_line(&mut sign, "bogus"), "unknown command, try help\n");
+        assert_eq!(run_line(&mut sign, ""), "");
+    }
+
+    /// Serial port replaying typed input and recording what is echoed
+    struct FakePort {
+        input: Vec<u8>,
+        output: Vec<u8>,
+    }
+
+    impl ErrorType for FakePort {
+        type Error = ErrorKind;
+    }
+
+    impl Read for FakePort {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            let len = buf.len().min(self.input.len());
+            buf[..len].copy_from_slice(&self.input[..len]);
+            self.input.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for FakePort {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn session_echoes_and_answers() {
+        let mut port = FakePort {
+            input: b"stx\x7fatus\r\n\x03".to_vec(),
+            output: Vec::new(),
+        };
+        block_on(run(&mut port, &mut FakeSign::default())).unwrap();
+        assert_eq!(
+            String::from_utf8(port.output).unwrap(),
+            "\r\nbus-sign console, type help\r\n> stx\x08 \x08atus\r\nfine\r\n> ^C\r\n> "
+        );
+    }
+}
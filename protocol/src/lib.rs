@@ -0,0 +1,12 @@
+//! The protocols the sign speaks that don't touch the hardware, kept apart
+//! from the firmware so they build and are tested on the host:
+//!
+//! ```sh
+//! cargo test --target x86_64-unknown-linux-gnu
+//! ```
+#![cfg_attr(not(test), no_std)]
+
+pub mod api;
+pub mod console;
+pub mod mqtt;
+pub mod tls12;
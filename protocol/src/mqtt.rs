@@ -0,0 +1,696 @@
+//! Minimal MQTT 3.1.1 client: QoS 0 publish and subscribe, keep-alive pings
+//! and a last will, which is all Home Assistant needs. Works over any
+//! `Read + Write` connection, so it is tested on the host against a
+//! scripted broker.
+
+use core::fmt::Write as _;
+use embedded_io_async::{Read, Write};
+
+/// Largest packet sent or received, enough for the discovery messages of
+/// the longest topic and client ID
+pub const BUFFER_SIZE: usize = 768;
+
+/// Longest topic prefix, discovery prefix included, that build.rs accepts
+pub const MAX_TOPIC_LEN: usize = 32;
+
+/// Longest client ID, also the Home Assistant node ID, that build.rs
+/// accepts
+pub const MAX_CLIENT_ID_LEN: usize = 23;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+/// PUBLISH flag asking the broker to keep the message for new subscribers
+const RETAIN: u8 = 0x01;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Io(E),
+    /// the broker closed the connection
+    Closed,
+    /// the broker refused the connection, with its return code
+    Refused(u8),
+    /// a packet doesn't fit in `BUFFER_SIZE`
+    TooLarge,
+    /// the broker sent something that isn't MQTT
+    Protocol,
+}
+
+/// What `Client::connect` sends
+pub struct ConnectOptions<'a> {
+    pub client_id: &'a str,
+    /// empty for none
+    pub username: &'a str,
+    pub password: &'a str,
+    /// seconds, the broker drops the client after one and a half times this
+    /// without a packet
+    pub keep_alive: u16,
+    /// retained message the broker publishes when the connection is lost
+    pub will: Option<(&'a str, &'a [u8])>,
+}
+
+/// Packet received from the broker
+#[derive(Debug, PartialEq)]
+pub enum Packet<'a> {
+    ConnAck {
+        return_code: u8,
+    },
+    Publish {
+        topic: &'a str,
+        payload: &'a [u8],
+    },
+    SubAck,
+    PingResp,
+    /// anything else, by its type
+    Other(u8),
+}
+
+type Buffer = heapless::Vec<u8, BUFFER_SIZE>;
+
+// Packets are built whole before sending, `None` when one doesn't fit in
+// `BUFFER_SIZE`
+
+fn push_length(out: &mut Buffer, mut length: usize) -> Option<()> {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte).ok()?;
+        if length == 0 {
+            return Some(());
+        }
+    }
+}
+
+fn push_bytes(out: &mut Buffer, bytes: &[u8]) -> Option<()> {
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes())
+        .ok()?;
+    out.extend_from_slice(bytes).ok()
+}
+
+/// Fixed header for `body` followed by the body
+fn packet(first: u8, body: &[u8]) -> Option<Buffer> {
+    let mut out = Buffer::new();
+    out.push(first).ok()?;
+    push_length(&mut out, body.len())?;
+    out.extend_from_slice(body).ok()?;
+    Some(out)
+}
+
+pub fn connect_packet(options: &ConnectOptions) -> Option<Buffer> {
+    let mut flags = 0x02; // clean session
+    if !options.username.is_empty() {
+        flags |= 0x80;
+        if !options.password.is_empty() {
+            flags |= 0x40;
+        }
+    }
+    if options.will.is_some() {
+        flags |= 0x20 | 0x04; // retained will, QoS 0
+    }
+
+    let mut body = Buffer::new();
+    push_bytes(&mut body, b"MQTT")?;
+    body.extend_from_slice(&[4, flags]).ok()?;
+    body.extend_from_slice(&options.keep_alive.to_be_bytes())
+        .ok()?;
+    push_bytes(&mut body, options.client_id.as_bytes())?;
+    if let Some((topic, message)) = options.will {
+        push_bytes(&mut body, topic.as_bytes())?;
+        push_bytes(&mut body, message)?;
+    }
+    if !options.username.is_empty() {
+        push_bytes(&mut body, options.username.as_bytes())?;
+        if !options.password.is_empty() {
+            push_bytes(&mut body, options.password.as_bytes())?;
+        }
+    }
+    packet(CONNECT, &body)
+}
+
+pub fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Option<Buffer> {
+    let mut body = Buffer::new();
+    push_bytes(&mut body, topic.as_bytes())?;
+    body.extend_from_slice(payload).ok()?;
+    packet(PUBLISH | if retain { RETAIN } else { 0 }, &body)
+}
+
+/// Subscribe to `topic` at QoS 0
+pub fn subscribe_packet(packet_id: u16, topic: &str) -> Option<Buffer> {
+    let mut body = Buffer::new();
+    body.extend_from_slice(&packet_id.to_be_bytes()).ok()?;
+    push_bytes(&mut body, topic.as_bytes())?;
+    body.push(0).ok()?;
+    packet(SUBSCRIBE, &body)
+}
+
+/// Length of the whole packet at the start of `buffer`, `None` until its
+/// fixed header has arrived
+fn packet_len(buffer: &[u8]) -> Result<Option<usize>, ()> {
+    let mut length = 0;
+    for (index, &byte) in buffer.iter().enumerate().skip(1).take(4) {
+        length += ((byte & 0x7f) as usize) << (7 * (index - 1));
+        if byte & 0x80 == 0 {
+            return Ok(Some(index + 1 + length));
+        }
+    }
+    if buffer.len() > 4 {
+        // a remaining length is at most four bytes
+        Err(())
+    } else {
+        Ok(None)
+    }
+}
+
+impl<'a> Packet<'a> {
+    /// Parse one whole packet, as measured by `packet_len`
+    fn parse(packet: &'a [u8]) -> Result<Self, ()> {
+        let header = packet
+            .iter()
+            .skip(1)
+            .position(|byte| byte & 0x80 == 0)
+            .ok_or(())?
+            + 2;
+        let body = &packet[header..];
+        Ok(match packet[0] & 0xf0 {
+            CONNACK => Packet::ConnAck {
+                return_code: *body.get(1).ok_or(())?,
+            },
+            PUBLISH => {
+                let topic_len =
+                    u16::from_be_bytes([*body.first().ok_or(())?, *body.get(1).ok_or(())?])
+                        as usize;
+                let topic = body.get(2..2 + topic_len).ok_or(())?;
+                let topic = core::str::from_utf8(topic).map_err(|_| ())?;
+                // QoS 1 and 2 carry a packet ID
+                let id_len = if packet[0] & 0x06 != 0 { 2 } else { 0 };
+                let payload = body.get(2 + topic_len + id_len..).ok_or(())?;
+                Packet::Publish { topic, payload }
+            }
+            SUBACK => Packet::SubAck,
+            PINGRESP => Packet::PingResp,
+            other => Packet::Other(other),
+        })
+    }
+}
+
+pub struct Client<C> {
+    connection: C,
+    buffer: [u8; BUFFER_SIZE],
+    len: usize,
+    /// length of the packet last returned by `receive`
+    consumed: usize,
+    next_packet_id: u16,
+}
+
+impl<C: Read + Write> Client<C> {
+    pub fn new(connection: C) -> Self {
+        Client {
+            connection,
+            buffer: [0; BUFFER_SIZE],
+            len: 0,
+            consumed: 0,
+            next_packet_id: 1,
+        }
+    }
+
+    async fn send(&mut self, packet: Option<Buffer>) -> Result<(), Error<C::Error>> {
+        let packet = packet.ok_or(Error::TooLarge)?;
+        self.connection
+            .write_all(&packet)
+            .await
+            .map_err(Error::Io)?;
+        self.connection.flush().await.map_err(Error::Io)
+    }
+
+    /// Send CONNECT and wait for the broker to accept it
+    pub async fn connect(&mut self, options: &ConnectOptions<'_>) -> Result<(), Error<C::Error>> {
+        self.send(connect_packet(options)).await?;
+        match self.receive().await? {
+            Packet::ConnAck { return_code: 0 } => Ok(()),
+            Packet::ConnAck { return_code } => Err(Error::Refused(return_code)),
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    pub async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> Result<(), Error<C::Error>> {
+        self.send(publish_packet(topic, payload, retain)).await
+    }
+
+    /// Subscribe to `topic`, the SUBACK arrives through `receive`
+    pub async fn subscribe(&mut self, topic: &str) -> Result<(), Error<C::Error>> {
+        let packet_id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        self.send(subscribe_packet(packet_id, topic)).await
+    }
+
+    pub async fn ping(&mut self) -> Result<(), Error<C::Error>> {
+        self.send(packet(PINGREQ, &[])).await
+    }
+
+    pub async fn disconnect(&mut self) -> Result<(), Error<C::Error>> {
+        self.send(packet(DISCONNECT, &[])).await
+    }
+
+    /// Wait for the next packet from the broker. Safe to cancel, a
+    /// partly read packet stays buffered for the next call.
+    pub async fn receive(&mut self) -> Result<Packet<'_>, Error<C::Error>> {
+        if self.consumed > 0 {
+            self.buffer.copy_within(self.consumed..self.len, 0);
+            self.len -= self.consumed;
+            self.consumed = 0;
+        }
+
+        let len = loop {
+            match packet_len(&self.buffer[..self.len]) {
+                Ok(Some(len)) if len > BUFFER_SIZE => return Err(Error::TooLarge),
+                Ok(Some(len)) if len <= self.len => break len,
+                Ok(_) => {}
+                Err(()) => return Err(Error::Protocol),
+            }
+            let read = self
+                .connection
+                .read(&mut self.buffer[self.len..])
+                .await
+                .map_err(Error::Io)?;
+            if read == 0 {
+                return Err(Error::Closed);
+            }
+            self.len += read;
+        };
+
+        self.consumed = len;
+        Packet::parse(&self.buffer[..len]).map_err(|_| Error::Protocol)
+    }
+}
+
+/// Topics under the sign's prefix, eg "bus-sign/brightness/set", long
+/// enough for any within `MAX_TOPIC_LEN` and `MAX_CLIENT_ID_LEN`
+pub type Topic = heapless::String<96>;
+
+pub fn topic(prefix: &str, suffix: core::fmt::Arguments) -> Topic {
+    let mut topic = Topic::new();
+    write!(topic, "{}/{}", prefix, suffix).ok();
+    topic
+}
+
+/// Command received on one of the sign's `.../set` topics
+#[derive(Debug, PartialEq)]
+pub enum Command<'a> {
+    Power(bool),
+    Brightness(u8),
+    /// shown instead of the routes, empty to clear
+    Message(&'a str),
+}
+
+impl<'a> Command<'a> {
+    /// The command published to `topic`, if it is one of the sign's
+    pub fn parse(prefix: &str, topic: &str, payload: &'a [u8]) -> Option<Self> {
+        let payload = core::str::from_utf8(payload).ok()?;
+        match topic.strip_prefix(prefix)?.strip_prefix('/')? {
+            "set" => match payload {
+                "ON" => Some(Command::Power(true)),
+                "OFF" => Some(Command::Power(false)),
+                _ => None,
+            },
+            "brightness/set" => payload.trim().parse().ok().map(Command::Brightness),
+            "message/set" => Some(Command::Message(payload)),
+            _ => None,
+        }
+    }
+}
+
+/// Home Assistant discovery messages, published retained to
+/// `<discovery_prefix>/<component>/<object_id>/config`, `None` when one
+/// doesn't fit in a `Payload`
+pub struct Discovery<'a> {
+    pub discovery_prefix: &'a str,
+    /// sign topic prefix
+    pub prefix: &'a str,
+    /// unique per sign, used in entity IDs
+    pub node_id: &'a str,
+}
+
+pub type Payload = heapless::String<BUFFER_SIZE>;
+
+impl Discovery<'_> {
+    fn device(&self, out: &mut Payload) -> Option<()> {
+        write!(
+            out,
+            "\"availability_topic\":\"{p}/availability\",\
+             \"device\":{{\"identifiers\":[\"{id}\"],\"name\":\"Bus sign\",\
+             \"manufacturer\":\"Pimoroni\",\"model\":\"Galactic Unicorn\"}}}}",
+            p = self.prefix,
+            id = self.node_id
+        )
+        .ok()
+    }
+
+    /// Display power and brightness as a light
+    pub fn light(&self) -> Option<(Topic, Payload)> {
+        let mut out = Payload::new();
+        write!(
+            out,
+            "{{\"name\":\"Display\",\"unique_id\":\"{id}_display\",\
+             \"command_topic\":\"{p}/set\",\"state_topic\":\"{p}/state\",\
+             \"brightness_command_topic\":\"{p}/brightness/set\",\
+             \"brightness_state_topic\":\"{p}/brightness\",\"brightness_scale\":255,\
+             \"on_command_type\":\"first\",",
+            p = self.prefix,
+            id = self.node_id
+        )
+        .ok()?;
+        self.device(&mut out)?;
+        let topic = topic(
+            self.discovery_prefix,
+            format_args!("light/{}/display/config", self.node_id),
+        );
+        Some((topic, out))
+    }
+
+    /// Text shown instead of the routes
+    pub fn message(&self, max: usize) -> Option<(Topic, Payload)> {
+        let mut out = Payload::new();
+        write!(
+            out,
+            "{{\"name\":\"Message\",\"unique_id\":\"{id}_message\",\
+             \"command_topic\":\"{p}/message/set\",\"state_topic\":\"{p}/message\",\
+             \"min\":0,\"max\":{max},",
+            p = self.prefix,
+            id = self.node_id
+        )
+        .ok()?;
+        self.device(&mut out)?;
+        let topic = topic(
+            self.discovery_prefix,
+            format_args!("text/{}/message/config", self.node_id),
+        );
+        Some((topic, out))
+    }
+
+    /// Minutes until the next bus on display row `row`, counted from 1.
+    /// The state is the row's JSON, which also becomes its attributes.
+    pub fn row(&self, row: usize) -> Option<(Topic, Payload)> {
+        let mut out = Payload::new();
+        write!(
+            out,
+            "{{\"name\":\"Next bus {row}\",\"unique_id\":\"{id}_row{row}\",\
+             \"state_topic\":\"{p}/row/{row}\",\"json_attributes_topic\":\"{p}/row/{row}\",\
+             \"value_template\":\"{{{{ value_json.minutes }}}}\",\
+             \"unit_of_measurement\":\"min\",\"icon\":\"mdi:bus\",",
+            p = self.prefix,
+            id = self.node_id
+        )
+        .ok()?;
+        self.device(&mut out)?;
+        let topic = topic(
+            self.discovery_prefix,
+            format_args!("sensor/{}/row{}/config", self.node_id, row),
+        );
+        Some((topic, out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+    use embedded_io_async::{ErrorKind, ErrorType};
+    use std::vec::Vec;
+
+    /// Broker answering each read with the next of `replies`, closed once
+    /// they run out, and recording what the client sends
+    struct FakeBroker {
+        replies: Vec<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl FakeBroker {
+        fn new(replies: &[&[u8]]) -> Self {
+            FakeBroker {
+                replies: replies.iter().rev().map(|reply| reply.to_vec()).collect(),
+                sent: Vec::new(),
+            }
+        }
+    }
+
+    impl ErrorType for FakeBroker {
+        type Error = ErrorKind;
+    }
+
+    impl Read for FakeBroker {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            let Some(mut reply) = self.replies.pop() else {
+                return Ok(0);
+            };
+            let len = buf.len().min(reply.len());
+            buf[..len].copy_from_slice(&reply[..len]);
+            if len < reply.len() {
+                self.replies.push(reply.split_off(len));
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for FakeBroker {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.sent.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    const OPTIONS: ConnectOptions = ConnectOptions {
+        client_id: "sign",
+        username: "",
+        password: "",
+        keep_alive: 60,
+        will: None,
+    };
+
+    #[test]
+    fn connect_packets() {
+        assert_eq!(
+            connect_packet(&OPTIONS).unwrap(),
+            b"\x10\x10\0\x04MQTT\x04\x02\0\x3c\0\x04sign"[..]
+        );
+
+        let options = ConnectOptions {
+            username: "u",
+            password: "p",
+            will: Some(("s/availability", b"offline")),
+            ..OPTIONS
+        };
+        let expected = [
+            &b"\x10\x2f\0\x04MQTT\x04\xe6\0\x3c\0\x04sign"[..],
+            b"\0\x0es/availability\0\x07offline",
+            b"\0\x01u\0\x01p",
+        ]
+        .concat();
+        assert_eq!(connect_packet(&options).unwrap(), expected[..]);
+
+        // a password alone isn't sent
+        let options = ConnectOptions {
+            password: "p",
+            ..OPTIONS
+        };
+        assert_eq!(connect_packet(&options).unwrap()[9], 0x02);
+    }
+
+    #[test]
+    fn publish_and_subscribe_packets() {
+        assert_eq!(
+            publish_packet("s/state", b"ON", true).unwrap(),
+            b"\x31\x0b\0\x07s/stateON"[..]
+        );
+        assert_eq!(
+            subscribe_packet(0x0102, "s/+/set").unwrap(),
+            b"\x82\x0c\x01\x02\0\x07s/+/set\0"[..]
+        );
+
+        // 2 + 1 + 200 bytes of body take two bytes of remaining length
+        let packet = publish_packet("t", &[b'x'; 200], false).unwrap();
+        assert_eq!(packet[..5], b"\x30\xcb\x01\0\x01"[..]);
+        assert_eq!(packet.len(), 3 + 203);
+
+        assert_eq!(publish_packet("t", &[0; BUFFER_SIZE], false), None);
+    }
+
+    #[test]
+    fn packet_lengths() {
+        assert_eq!(packet_len(b""), Ok(None));
+        assert_eq!(packet_len(b"\x30"), Ok(None));
+        assert_eq!(packet_len(b"\xd0\0"), Ok(Some(2)));
+        assert_eq!(packet_len(b"\x30\xcb"), Ok(None));
+        assert_eq!(packet_len(b"\x30\xcb\x01"), Ok(Some(206)));
+        assert_eq!(
+            packet_len(b"\x30\xff\xff\xff\x7f"),
+            Ok(Some(5 + 268_435_455))
+        );
+        assert_eq!(packet_len(b"\x30\xff\xff\xff\xff\x01"), Err(()));
+    }
+
+    #[test]
+    fn packets_parse() {
+        assert_eq!(
+            Packet::parse(b"\x20\x02\0\x05"),
+            Ok(Packet::ConnAck { return_code: 5 })
+        );
+        assert_eq!(
+            Packet::parse(b"\x30\x06\0\x01tabc"),
+            Ok(Packet::Publish {
+                topic: "t",
+                payload: b"abc"
+            })
+        );
+        // QoS 1, with a packet ID before the payload
+        assert_eq!(
+            Packet::parse(b"\x32\x08\0\x01t\0\x07abc"),
+            Ok(Packet::Publish {
+                topic: "t",
+                payload: b"abc"
+            })
+        );
+        assert_eq!(Packet::parse(b"\x90\x03\0\x01\0"), Ok(Packet::SubAck));
+        assert_eq!(Packet::parse(b"\xd0\0"), Ok(Packet::PingResp));
+        assert_eq!(Packet::parse(b"\xb0\x02\0\x01"), Ok(Packet::Other(0xb0)));
+
+        assert_eq!(Packet::parse(b"\x20\x01\0"), Err(()));
+        assert_eq!(Packet::parse(b"\x30\x03\0\x09t"), Err(()));
+        assert_eq!(Packet::parse(b"\x30\x03\0\x01\xff"), Err(()));
+    }
+
+    #[test]
+    fn client_connects_and_receives() {
+        let mut broker = FakeBroker::new(&[
+            b"\x20\x02\0\0",
+            // a SUBACK and the start of a PUBLISH in one read, the rest in
+            // the next
+            b"\x90\x03\0\x01\0\x30\x09\0\x05s/set",
+            b"ON\xd0\0",
+        ]);
+        let mut client = Client::new(&mut broker);
+        block_on(async {
+            client.connect(&OPTIONS).await.unwrap();
+            client.subscribe("s/set").await.unwrap();
+            assert_eq!(client.receive().await.unwrap(), Packet::SubAck);
+            assert_eq!(
+                client.receive().await.unwrap(),
+                Packet::Publish {
+                    topic: "s/set",
+                    payload: b"ON"
+                }
+            );
+            client.ping().await.unwrap();
+            assert_eq!(client.receive().await.unwrap(), Packet::PingResp);
+            assert!(matches!(client.receive().await, Err(Error::Closed)));
+        });
+        let connect = connect_packet(&OPTIONS).unwrap();
+        assert_eq!(broker.sent[..connect.len()], connect[..]);
+        assert_eq!(
+            broker.sent[connect.len()..],
+            b"\x82\x0a\0\x01\0\x05s/set\0\xc0\0"[..]
+        );
+    }
+
+    #[test]
+    fn client_errors() {
+        let connect = |replies: &[&[u8]]| {
+            let mut broker = FakeBroker::new(replies);
+            let result = block_on(Client::new(&mut broker).connect(&OPTIONS));
+            result.map_err(|e| std::format!("{e:?}"))
+        };
+        assert_eq!(connect(&[b"\x20\x02\0\x05"]), Err("Refused(5)".into()));
+        assert_eq!(connect(&[b"\xd0\0"]), Err("Protocol".into()));
+        assert_eq!(connect(&[b"\x20\x02"]), Err("Closed".into()));
+        assert_eq!(connect(&[b"\x30\x80\x08"]), Err("TooLarge".into()));
+        assert_eq!(
+            connect(&[b"\x20\xff\xff\xff\xff\xff"]),
+            Err("Protocol".into())
+        );
+    }
+
+    #[test]
+    fn commands_parse() {
+        let parse = |topic, payload| Command::parse("bus-sign", topic, payload);
+        assert_eq!(parse("bus-sign/set", b"ON"), Some(Command::Power(true)));
+        assert_eq!(parse("bus-sign/set", b"OFF"), Some(Command::Power(false)));
+        assert_eq!(parse("bus-sign/set", b"on"), None);
+        assert_eq!(
+            parse("bus-sign/brightness/set", b" 128\n"),
+            Some(Command::Brightness(128))
+        );
+        assert_eq!(parse("bus-sign/brightness/set", b"256"), None);
+        assert_eq!(
+            parse("bus-sign/message/set", b"Snow day"),
+            Some(Command::Message("Snow day"))
+        );
+        assert_eq!(
+            parse("bus-sign/message/set", b""),
+            Some(Command::Message(""))
+        );
+        assert_eq!(parse("bus-sign/message/set", b"\xff"), None);
+        assert_eq!(parse("bus-signs/set", b"ON"), None);
+        assert_eq!(parse("other/set", b"ON"), None);
+        assert_eq!(parse("bus-sign/state", b"ON"), None);
+    }
+
+    #[test]
+    fn discovery_messages() {
+        let discovery = Discovery {
+            discovery_prefix: "homeassistant",
+            prefix: "bus-sign",
+            node_id: "sign_e45f",
+        };
+        let (topic, payload) = discovery.light().unwrap();
+        assert_eq!(topic, "homeassistant/light/sign_e45f/display/config");
+        assert!(payload.starts_with("{\"name\":\"Display\",\"unique_id\":\"sign_e45f_display\""));
+        assert!(payload.contains("\"availability_topic\":\"bus-sign/availability\""));
+        assert!(payload.ends_with("\"model\":\"Galactic Unicorn\"}}"));
+
+        let (topic, payload) = discovery.row(2).unwrap();
+        assert_eq!(topic, "homeassistant/sensor/sign_e45f/row2/config");
+        assert!(payload.contains("\"state_topic\":\"bus-sign/row/2\""));
+        assert!(payload.contains("\"value_template\":\"{{ value_json.minutes }}\""));
+
+        let (topic, payload) = discovery.message(40).unwrap();
+        assert_eq!(topic, "homeassistant/text/sign_e45f/message/config");
+        assert!(payload.contains("\"max\":40,"));
+    }
+
+    #[test]
+    fn discovery_fits_the_longest_config() {
+        let prefix = "p".repeat(MAX_TOPIC_LEN);
+        let discovery = Discovery {
+            discovery_prefix: &"d".repeat(MAX_TOPIC_LEN),
+            prefix: &prefix,
+            node_id: &"n".repeat(MAX_CLIENT_ID_LEN),
+        };
+        for message in [discovery.light(), discovery.message(255), discovery.row(8)] {
+            let (topic, payload) = message.unwrap();
+            assert!(topic.ends_with("/config"), "{topic}");
+            assert!(payload.ends_with("}}"), "{payload}");
+            assert!(publish_packet(&topic, payload.as_bytes(), true).is_some());
+        }
+        let command = topic(&prefix, format_args!("brightness/set"));
+        assert_eq!(command.len(), MAX_TOPIC_LEN + 15);
+
+        let discovery = Discovery {
+            prefix: &"p".repeat(BUFFER_SIZE / 4),
+            ..discovery
+        };
+        assert!(discovery.light().is_none());
+    }
+}
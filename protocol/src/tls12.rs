@@ -0,0 +1,1062 @@
+//! Minimal TLS 1.2 client, enough to talk to `api-v3.mbta.com` directly.
+//!
+//! Supports ECDHE on secp256r1 with either an ECDSA or RSA (PKCS#1 v1.5)
+//! server signature, and AES-128-GCM records. The server is trusted by
+//! pinning the SHA-256 hash of a SubjectPublicKeyInfo in its chain. A pinned
+//! leaf is trusted as is. A pinned issuer vouches for a leaf naming the
+//! server in its DNS subject alternative names, through a chain of CA
+//! certificates each signing the next with SHA-256 and RSA or P-256.
+//! Validity dates and revocation aren't checked.
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::Aes128Gcm;
+use core::ops::Range;
+use crypto_bigint::modular::runtime_mod::{DynResidue, DynResidueParams};
+use crypto_bigint::{Encoding, Integer, U4096, U64};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use hmac::{Hmac, Mac};
+use log::*;
+use p256::ecdh::EphemeralSecret;
+use p256::ecdsa::signature::hazmat::PrehashVerifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::PublicKey;
+use rand_core::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// SHA-256 of a certificate's DER encoded SubjectPublicKeyInfo
+pub type Pin = [u8; 32];
+
+const CONTENT_CHANGE_CIPHER_SPEC: u8 = 20;
+const CONTENT_ALERT: u8 = 21;
+const CONTENT_HANDSHAKE: u8 = 22;
+const CONTENT_APPLICATION_DATA: u8 = 23;
+
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const HANDSHAKE_SERVER_HELLO: u8 = 2;
+const HANDSHAKE_CERTIFICATE: u8 = 11;
+const HANDSHAKE_SERVER_KEY_EXCHANGE: u8 = 12;
+const HANDSHAKE_SERVER_HELLO_DONE: u8 = 14;
+const HANDSHAKE_CLIENT_KEY_EXCHANGE: u8 = 16;
+const HANDSHAKE_FINISHED: u8 = 20;
+
+const VERSION: [u8; 2] = [3, 3];
+const ECDHE_ECDSA_AES128_GCM_SHA256: u16 = 0xc02b;
+const ECDHE_RSA_AES128_GCM_SHA256: u16 = 0xc02f;
+const ECDSA_SECP256R1_SHA256: u16 = 0x0403;
+const RSA_PKCS1_SHA256: u16 = 0x0401;
+const SECP256R1: u16 = 0x0017;
+
+const RECORD_HEADER: usize = 5;
+const HANDSHAKE_HEADER: usize = 4;
+const MAX_FRAGMENT: usize = 16384;
+const EXPLICIT_NONCE: usize = 8;
+const TAG: usize = 16;
+const VERIFY_DATA: usize = 12;
+
+/// Largest record the server may send, encrypted
+const RECORD_BUFFER_SIZE: usize = RECORD_HEADER + EXPLICIT_NONCE + MAX_FRAGMENT + TAG;
+/// Largest handshake message accepted, in practice the certificate chain.
+/// Reused to encrypt outgoing records once the handshake is done.
+const HANDSHAKE_BUFFER_SIZE: usize = 8192;
+/// Most certificates of the server's chain looked at, the leaf included
+const MAX_CHAIN: usize = 4;
+/// Size of the buffer a connection needs
+pub const BUFFER_SIZE: usize = RECORD_BUFFER_SIZE + HANDSHAKE_BUFFER_SIZE;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TlsError {
+    Io,
+    Closed,
+    Alert(u8),
+    Decode,
+    Unsupported,
+    BufferTooSmall,
+    PinMismatch,
+    BadCertificate,
+    BadSignature,
+    BadRecordMac,
+    BadFinished,
+}
+
+impl embedded_io_async::Error for TlsError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            TlsError::Io => ErrorKind::Other,
+            TlsError::Closed => ErrorKind::ConnectionAborted,
+            TlsError::Decode | TlsError::BadRecordMac => ErrorKind::InvalidData,
+            TlsError::BufferTooSmall => ErrorKind::OutOfMemory,
+            _ => ErrorKind::PermissionDenied,
+        }
+    }
+}
+
+/// Parse a comma separated list of hex encoded pins
+pub fn parse_pins<const N: usize>(value: &str) -> heapless::Vec<Pin, N> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter_map(|hex| {
+            if hex.len() != 64 {
+                return None;
+            }
+            let mut pin = [0; 32];
+            for (i, byte) in pin.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+            }
+            Some(pin)
+        })
+        .collect()
+}
+
+struct CipherState {
+    cipher: Aes128Gcm,
+    iv: [u8; 4],
+    seq: u64,
+}
+
+impl CipherState {
+    fn new(key: &[u8], iv: &[u8]) -> Self {
+        let mut implicit = [0; 4];
+        implicit.copy_from_slice(iv);
+        CipherState {
+            cipher: Aes128Gcm::new(GenericArray::from_slice(key)),
+            iv: implicit,
+            seq: 0,
+        }
+    }
+
+    fn nonce_and_aad(&self, explicit: &[u8], content_type: u8, len: usize) -> ([u8; 12], [u8; 13]) {
+        let mut nonce = [0; 12];
+        nonce[..4].copy_from_slice(&self.iv);
+        nonce[4..].copy_from_slice(explicit);
+
+        let mut aad = [0; 13];
+        aad[..8].copy_from_slice(&self.seq.to_be_bytes());
+        aad[8] = content_type;
+        aad[9..11].copy_from_slice(&VERSION);
+        aad[11..].copy_from_slice(&(len as u16).to_be_bytes());
+        (nonce, aad)
+    }
+
+    /// Encrypt `payload` in place, returning the explicit nonce and tag
+    fn seal(&mut self, content_type: u8, payload: &mut [u8]) -> ([u8; EXPLICIT_NONCE], [u8; TAG]) {
+        let explicit = self.seq.to_be_bytes();
+        let (nonce, aad) = self.nonce_and_aad(&explicit, content_type, payload.len());
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(GenericArray::from_slice(&nonce), &aad, payload)
+            .unwrap();
+        self.seq += 1;
+        (explicit, tag.into())
+    }
+
+    /// Decrypt `payload` in place
+    fn open(
+        &mut self,
+        content_type: u8,
+        explicit: &[u8],
+        payload: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), TlsError> {
+        let (nonce, aad) = self.nonce_and_aad(explicit, content_type, payload.len());
+        self.cipher
+            .decrypt_in_place_detached(
+                GenericArray::from_slice(&nonce),
+                &aad,
+                payload,
+                GenericArray::from_slice(tag),
+            )
+            .map_err(|_| TlsError::BadRecordMac)?;
+        self.seq += 1;
+        Ok(())
+    }
+}
+
+/// Public key from the server's leaf certificate
+enum ServerKey<'a> {
+    Ecdsa(VerifyingKey),
+    Rsa {
+        modulus: &'a [u8],
+        exponent: &'a [u8],
+    },
+}
+
+/// Application data stream over an established TLS 1.2 session
+pub struct Tls12Connection<'b, S> {
+    socket: S,
+    record: &'b mut [u8],
+    handshake: &'b mut [u8],
+    /// bytes of handshake messages buffered in `handshake`
+    handshake_len: usize,
+    /// length of the handshake message returned last, removed on the next read
+    handshake_consumed: usize,
+    transcript: Sha256,
+    read_cipher: Option<CipherState>,
+    pending_read_cipher: Option<CipherState>,
+    write_cipher: Option<CipherState>,
+    /// decrypted application data in `record` not read yet
+    plaintext: Range<usize>,
+}
+
+impl<'b, S> Tls12Connection<'b, S>
+where
+    S: Read + Write,
+{
+    /// Run the handshake over `socket`, which is already connected to
+    /// `server_name`. `buffer` must be at least `BUFFER_SIZE` bytes.
+    pub async fn connect(
+        socket: S,
+        buffer: &'b mut [u8],
+        server_name: &str,
+        pins: &[Pin],
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> Result<Self, TlsError> {
+        if buffer.len() < BUFFER_SIZE {
+            return Err(TlsError::BufferTooSmall);
+        }
+        let (record, handshake) = buffer.split_at_mut(RECORD_BUFFER_SIZE);
+        let mut connection = Tls12Connection {
+            socket,
+            record,
+            handshake,
+            handshake_len: 0,
+            handshake_consumed: 0,
+            transcript: Sha256::new(),
+            read_cipher: None,
+            pending_read_cipher: None,
+            write_cipher: None,
+            plaintext: 0..0,
+        };
+        connection.handshake(server_name, pins, rng).await?;
+        Ok(connection)
+    }
+
+    async fn handshake(
+        &mut self,
+        server_name: &str,
+        pins: &[Pin],
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> Result<(), TlsError> {
+        let mut client_random = [0; 32];
+        rng.fill_bytes(&mut client_random);
+
+        let mut hello = [0; 512];
+        let len = client_hello(&mut hello, &client_random, server_name)?;
+        self.send_handshake(&hello[..len]).await?;
+
+        // ServerHello
+        let message = self.next_handshake(HANDSHAKE_SERVER_HELLO).await?;
+        let mut reader = Reader::new(&self.handshake[message]);
+        if reader.bytes(2)? != VERSION {
+            return Err(TlsError::Unsupported);
+        }
+        let mut server_random = [0; 32];
+        server_random.copy_from_slice(reader.bytes(32)?);
+        let session_id_len = reader.u8()? as usize;
+        reader.bytes(session_id_len)?;
+        let cipher_suite = reader.u16()?;
+        if reader.u8()? != 0 {
+            return Err(TlsError::Unsupported);
+        }
+        info!("TLS cipher suite {:#06x}", cipher_suite);
+
+        // Certificate, checked against the pins from the leaf up
+        let certificate = self.next_handshake(HANDSHAKE_CERTIFICATE).await?;
+        let leaf = {
+            let chain = certificate_chain(&self.handshake[certificate])?;
+            verify_chain(&chain, server_name, pins)?;
+            chain[0]
+        };
+        let spki = subject_public_key_info(leaf).ok_or(TlsError::Decode)?;
+
+        // ServerKeyExchange, following the certificate in the buffer, so
+        // the certificate must be copied out before reading it
+        let mut spki_copy = [0; 600];
+        let spki_len = spki.len();
+        spki_copy
+            .get_mut(..spki_len)
+            .ok_or(TlsError::Unsupported)?
+            .copy_from_slice(spki);
+
+        let key_exchange = self.next_handshake(HANDSHAKE_SERVER_KEY_EXCHANGE).await?;
+        let body = &self.handshake[key_exchange];
+        let mut reader = Reader::new(body);
+        if reader.u8()? != 3 || reader.u16()? != SECP256R1 {
+            return Err(TlsError::Unsupported);
+        }
+        let point_len = reader.u8()? as usize;
+        let server_point =
+            PublicKey::from_sec1_bytes(reader.bytes(point_len)?).map_err(|_| TlsError::Decode)?;
+        let params = &body[..4 + point_len];
+        let algorithm = reader.u16()?;
+        let signature_len = reader.u16()? as usize;
+        let signature = reader.bytes(signature_len)?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(client_random);
+        hasher.update(server_random);
+        hasher.update(params);
+        let hash: [u8; 32] = hasher.finalize().into();
+
+        let server_key = server_key(&spki_copy[..spki_len]).ok_or(TlsError::Unsupported)?;
+        let verified = match (cipher_suite, algorithm, &server_key) {
+            (ECDHE_ECDSA_AES128_GCM_SHA256, ECDSA_SECP256R1_SHA256, ServerKey::Ecdsa(key)) => {
+                Signature::from_der(signature)
+                    .map(|signature| key.verify_prehash(&hash, &signature).is_ok())
+                    .unwrap_or(false)
+            }
+            (
+                ECDHE_RSA_AES128_GCM_SHA256,
+                RSA_PKCS1_SHA256,
+                ServerKey::Rsa { modulus, exponent },
+            ) => verify_rsa(modulus, exponent, &hash, signature),
+            _ => return Err(TlsError::Unsupported),
+        };
+        if !verified {
+            error!("ServerKeyExchange signature is invalid");
+            return Err(TlsError::BadSignature);
+        }
+
+        self.next_handshake(HANDSHAKE_SERVER_HELLO_DONE).await?;
+
+        // ClientKeyExchange
+        let secret = EphemeralSecret::random(rng);
+        let public = secret.public_key().to_encoded_point(false);
+        let mut key_exchange = [0; HANDSHAKE_HEADER + 1 + 65];
+        key_exchange[0] = HANDSHAKE_CLIENT_KEY_EXCHANGE;
+        key_exchange[3] = 66;
+        key_exchange[4] = 65;
+        key_exchange[5..].copy_from_slice(public.as_bytes());
+        self.send_handshake(&key_exchange).await?;
+
+        let shared = secret.diffie_hellman(&server_point);
+        let mut master_secret = [0; 48];
+        prf(
+            shared.raw_secret_bytes(),
+            b"master secret",
+            &client_random,
+            &server_random,
+            &mut master_secret,
+        );
+
+        let mut key_block = [0; 40];
+        prf(
+            &master_secret,
+            b"key expansion",
+            &server_random,
+            &client_random,
+            &mut key_block,
+        );
+        let client = CipherState::new(&key_block[0..16], &key_block[32..36]);
+        let server = CipherState::new(&key_block[16..32], &key_block[36..40]);
+
+        self.send_record(CONTENT_CHANGE_CIPHER_SPEC, &mut [1])
+            .await?;
+        self.write_cipher = Some(client);
+        self.pending_read_cipher = Some(server);
+
+        // client Finished
+        let mut finished = [0; HANDSHAKE_HEADER + VERIFY_DATA];
+        finished[0] = HANDSHAKE_FINISHED;
+        finished[3] = VERIFY_DATA as u8;
+        prf(
+            &master_secret,
+            b"client finished",
+            &self.transcript.clone().finalize(),
+            &[],
+            &mut finished[HANDSHAKE_HEADER..],
+        );
+        self.send_handshake(&finished).await?;
+
+        // server Finished
+        let mut expected = [0; VERIFY_DATA];
+        prf(
+            &master_secret,
+            b"server finished",
+            &self.transcript.clone().finalize(),
+            &[],
+            &mut expected,
+        );
+        let server_finished = self.next_handshake(HANDSHAKE_FINISHED).await?;
+        if self.read_cipher.is_none() || self.handshake[server_finished] != expected {
+            return Err(TlsError::BadFinished);
+        }
+
+        self.handshake_len = 0;
+        self.handshake_consumed = 0;
+        info!("TLS handshake with {} complete", server_name);
+        Ok(())
+    }
+
+    async fn send_handshake(&mut self, message: &[u8]) -> Result<(), TlsError> {
+        self.transcript.update(message);
+        let mut payload = [0; 512];
+        let payload = payload
+            .get_mut(..message.len())
+            .ok_or(TlsError::BufferTooSmall)?;
+        payload.copy_from_slice(message);
+        self.send_record(CONTENT_HANDSHAKE, payload).await
+    }
+
+    /// Send one record, `payload` is scratch space if the record is encrypted
+    async fn send_record(&mut self, content_type: u8, payload: &mut [u8]) -> Result<(), TlsError> {
+        let Some(cipher) = self.write_cipher.as_mut() else {
+            let mut header = [content_type, VERSION[0], VERSION[1], 0, 0];
+            header[3..].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+            self.socket
+                .write_all(&header)
+                .await
+                .map_err(|_| TlsError::Io)?;
+            self.socket
+                .write_all(payload)
+                .await
+                .map_err(|_| TlsError::Io)?;
+            return Ok(());
+        };
+
+        let (explicit, tag) = cipher.seal(content_type, payload);
+        let len = EXPLICIT_NONCE + payload.len() + TAG;
+        let mut header = [content_type, VERSION[0], VERSION[1], 0, 0];
+        header[3..].copy_from_slice(&(len as u16).to_be_bytes());
+        for part in [&header[..], &explicit, payload, &tag] {
+            self.socket
+                .write_all(part)
+                .await
+                .map_err(|_| TlsError::Io)?;
+        }
+        self.socket.flush().await.map_err(|_| TlsError::Io)
+    }
+
+    /// Read and decrypt one record into `record`, returning its content type
+    /// and the range of its plaintext
+    async fn read_record(&mut self) -> Result<(u8, Range<usize>), TlsError> {
+        read_exact(&mut self.socket, &mut self.record[..RECORD_HEADER]).await?;
+        let content_type = self.record[0];
+        let len = u16::from_be_bytes([self.record[3], self.record[4]]) as usize;
+        let end = RECORD_HEADER + len;
+        if end > self.record.len() {
+            return Err(TlsError::BufferTooSmall);
+        }
+        read_exact(&mut self.socket, &mut self.record[RECORD_HEADER..end]).await?;
+
+        let mut plaintext = RECORD_HEADER..end;
+        if let Some(cipher) = self.read_cipher.as_mut() {
+            if len < EXPLICIT_NONCE + TAG {
+                return Err(TlsError::Decode);
+            }
+            let body = &mut self.record[RECORD_HEADER..end];
+            let (explicit, rest) = body.split_at_mut(EXPLICIT_NONCE);
+            let (payload, tag) = rest.split_at_mut(rest.len() - TAG);
+            cipher.open(content_type, explicit, payload, tag)?;
+            plaintext = RECORD_HEADER + EXPLICIT_NONCE..end - TAG;
+        }
+
+        if content_type == CONTENT_ALERT {
+            let alert = &self.record[plaintext];
+            return match alert.get(1) {
+                Some(0) => Err(TlsError::Closed),
+                Some(&description) => {
+                    error!("TLS alert {}", description);
+                    Err(TlsError::Alert(description))
+                }
+                None => Err(TlsError::Decode),
+            };
+        }
+
+        Ok((content_type, plaintext))
+    }
+
+    /// Next handshake message, which must be of type `expected`. Returns the
+    /// range of its body in `handshake`.
+    async fn next_handshake(&mut self, expected: u8) -> Result<Range<usize>, TlsError> {
+        self.handshake
+            .copy_within(self.handshake_consumed..self.handshake_len, 0);
+        self.handshake_len -= self.handshake_consumed;
+        self.handshake_consumed = 0;
+
+        loop {
+            if self.handshake_len >= HANDSHAKE_HEADER {
+                let header = &self.handshake[..HANDSHAKE_HEADER];
+                let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+                let end = HANDSHAKE_HEADER + len;
+                if end > self.handshake.len() {
+                    return Err(TlsError::BufferTooSmall);
+                }
+                if self.handshake_len >= end {
+                    if header[0] != expected {
+                        error!("Expected handshake {} got {}", expected, header[0]);
+                        return Err(TlsError::Unsupported);
+                    }
+                    self.transcript.update(&self.handshake[..end]);
+                    self.handshake_consumed = end;
+                    return Ok(HANDSHAKE_HEADER..end);
+                }
+            }
+
+            let (content_type, plaintext) = self.read_record().await?;
+            match content_type {
+                CONTENT_HANDSHAKE => {
+                    let len = plaintext.len();
+                    let destination = self
+                        .handshake
+                        .get_mut(self.handshake_len..self.handshake_len + len)
+                        .ok_or(TlsError::BufferTooSmall)?;
+                    destination.copy_from_slice(&self.record[plaintext]);
+                    self.handshake_len += len;
+                }
+                CONTENT_CHANGE_CIPHER_SPEC => {
+                    self.read_cipher = self.pending_read_cipher.take();
+                    if self.read_cipher.is_none() {
+                        return Err(TlsError::Decode);
+                    }
+                }
+                _ => return Err(TlsError::Decode),
+            }
+        }
+    }
+}
+
+impl<S> ErrorType for Tls12Connection<'_, S> {
+    type Error = TlsError;
+}
+
+impl<S> Read for Tls12Connection<'_, S>
+where
+    S: Read + Write,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, TlsError> {
+        while self.plaintext.is_empty() {
+            match self.read_record().await {
+                Ok((CONTENT_APPLICATION_DATA, plaintext)) => self.plaintext = plaintext,
+                // renegotiation is not supported, ignore HelloRequest
+                Ok((CONTENT_HANDSHAKE, _)) => {}
+                Ok(_) => return Err(TlsError::Decode),
+                Err(TlsError::Closed) => return Ok(0),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let len = core::cmp::min(buf.len(), self.plaintext.len());
+        let start = self.plaintext.start;
+        buf[..len].copy_from_slice(&self.record[start..start + len]);
+        self.plaintext.start += len;
+        Ok(len)
+    }
+}
+
+impl<S> Write for Tls12Connection<'_, S>
+where
+    S: Read + Write,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, TlsError> {
+        let len = core::cmp::min(buf.len(), MAX_FRAGMENT.min(self.handshake.len()));
+        let (scratch, _) = self.handshake.split_at_mut(len);
+        scratch.copy_from_slice(&buf[..len]);
+
+        // `send_record` borrows all of self, so move the scratch buffer out
+        let handshake = core::mem::take(&mut self.handshake);
+        let result = self
+            .send_record(CONTENT_APPLICATION_DATA, &mut handshake[..len])
+            .await;
+        self.handshake = handshake;
+        result.map(|_| len)
+    }
+
+    async fn flush(&mut self) -> Result<(), TlsError> {
+        self.socket.flush().await.map_err(|_| TlsError::Io)
+    }
+}
+
+async fn read_exact<S: Read>(socket: &mut S, mut buf: &mut [u8]) -> Result<(), TlsError> {
+    while !buf.is_empty() {
+        match socket.read(buf).await {
+            Ok(0) => return Err(TlsError::Closed),
+            Ok(n) => buf = &mut buf[n..],
+            Err(_) => return Err(TlsError::Io),
+        }
+    }
+    Ok(())
+}
+
+fn client_hello(out: &mut [u8], random: &[u8; 32], server_name: &str) -> Result<usize, TlsError> {
+    let name = server_name.as_bytes();
+    let name_len = name.len() as u16;
+    let mut writer = Writer::new(out);
+
+    writer.u8(HANDSHAKE_CLIENT_HELLO)?;
+    let length = writer.reserve(3)?;
+    writer.bytes(&VERSION)?;
+    writer.bytes(random)?;
+    writer.u8(0)?; // no session id
+    writer.u16(4)?;
+    writer.u16(ECDHE_ECDSA_AES128_GCM_SHA256)?;
+    writer.u16(ECDHE_RSA_AES128_GCM_SHA256)?;
+    writer.bytes(&[1, 0])?; // null compression
+
+    let extensions = writer.reserve(2)?;
+    // server_name
+    writer.u16(0x0000)?;
+    writer.u16(name_len + 5)?;
+    writer.u16(name_len + 3)?;
+    writer.u8(0)?;
+    writer.u16(name_len)?;
+    writer.bytes(name)?;
+    // supported_groups
+    writer.bytes(&[0x00, 0x0a, 0x00, 0x04, 0x00, 0x02])?;
+    writer.u16(SECP256R1)?;
+    // ec_point_formats, uncompressed only
+    writer.bytes(&[0x00, 0x0b, 0x00, 0x02, 0x01, 0x00])?;
+    // signature_algorithms
+    writer.bytes(&[0x00, 0x0d, 0x00, 0x06, 0x00, 0x04])?;
+    writer.u16(ECDSA_SECP256R1_SHA256)?;
+    writer.u16(RSA_PKCS1_SHA256)?;
+    // renegotiation_info, empty
+    writer.bytes(&[0xff, 0x01, 0x00, 0x01, 0x00])?;
+
+    let len = writer.len;
+    writer.fill_length(extensions.clone(), len - extensions.end);
+    writer.fill_length(length.clone(), len - length.end);
+    Ok(len)
+}
+
+/// TLS 1.2 P_SHA256 pseudorandom function, with the seed in two parts
+fn prf(secret: &[u8], label: &[u8], seed_a: &[u8], seed_b: &[u8], out: &mut [u8]) {
+    let hmac = |parts: &[&[u8]]| -> [u8; 32] {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(secret).unwrap();
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().into()
+    };
+
+    let mut a = hmac(&[label, seed_a, seed_b]);
+    for chunk in out.chunks_mut(32) {
+        let block = hmac(&[&a, label, seed_a, seed_b]);
+        chunk.copy_from_slice(&block[..chunk.len()]);
+        a = hmac(&[&a]);
+    }
+}
+
+/// PKCS#1 v1.5 signature check of a SHA-256 hash
+fn verify_rsa(modulus: &[u8], exponent: &[u8], hash: &[u8; 32], signature: &[u8]) -> bool {
+    const DIGEST_INFO: [u8; 19] = [
+        0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01,
+        0x05, 0x00, 0x04, 0x20,
+    ];
+
+    let len = modulus.len();
+    if !(128..=512).contains(&len) || signature.len() != len || exponent.len() > 4 {
+        return false;
+    }
+
+    let padded = |bytes: &[u8]| {
+        let mut buf = [0; 512];
+        buf[512 - bytes.len()..].copy_from_slice(bytes);
+        U4096::from_be_slice(&buf)
+    };
+    let n = padded(modulus);
+    let s = padded(signature);
+    if !bool::from(n.is_odd()) || s >= n {
+        return false;
+    }
+    let mut e = [0; 4];
+    e[4 - exponent.len()..].copy_from_slice(exponent);
+    let e = U64::from_u32(u32::from_be_bytes(e));
+
+    let params = DynResidueParams::new(&n);
+    let m = DynResidue::new(&s, params)
+        .pow_bounded_exp(&e, 32)
+        .retrieve();
+    let encoded = m.to_be_bytes();
+    let encoded = &encoded[512 - len..];
+
+    // 00 01 FF .. FF 00 DigestInfo hash
+    let padding_end = len - DIGEST_INFO.len() - hash.len();
+    encoded[0] == 0
+        && encoded[1] == 1
+        && encoded[2..padding_end - 1].iter().all(|&b| b == 0xff)
+        && encoded[padding_end - 1] == 0
+        && encoded[padding_end..padding_end + DIGEST_INFO.len()] == DIGEST_INFO
+        && encoded[padding_end + DIGEST_INFO.len()..] == hash[..]
+}
+
+/// Split a DER element into its tag, contents and the bytes after it
+fn der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *input.first()?;
+    let first = *input.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 3 {
+            return None;
+        }
+        let len = input
+            .get(2..2 + count)?
+            .iter()
+            .fold(0, |len, &b| (len << 8) | b as usize);
+        (len, 2 + count)
+    };
+    let contents = input.get(header..header + len)?;
+    Some((tag, contents, &input[header + len..]))
+}
+
+/// Certificates of a Certificate message, the leaf first
+fn certificate_chain(body: &[u8]) -> Result<heapless::Vec<&[u8], MAX_CHAIN>, TlsError> {
+    let mut reader = Reader::new(body);
+    let len = reader.u24()? as usize;
+    let mut reader = Reader::new(reader.bytes(len)?);
+    let mut chain = heapless::Vec::new();
+    while !reader.buf.is_empty() && !chain.is_full() {
+        let len = reader.u24()? as usize;
+        chain.push(reader.bytes(len)?).ok();
+    }
+    if chain.is_empty() {
+        return Err(TlsError::Decode);
+    }
+    Ok(chain)
+}
+
+/// Find the lowest certificate of `chain` with a pinned key and check that
+/// it vouches for the leaf, see the module docs
+fn verify_chain(chain: &[&[u8]], server_name: &str, pins: &[Pin]) -> Result<(), TlsError> {
+    let pinned = chain.iter().position(|certificate| {
+        subject_public_key_info(certificate).is_some_and(|spki| {
+            let pin: Pin = Sha256::digest(spki).into();
+            pins.contains(&pin)
+        })
+    });
+    let Some(pinned) = pinned else {
+        error!("Server key does not match any pin");
+        return Err(TlsError::PinMismatch);
+    };
+
+    for link in chain[..=pinned].windows(2) {
+        let (certificate, issuer) = (link[0], link[1]);
+        if !is_ca(issuer) {
+            error!("Certificate issued by a certificate that isn't a CA");
+            return Err(TlsError::BadCertificate);
+        }
+        if !signed_by(certificate, issuer)? {
+            error!("Certificate signature does not match its issuer");
+            return Err(TlsError::BadCertificate);
+        }
+    }
+    if pinned > 0 && !names(chain[0], server_name) {
+        error!("Certificate is not for {}", server_name);
+        return Err(TlsError::BadCertificate);
+    }
+    Ok(())
+}
+
+/// Fields of an X.509 certificate's TBSCertificate after the optional version
+fn tbs_fields(certificate: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der(certificate)?;
+    let (_, tbs, _) = der(certificate)?;
+    if *tbs.first()? == 0xa0 {
+        return Some(der(tbs)?.2);
+    }
+    Some(tbs)
+}
+
+/// DER encoded SubjectPublicKeyInfo of an X.509 certificate
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    // skip serial, signature, issuer, validity and subject
+    let mut rest = tbs_fields(certificate)?;
+    for _ in 0..5 {
+        rest = der(rest)?.2;
+    }
+
+    let (_, _, after) = der(rest)?;
+    Some(&rest[..rest.len() - after.len()])
+}
+
+/// Value of the extension `oid` of an X.509 certificate
+fn extension<'a>(certificate: &'a [u8], oid: &[u8]) -> Option<&'a [u8]> {
+    // skip serial, signature, issuer, validity, subject and key, then the
+    // optional unique IDs before the extensions
+    let mut rest = tbs_fields(certificate)?;
+    for _ in 0..6 {
+        rest = der(rest)?.2;
+    }
+    let extensions = loop {
+        let (tag, contents, after) = der(rest)?;
+        if tag == 0xa3 {
+            break der(contents)?.1;
+        }
+        rest = after;
+    };
+
+    let mut rest = extensions;
+    while let Some((_, extension, after)) = der(rest) {
+        let (_, id, mut value) = der(extension)?;
+        if id == oid {
+            // skip the critical flag
+            if value.first() == Some(&0x01) {
+                value = der(value)?.2;
+            }
+            return der(value).map(|(_, value, _)| value);
+        }
+        rest = after;
+    }
+    None
+}
+
+/// Whether the basic constraints mark a certificate as a CA
+fn is_ca(certificate: &[u8]) -> bool {
+    const BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+
+    extension(certificate, BASIC_CONSTRAINTS)
+        .and_then(der)
+        .and_then(|(_, constraints, _)| der(constraints))
+        .is_some_and(|(tag, ca, _)| tag == 0x01 && ca == [0xff])
+}
+
+/// Whether a certificate's DNS subject alternative names include `host`
+fn names(certificate: &[u8], host: &str) -> bool {
+    const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+    const DNS_NAME: u8 = 0x82;
+
+    let Some((_, mut rest, _)) = extension(certificate, SUBJECT_ALT_NAME).and_then(der) else {
+        return false;
+    };
+    while let Some((tag, name, after)) = der(rest) {
+        if tag == DNS_NAME && core::str::from_utf8(name).is_ok_and(|name| name_matches(name, host))
+        {
+            return true;
+        }
+        rest = after;
+    }
+    false
+}
+
+/// Match `host` against a DNS name, where a leading `*.` stands for one label
+fn name_matches(name: &str, host: &str) -> bool {
+    match name.strip_prefix("*.") {
+        Some(domain) => host
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(domain)),
+        None => name.eq_ignore_ascii_case(host),
+    }
+}
+
+/// Check that `issuer`'s key signed `certificate`, SHA-256 with RSA or
+/// P-256 being the only algorithms supported
+fn signed_by(certificate: &[u8], issuer: &[u8]) -> Result<bool, TlsError> {
+    const SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+    const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+
+    // the signed TBSCertificate, then the algorithm and signature
+    let (_, contents, _) = der(certificate).ok_or(TlsError::Decode)?;
+    let (_, _, rest) = der(contents).ok_or(TlsError::Decode)?;
+    let tbs = &contents[..contents.len() - rest.len()];
+    let (_, algorithm, rest) = der(rest).ok_or(TlsError::Decode)?;
+    let (_, algorithm, _) = der(algorithm).ok_or(TlsError::Decode)?;
+    let (_, signature, _) = der(rest).ok_or(TlsError::Decode)?;
+    // skip the unused bits count of the BIT STRING
+    let signature = signature.get(1..).ok_or(TlsError::Decode)?;
+
+    let key = subject_public_key_info(issuer)
+        .and_then(server_key)
+        .ok_or(TlsError::Unsupported)?;
+    let hash: [u8; 32] = Sha256::digest(tbs).into();
+    Ok(match (algorithm, key) {
+        (ECDSA_WITH_SHA256, ServerKey::Ecdsa(key)) => Signature::from_der(signature)
+            .map(|signature| key.verify_prehash(&hash, &signature).is_ok())
+            .unwrap_or(false),
+        (SHA256_WITH_RSA, ServerKey::Rsa { modulus, exponent }) => {
+            verify_rsa(modulus, exponent, &hash, signature)
+        }
+        // a key of the other type can't have made the signature
+        (ECDSA_WITH_SHA256 | SHA256_WITH_RSA, _) => false,
+        _ => return Err(TlsError::Unsupported),
+    })
+}
+
+fn server_key(spki: &[u8]) -> Option<ServerKey<'_>> {
+    const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+    const PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+    const RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+    let (_, spki, _) = der(spki)?;
+    let (_, algorithm, rest) = der(spki)?;
+    let (_, key, _) = der(rest)?;
+    // skip the unused bits count of the BIT STRING
+    let key = key.get(1..)?;
+    let (_, oid, parameters) = der(algorithm)?;
+
+    if oid == EC_PUBLIC_KEY {
+        let (_, curve, _) = der(parameters)?;
+        if curve != PRIME256V1 {
+            return None;
+        }
+        VerifyingKey::from_sec1_bytes(key)
+            .ok()
+            .map(ServerKey::Ecdsa)
+    } else if oid == RSA_ENCRYPTION {
+        let (_, key, _) = der(key)?;
+        let (_, modulus, rest) = der(key)?;
+        let (_, exponent, _) = der(rest)?;
+        let modulus = match modulus {
+            [0, rest @ ..] => rest,
+            _ => modulus,
+        };
+        Some(ServerKey::Rsa { modulus, exponent })
+    } else {
+        None
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], TlsError> {
+        if len > self.buf.len() {
+            return Err(TlsError::Decode);
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, TlsError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, TlsError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Result<u32, TlsError> {
+        let bytes = self.bytes(3)?;
+        Ok(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]))
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Writer { buf, len: 0 }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), TlsError> {
+        self.buf
+            .get_mut(self.len..self.len + bytes.len())
+            .ok_or(TlsError::BufferTooSmall)?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), TlsError> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), TlsError> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// Skip a length field to fill in once the contents are written
+    fn reserve(&mut self, len: usize) -> Result<Range<usize>, TlsError> {
+        let start = self.len;
+        self.bytes(&[0; 3][..len])?;
+        Ok(start..self.len)
+    }
+
+    fn fill_length(&mut self, field: Range<usize>, value: usize) {
+        let bytes = (value as u32).to_be_bytes();
+        let width = field.len();
+        self.buf[field].copy_from_slice(&bytes[4 - width..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CA_RSA: &[u8] = include_bytes!("../tests/tls/ca-rsa.der");
+    const CA_EC: &[u8] = include_bytes!("../tests/tls/ca-ec.der");
+    const LEAF_EC: &[u8] = include_bytes!("../tests/tls/leaf-ec.der");
+    const LEAF_RSA: &[u8] = include_bytes!("../tests/tls/leaf-rsa.der");
+    const ROGUE: &[u8] = include_bytes!("../tests/tls/rogue.der");
+
+    fn pin(certificate: &[u8]) -> Pin {
+        Sha256::digest(subject_public_key_info(certificate).unwrap()).into()
+    }
+
+    #[test]
+    fn pins_parse_from_hex() {
+        let pins = parse_pins::<2>(
+            " 0073d38cce922f57c2fc4b5a85d9508bcd2510876a6e605b37cc85774308cef8, 00ff, \
+             0B36394CB5F032E0966DF71B443C69E844EBD57EE13C6F96AADB2EA6469C90B0",
+        );
+        assert_eq!(pins, [pin(CA_RSA), pin(CA_EC)]);
+        assert!(parse_pins::<2>("").is_empty());
+    }
+
+    #[test]
+    fn wildcards_match_one_label() {
+        assert!(name_matches("api-v3.mbta.com", "API-V3.mbta.com"));
+        assert!(name_matches("*.mbta.com", "api-v3.mbta.com"));
+        assert!(!name_matches("*.mbta.com", "mbta.com"));
+        assert!(!name_matches("*.mbta.com", "a.api-v3.mbta.com"));
+        assert!(!name_matches("*.mbta.com", ".mbta.com"));
+        assert!(!name_matches("mbta.com", "api-v3.mbta.com"));
+    }
+
+    #[test]
+    fn certificate_fields() {
+        assert!(is_ca(CA_RSA) && is_ca(CA_EC));
+        assert!(!is_ca(LEAF_EC) && !is_ca(LEAF_RSA));
+        assert!(names(LEAF_EC, "localhost"));
+        assert!(names(LEAF_RSA, "www.sign.test"));
+        assert!(!names(LEAF_EC, "sign.test"));
+        assert!(!names(CA_RSA, "localhost"));
+    }
+
+    #[test]
+    fn signatures_check_against_the_issuer() {
+        assert_eq!(signed_by(LEAF_EC, CA_RSA), Ok(true));
+        assert_eq!(signed_by(LEAF_RSA, CA_EC), Ok(true));
+        assert_eq!(signed_by(LEAF_EC, CA_EC), Ok(false));
+        assert_eq!(signed_by(LEAF_RSA, CA_RSA), Ok(false));
+    }
+
+    #[test]
+    fn chains_need_a_pin_and_a_leaf_for_the_server() {
+        let chain = [LEAF_EC, CA_RSA];
+        assert_eq!(verify_chain(&chain, "localhost", &[pin(CA_RSA)]), Ok(()));
+        assert_eq!(verify_chain(&chain, "example.com", &[pin(LEAF_EC)]), Ok(()));
+        assert_eq!(
+            verify_chain(&chain, "example.com", &[pin(CA_RSA)]),
+            Err(TlsError::BadCertificate)
+        );
+        assert_eq!(
+            verify_chain(&chain, "localhost", &[pin(CA_EC)]),
+            Err(TlsError::PinMismatch)
+        );
+        assert_eq!(
+            verify_chain(&[LEAF_EC, CA_EC], "localhost", &[pin(CA_EC)]),
+            Err(TlsError::BadCertificate)
+        );
+        assert_eq!(
+            verify_chain(&[ROGUE, LEAF_EC, CA_RSA], "localhost", &[pin(CA_RSA)]),
+            Err(TlsError::BadCertificate)
+        );
+    }
+}
@@ -0,0 +1,59 @@
+#!/bin/sh
+# Regenerate the certificates tests/tls12.rs runs openssl s_server with.
+#
+#   root ── ca-rsa (RSA CA) ── leaf-ec (P-256 leaf) ── rogue (signed by a leaf)
+#        └─ ca-ec (P-256 CA) ── leaf-rsa (RSA leaf)
+#
+# Leaves name localhost and *.sign.test. Only the certificates and the leaf
+# keys are kept; after running it, update the pins at the top of the tests.
+set -eu
+cd "$(dirname "$0")"
+work=$(mktemp -d)
+trap 'rm -r "$work"' EXIT
+
+cat > "$work/ext.cnf" <<EOF
+[ca]
+basicConstraints = critical, CA:TRUE
+keyUsage = critical, keyCertSign, cRLSign
+[leaf]
+basicConstraints = critical, CA:FALSE
+keyUsage = critical, digitalSignature, keyEncipherment
+extendedKeyUsage = serverAuth
+subjectAltName = DNS:localhost, DNS:*.sign.test
+EOF
+
+key() {
+    case $2 in
+    rsa) openssl genpkey -quiet -algorithm RSA -pkeyopt rsa_keygen_bits:2048 -out "$1" ;;
+    ec) openssl genpkey -quiet -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1" ;;
+    esac
+}
+
+# name, key type, issuer, extensions section
+cert() {
+    key "$work/$1.key" "$2"
+    openssl req -new -key "$work/$1.key" -subj "/CN=bus-sign test $1" -out "$work/$1.csr"
+    openssl x509 -req -sha256 -days 36500 -in "$work/$1.csr" \
+        -CA "$work/$3.pem" -CAkey "$work/$3.key" -CAcreateserial \
+        -extfile "$work/ext.cnf" -extensions "$4" -out "$work/$1.pem"
+}
+
+key "$work/root.key" ec
+openssl req -new -x509 -sha256 -days 36500 -key "$work/root.key" \
+    -subj "/CN=bus-sign test root" -out "$work/root.pem"
+
+cert ca-rsa rsa root ca
+cert ca-ec ec root ca
+cert leaf-ec ec ca-rsa leaf
+cert leaf-rsa rsa ca-ec leaf
+cert rogue ec leaf-ec leaf
+
+for name in ca-rsa ca-ec leaf-ec leaf-rsa rogue; do
+    cp "$work/$name.pem" .
+    openssl x509 -in "$name.pem" -outform DER -out "$name.der"
+    printf '%s ' "$name"
+    openssl x509 -in "$name.pem" -pubkey -noout | openssl pkey -pubin -outform DER |
+        openssl dgst -sha256 -r | cut -d' ' -f1
+done
+cp "$work/leaf-ec.key" "$work/leaf-rsa.key" "$work/rogue.key" .
+cat leaf-ec.pem ca-rsa.pem > rogue-chain.pem
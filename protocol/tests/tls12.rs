@@ -0,0 +1,168 @@
+//! The TLS 1.2 client against `openssl s_server`, which must be on the PATH.
+//! The certificates in `tls/` come from `tls/make-certs.sh`.
+
+use bus_sign_protocol::tls12::{parse_pins, Pin, Tls12Connection, TlsError, BUFFER_SIZE};
+use embassy_futures::block_on;
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use rand_core::OsRng;
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::Duration;
+
+const CA_RSA: &str = "0073d38cce922f57c2fc4b5a85d9508bcd2510876a6e605b37cc85774308cef8";
+const CA_EC: &str = "0b36394cb5f032e0966df71b443c69e844ebd57ee13c6f96aadb2ea6469c90b0";
+const LEAF_EC: &str = "d7511baefd6c111296e58e019d31fb47deacc978c0ce6f24147a62dd9257e639";
+
+/// `openssl s_server` answering HTTP with a status page, killed on drop
+struct Server {
+    child: Child,
+    port: u16,
+}
+
+impl Server {
+    fn start(cert: &str, key: &str, chain: &str) -> Self {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let file = |name: &str| format!("{}/tests/tls/{name}", env!("CARGO_MANIFEST_DIR"));
+        let child = Command::new("openssl")
+            .args(["s_server", "-tls1_2", "-www", "-accept"])
+            .arg(format!("127.0.0.1:{port}"))
+            .arg("-cert")
+            .arg(file(cert))
+            .arg("-key")
+            .arg(file(key))
+            .arg("-cert_chain")
+            .arg(file(chain))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("openssl not on the PATH");
+        let server = Server { child, port };
+        for _ in 0..50 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                return server;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        panic!("openssl s_server did not start");
+    }
+
+    /// Fetch the status page, checking the server against `pins`
+    fn get(&self, server_name: &str, pins: &str) -> Result<String, TlsError> {
+        let socket = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let pins = parse_pins::<2>(pins);
+        let mut buffer = vec![0; BUFFER_SIZE];
+        block_on(fetch(Socket(socket), &mut buffer, server_name, &pins))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+async fn fetch(
+    socket: Socket,
+    buffer: &mut [u8],
+    server_name: &str,
+    pins: &[Pin],
+) -> Result<String, TlsError> {
+    let mut tls = Tls12Connection::connect(socket, buffer, server_name, pins, &mut OsRng).await?;
+    tls.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+    tls.flush().await?;
+
+    let mut response = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        let len = tls.read(&mut chunk).await?;
+        if len == 0 {
+            return Ok(String::from_utf8_lossy(&response).into_owned());
+        }
+        response.extend_from_slice(&chunk[..len]);
+    }
+}
+
+/// Blocking `TcpStream` standing in for the sign's socket
+struct Socket(TcpStream);
+
+impl ErrorType for Socket {
+    type Error = ErrorKind;
+}
+
+impl Read for Socket {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        std::io::Read::read(&mut self.0, buf).map_err(|_| ErrorKind::Other)
+    }
+}
+
+impl Write for Socket {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        std::io::Write::write(&mut self.0, buf).map_err(|_| ErrorKind::Other)
+    }
+
+    async fn flush(&mut self) -> Result<(), ErrorKind> {
+        std::io::Write::flush(&mut self.0).map_err(|_| ErrorKind::Other)
+    }
+}
+
+fn assert_served(response: Result<String, TlsError>) {
+    let response = response.unwrap();
+    assert!(response.starts_with("HTTP/1.0 200 ok"), "{response}");
+}
+
+#[test]
+fn ecdsa_leaf_under_pinned_rsa_ca() {
+    let server = Server::start("leaf-ec.pem", "leaf-ec.key", "ca-rsa.pem");
+    assert_served(server.get("localhost", CA_RSA));
+}
+
+#[test]
+fn rsa_leaf_under_pinned_ecdsa_ca() {
+    let server = Server::start("leaf-rsa.pem", "leaf-rsa.key", "ca-ec.pem");
+    assert_served(server.get("www.sign.test", CA_EC));
+}
+
+#[test]
+fn backup_pin() {
+    let server = Server::start("leaf-rsa.pem", "leaf-rsa.key", "ca-ec.pem");
+    assert_served(server.get("localhost", &format!("{CA_RSA},{CA_EC}")));
+}
+
+#[test]
+fn pinned_leaf_needs_no_name() {
+    let server = Server::start("leaf-ec.pem", "leaf-ec.key", "ca-rsa.pem");
+    assert_served(server.get("192.0.2.1", LEAF_EC));
+}
+
+#[test]
+fn unpinned_chain() {
+    let server = Server::start("leaf-ec.pem", "leaf-ec.key", "ca-rsa.pem");
+    assert_eq!(server.get("localhost", CA_EC), Err(TlsError::PinMismatch));
+}
+
+#[test]
+fn leaf_for_another_name() {
+    let server = Server::start("leaf-ec.pem", "leaf-ec.key", "ca-rsa.pem");
+    assert_eq!(
+        server.get("api-v3.mbta.com", CA_RSA),
+        Err(TlsError::BadCertificate)
+    );
+}
+
+#[test]
+fn leaf_issued_by_a_leaf() {
+    let server = Server::start("rogue.pem", "rogue.key", "rogue-chain.pem");
+    assert_eq!(
+        server.get("localhost", CA_RSA),
+        Err(TlsError::BadCertificate)
+    );
+}
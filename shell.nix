@@ -7,6 +7,7 @@ pkgs.mkShell {
     elf2uf2-rs
     rustup
     git
+    openssl
   ];
 
   RUSTC_VERSION = overrides.toolchain.channel;
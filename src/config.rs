@@ -0,0 +1,443 @@
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::console::{SetError, Settings};
+use crate::sign::CONFIG;
+use core::cell::RefCell;
+use core::fmt::Write;
+
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// The flash, shared by the stored config and firmware updates in `ota`
+pub type SharedFlash =
+    blocking_mutex::Mutex<ThreadModeRawMutex, RefCell<Flash<'static, FLASH, Blocking, FLASH_SIZE>>>;
+
+/// Two sectors at the end of flash, reserved in `memory.x`
+const REGION_OFFSET: u32 = (FLASH_SIZE - 2 * ERASE_SIZE) as u32;
+const REGION_SIZE: usize = 2 * ERASE_SIZE;
+
+/// Each save goes to the next slot so the sectors wear evenly
+const SLOT_SIZE: usize = 512;
+const SLOTS: usize = REGION_SIZE / SLOT_SIZE;
+const SLOTS_PER_SECTOR: usize = ERASE_SIZE / SLOT_SIZE;
+
+const MAGIC: u32 = 0x5349_474e; // "SIGN"
+const HEADER_SIZE: usize = 16;
+const MAX_PAYLOAD: usize = SLOT_SIZE - HEADER_SIZE;
+
+/// Bump when a stored field changes meaning or type, and teach `migrate`
+/// how to read the old version. Adding a field with a default doesn't need
+/// a bump, older records just deserialize with the default.
+pub const CONFIG_VERSION: u16 = 1;
+
+/// Settings that can change after the sign is built, the first boot takes
+/// them from `sign.toml`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub wifi_ssid: heapless::String<32>,
+    pub wifi_password: heapless::String<64>,
+    pub bus_stop: heapless::String<16>,
+    /// comma separated routes in display order, see `RouteSpec`
+    pub routes: heapless::String<192>,
+    /// host running `mbta-proxy.py` in proxy mode, empty to discover it
+    pub proxy_ip: heapless::String<64>,
+}
+
+impl Default for Config {
+    /// The sign definition, for the first boot. `build.rs` has already
+    /// checked that every value fits.
+    fn default() -> Self {
+        fn string<const N: usize>(value: &str) -> heapless::String<N> {
+            heapless::String::try_from(value).unwrap_or_default()
+        }
+
+        let mut routes = heapless::String::new();
+        for route in CONFIG.routes {
+            if !routes.is_empty() {
+                routes.push(',').ok();
+            }
+            routes.push_str(route.id).ok();
+            if let Some(direction) = route.direction {
+                write!(routes, "/{}", direction).ok();
+            }
+            if let Some(stop) = route.stop {
+                write!(routes, "@{}", stop).ok();
+            }
+            if let Some(tag) = route.tag {
+                write!(routes, "#{}", tag).ok();
+            }
+        }
+
+        Config {
+            wifi_ssid: string(CONFIG.wifi.ssid),
+            wifi_password: string(CONFIG.wifi.password),
+            bus_stop: string(CONFIG.stop),
+            routes,
+            proxy_ip: string(CONFIG.mbta.proxy_ip),
+        }
+    }
+}
+
+impl Config {
+    /// Field names accepted by `get` and `set`
+    pub const KEYS: [&'static str; 5] = [
+        "wifi_ssid",
+        "wifi_password",
+        "bus_stop",
+        "routes",
+        "proxy_ip",
+    ];
+
+    /// Value of the field called `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        match key {
+            "wifi_ssid" => Some(self.wifi_ssid.as_str()),
+            "wifi_password" => Some(self.wifi_password.as_str()),
+            "bus_stop" => Some(self.bus_stop.as_str()),
+            "routes" => Some(self.routes.as_str()),
+            "proxy_ip" => Some(self.proxy_ip.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Replace the field called `key` with `value`
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        fn replace<const N: usize>(
+            field: &mut heapless::String<N>,
+            value: &str,
+        ) -> Result<(), ConfigError> {
+            *field = heapless::String::try_from(value).map_err(|_| ConfigError::TooLarge)?;
+            Ok(())
+        }
+
+        match key {
+            "wifi_ssid" => replace(&mut self.wifi_ssid, value),
+            "wifi_password" if !value.is_empty() && !password_valid(value) => {
+                Err(ConfigError::Invalid)
+            }
+            "wifi_password" => replace(&mut self.wifi_password, value),
+            "bus_stop" => replace(&mut self.bus_stop, value),
+            "routes" => {
+                let mut routes = self.clone();
+                replace(&mut routes.routes, value)?;
+                if !routes.routes_valid() {
+                    return Err(ConfigError::Invalid);
+                }
+                self.routes = routes.routes;
+                Ok(())
+            }
+            "proxy_ip" => replace(&mut self.proxy_ip, value),
+            _ => Err(ConfigError::UnknownKey),
+        }
+    }
+
+    /// Entries of `routes`, in display order. `set`, the setup portal and
+    /// `init` only let through routes that pass `routes_valid`, so there
+    /// are at most `MAX_ROUTES` and none are skipped.
+    pub fn routes(&self) -> impl Iterator<Item = RouteSpec<'_>> {
+        self.route_entries().filter_map(RouteSpec::parse)
+    }
+
+    /// Whether `routes` lists 1 to `MAX_ROUTES` routes, all valid
+    pub fn routes_valid(&self) -> bool {
+        let count = self.route_entries().count();
+        (1..=MAX_ROUTES).contains(&count)
+            && self
+                .route_entries()
+                .all(|entry| RouteSpec::parse(entry).is_some())
+    }
+
+    fn route_entries(&self) -> impl Iterator<Item = &str> {
+        self.routes
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+    }
+}
+
+impl Settings for Config {
+    const KEYS: &'static [&'static str] = &Config::KEYS;
+
+    fn get(&self, key: &str) -> Option<&str> {
+        Config::get(self, key)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), SetError> {
+        Config::set(self, key, value).map_err(SetError::from)
+    }
+}
+
+/// Whether `password` can be used for WPA: a passphrase of 8 to 63
+/// characters, or the key itself as 64 hex digits
+pub fn password_valid(password: &str) -> bool {
+    (8..=63).contains(&password.len())
+        || (password.len() == 64 && password.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Most routes the sign can watch
+pub const MAX_ROUTES: usize = 8;
+
+/// One entry of `Config::routes`, written `id[/direction][@stop][#tag]`:
+/// "87" for both directions at the sign's stop, "87/1" for MBTA direction
+/// ID 1 only, "87@2580" for another stop and "87@2580#N" to label the row
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RouteSpec<'a> {
+    pub id: &'a str,
+    pub direction: Option<u8>,
+    /// `None` for `Config::bus_stop`
+    pub stop: Option<&'a str>,
+    /// shown in place of "BUS"
+    pub tag: Option<&'a str>,
+}
+
+impl<'a> RouteSpec<'a> {
+    pub fn parse(entry: &'a str) -> Option<Self> {
+        fn is_id(value: &str) -> bool {
+            !value.is_empty()
+                && value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        }
+
+        let (entry, tag) = match entry.split_once('#') {
+            Some((entry, tag)) => (entry, Some(tag)),
+            None => (entry, None),
+        };
+        let (entry, stop) = match entry.split_once('@') {
+            Some((entry, stop)) => (entry, Some(stop)),
+            None => (entry, None),
+        };
+        let (id, direction) = match entry.split_once('/') {
+            Some((id, "0")) => (id, Some(0)),
+            Some((id, "1")) => (id, Some(1)),
+            Some(_) => return None,
+            None => (entry, None),
+        };
+
+        let valid = is_id(id)
+            && stop.map_or(true, |stop| stop.len() <= 16 && is_id(stop))
+            && tag.map_or(true, |tag| {
+                (1..=3).contains(&tag.len()) && tag.chars().all(|c| c.is_ascii_alphanumeric())
+            });
+        valid.then_some(RouteSpec {
+            id,
+            direction,
+            stop,
+            tag,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Flash(embassy_rp::flash::Error),
+    TooLarge,
+    NotInitialized,
+    UnknownKey,
+    Invalid,
+}
+
+impl From<ConfigError> for SetError {
+    fn from(error: ConfigError) -> Self {
+        match error {
+            ConfigError::UnknownKey => SetError::UnknownKey,
+            ConfigError::TooLarge => SetError::TooLarge,
+            ConfigError::Invalid => SetError::Invalid,
+            ConfigError::Flash(_) | ConfigError::NotInitialized => SetError::Save,
+        }
+    }
+}
+
+/// Read a record written by an older firmware
+fn migrate(version: u16, payload: &[u8]) -> Option<Config> {
+    match version {
+        CONFIG_VERSION => serde_json_core::from_slice::<Config>(payload)
+            .ok()
+            .map(|(config, _used)| config),
+        _ => None,
+    }
+}
+
+struct Store {
+    flash: &'static SharedFlash,
+    /// slot holding the newest valid record
+    latest: Option<usize>,
+    sequence: u32,
+}
+
+static STORE: Mutex<ThreadModeRawMutex, Option<Store>> = Mutex::new(None);
+
+/// Load the stored configuration, falling back to defaults
+pub async fn init(flash: &'static SharedFlash) -> Config {
+    let mut store = Store {
+        flash,
+        latest: None,
+        sequence: 0,
+    };
+
+    let mut payload = [0; MAX_PAYLOAD];
+    let mut config = None;
+    for slot in 0..SLOTS {
+        let Some(header) = store.read_slot(slot, &mut payload) else {
+            continue;
+        };
+        if store.latest.is_some() && header.sequence <= store.sequence {
+            continue;
+        }
+        match migrate(header.version, &payload[..header.len]) {
+            Some(stored) => {
+                config = Some(stored);
+                store.latest = Some(slot);
+                store.sequence = header.sequence;
+            }
+            None => warn!(
+                "Config slot {} has unreadable version {}",
+                slot, header.version
+            ),
+        }
+    }
+
+    let config = match config {
+        Some(mut config) => {
+            info!("Loaded config record {}", store.sequence);
+            if !config.routes_valid() {
+                warn!(
+                    "Stored routes {:?} are not valid, using the defaults",
+                    config.routes.as_str()
+                );
+                config.routes = Config::default().routes;
+            }
+            config
+        }
+        None => {
+            info!("No stored config, using defaults");
+            Config::default()
+        }
+    };
+
+    *(STORE.lock().await) = Some(store);
+    config
+}
+
+/// Persist `config`, skipping the write if nothing changed
+pub async fn save(config: &Config) -> Result<(), ConfigError> {
+    let mut store_locked = STORE.lock().await;
+    let store = store_locked.as_mut().ok_or(ConfigError::NotInitialized)?;
+
+    let mut record = [0xff; SLOT_SIZE];
+    let len = serde_json_core::to_slice(config, &mut record[HEADER_SIZE..])
+        .map_err(|_| ConfigError::TooLarge)?;
+
+    if let Some(latest) = store.latest {
+        let mut payload = [0; MAX_PAYLOAD];
+        if let Some(header) = store.read_slot(latest, &mut payload) {
+            if header.version == CONFIG_VERSION
+                && payload[..header.len] == record[HEADER_SIZE..HEADER_SIZE + len]
+            {
+                info!("Config unchanged, not writing");
+                return Ok(());
+            }
+        }
+    }
+
+    let slot = store.latest.map_or(0, |latest| (latest + 1) % SLOTS);
+    let sequence = store.sequence.wrapping_add(1);
+
+    record[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    record[4..6].copy_from_slice(&CONFIG_VERSION.to_le_bytes());
+    record[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+    record[8..12].copy_from_slice(&sequence.to_le_bytes());
+    let crc = crc32(&record[4..12], &record[HEADER_SIZE..HEADER_SIZE + len]);
+    record[12..16].copy_from_slice(&crc.to_le_bytes());
+
+    // Entering a sector erases it, the newest record is always in the
+    // other sector until this write completes
+    let offset = slot_offset(slot);
+    store
+        .flash
+        .lock(|flash| {
+            let mut flash = flash.borrow_mut();
+            if slot % SLOTS_PER_SECTOR == 0 {
+                flash.blocking_erase(offset, offset + ERASE_SIZE as u32)?;
+            }
+            flash.blocking_write(offset, &record[..HEADER_SIZE + len])
+        })
+        .map_err(ConfigError::Flash)?;
+
+    info!("Saved config record {} to slot {}", sequence, slot);
+    store.latest = Some(slot);
+    store.sequence = sequence;
+    Ok(())
+}
+
+struct Header {
+    version: u16,
+    len: usize,
+    sequence: u32,
+}
+
+impl Store {
+    /// Header of a valid record in `slot`, with its payload copied out
+    fn read_slot(&mut self, slot: usize, payload: &mut [u8; MAX_PAYLOAD]) -> Option<Header> {
+        let offset = slot_offset(slot);
+        let mut header = [0; HEADER_SIZE];
+        self.read(offset, &mut header)?;
+
+        let word = |index: usize| {
+            u32::from_le_bytes([
+                header[index],
+                header[index + 1],
+                header[index + 2],
+                header[index + 3],
+            ])
+        };
+        if word(0) != MAGIC {
+            return None;
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        let len = u16::from_le_bytes([header[6], header[7]]) as usize;
+        if len > MAX_PAYLOAD {
+            return None;
+        }
+
+        self.read(offset + HEADER_SIZE as u32, &mut payload[..len])?;
+        if crc32(&header[4..12], &payload[..len]) != word(12) {
+            warn!("Config slot {} fails checksum", slot);
+            return None;
+        }
+
+        Some(Header {
+            version,
+            len,
+            sequence: word(8),
+        })
+    }
+
+    fn read(&self, offset: u32, bytes: &mut [u8]) -> Option<()> {
+        self.flash
+            .lock(|flash| flash.borrow_mut().blocking_read(offset, bytes))
+            .ok()
+    }
+}
+
+fn slot_offset(slot: usize) -> u32 {
+    REGION_OFFSET + (slot * SLOT_SIZE) as u32
+}
+
+/// CRC-32 (IEEE) over the header fields and payload
+fn crc32(header: &[u8], payload: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in header.iter().chain(payload) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
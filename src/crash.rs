@@ -0,0 +1,404 @@
+//! Keeps the last panic across the restart that follows it. The panic
+//! handler writes the message, location and uptime to a few hundred bytes
+//! of RAM that `memory.x` keeps out of everything else, in this firmware
+//! and the bootloader, and which a reset leaves alone. `init` picks the
+//! record up on the next boot for the log, the status API and the display.
+//!
+//! The panic and HardFault handlers then take over the display `main`
+//! handed to `keep_display` and show a red error screen with a short code
+//! and the file and line, for someone at the sign to read out. Nothing
+//! else runs any more, so they feed the watchdog themselves for
+//! `SCREEN_TIME` and then let it restart the sign.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{compiler_fence, AtomicBool, Ordering};
+use cortex_m_rt::{exception, ExceptionFrame};
+use embassy_rp::peripherals::WATCHDOG;
+use embassy_rp::watchdog::Watchdog;
+use embassy_sync::blocking_mutex;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_time::{block_for, Duration, Instant};
+use embedded_graphics::mono_font::{ascii::FONT_4X6, MonoTextStyle};
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::{Point, Primitive, RgbColor, Size};
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::text::Text;
+use embedded_graphics::Drawable;
+use galactic_unicorn_embassy::{GalacticUnicorn, HEIGHT, WIDTH};
+use heapless::String;
+use log::*;
+use unicorn_graphics::UnicornGraphics;
+
+use crate::watchdog;
+
+/// Longest source path kept
+pub const FILE_SIZE: usize = 60;
+
+/// Longest message kept, the rest is cut off
+pub const MESSAGE_SIZE: usize = 428;
+
+/// Marks the record as written by `panic`
+const MAGIC: u32 = 0x5041_4e43;
+
+/// Stands in for the source file of a HardFault, which has none
+const FAULT_FILE: &str = "HardFault";
+
+/// Error screen brightness, what `main` starts the display at
+const BRIGHTNESS: u8 = 100;
+
+/// Time between one-pixel scroll steps of the error screen
+const SCROLL_STEP: Duration = Duration::from_millis(150);
+
+/// How long the error screen is up before the watchdog restarts the sign
+const SCREEN_TIME: Duration = Duration::from_secs(60);
+
+/// Set by the first panic or fault, another while showing it restarts
+/// straight away
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// As laid out in the reserved RAM, `PANIC` in memory.x
+#[repr(C)]
+struct Record {
+    magic: u32,
+    /// seconds since boot
+    uptime: u32,
+    line: u32,
+    file_len: u32,
+    message_len: u32,
+    file: [u8; FILE_SIZE],
+    message: [u8; MESSAGE_SIZE],
+}
+
+// the region is 512 bytes
+const _: () = assert!(core::mem::size_of::<Record>() <= 0x200);
+
+extern "C" {
+    static mut __panic_start: u32;
+}
+
+/// A panic from before the last restart
+#[derive(Clone)]
+pub struct Panic {
+    /// source file, eg "src/rtc.rs"
+    pub file: String<FILE_SIZE>,
+    pub line: u32,
+    pub message: String<MESSAGE_SIZE>,
+    /// seconds after boot it happened
+    pub uptime: u32,
+}
+
+impl Panic {
+    /// "PANIC", or "FAULT" for a HardFault
+    pub fn title(&self) -> &'static str {
+        if self.file == FAULT_FILE {
+            "FAULT"
+        } else {
+            "PANIC"
+        }
+    }
+
+    /// Short code also shown on the error screen
+    pub fn code(&self) -> u16 {
+        code(&self.file, self.line)
+    }
+
+    /// Source file and line, eg "src/rtc.rs:51"
+    pub fn location(&self) -> Location<'_> {
+        Location {
+            file: &self.file,
+            line: self.line,
+        }
+    }
+
+    /// `location` without the file's directories, eg "rtc.rs:51"
+    pub fn short_location(&self) -> Location<'_> {
+        Location {
+            file: file_name(&self.file),
+            line: self.line,
+        }
+    }
+}
+
+/// Where a panic happened, just the file for a HardFault
+pub struct Location<'a> {
+    file: &'a str,
+    line: u32,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            f.write_str(self.file)
+        } else {
+            write!(f, "{}:{}", self.file, self.line)
+        }
+    }
+}
+
+fn file_name(file: &str) -> &str {
+    file.rsplit('/').next().unwrap_or(file)
+}
+
+/// FNV-1a of the file and line, folded to 16 bits
+fn code(file: &str, line: u32) -> u16 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in file.bytes().chain(line.to_le_bytes()) {
+        hash = (hash ^ byte as u32).wrapping_mul(0x0100_0193);
+    }
+    (hash >> 16) as u16 ^ hash as u16
+}
+
+static LAST: Mutex<ThreadModeRawMutex, Option<Panic>> = Mutex::new(None);
+
+/// The display once `main` has set it up, behind a critical section so the
+/// handlers can take it over from whatever was drawing
+static DISPLAY: blocking_mutex::Mutex<
+    CriticalSectionRawMutex,
+    RefCell<Option<GalacticUnicorn<'static>>>,
+> = blocking_mutex::Mutex::new(RefCell::new(None));
+
+/// Hand the display over, for the error screen to use as it is
+pub fn keep_display(gu: GalacticUnicorn<'static>) {
+    DISPLAY.lock(|display| *display.borrow_mut() = Some(gu));
+}
+
+/// Draw on the display handed to `keep_display`
+pub fn with_display<R>(f: impl FnOnce(&mut GalacticUnicorn<'static>) -> R) -> R {
+    DISPLAY.lock(|display| {
+        let mut display = display.borrow_mut();
+        f(display.as_mut().expect("display not handed over yet"))
+    })
+}
+
+fn record() -> *mut Record {
+    unsafe { addr_of_mut!(__panic_start) as *mut Record }
+}
+
+/// Take the record left by a panic before the restart and report it
+pub async fn init() {
+    // anything but a panic leaves garbage here after power on
+    let panic = unsafe {
+        let record = &mut *record();
+        let panic = read(record);
+        record.magic = 0;
+        panic
+    };
+    if let Some(panic) = &panic {
+        error!(
+            "Restarted after {} {:04X} at {}, {} s after boot: {}",
+            panic.title(),
+            panic.code(),
+            panic.location(),
+            panic.uptime,
+            panic.message
+        );
+    }
+    *(LAST.lock().await) = panic;
+}
+
+fn read(record: &Record) -> Option<Panic> {
+    if record.magic != MAGIC
+        || record.file_len as usize > FILE_SIZE
+        || record.message_len as usize > MESSAGE_SIZE
+    {
+        return None;
+    }
+    let file = core::str::from_utf8(&record.file[..record.file_len as usize]).ok()?;
+    let message = core::str::from_utf8(&record.message[..record.message_len as usize]).ok()?;
+    Some(Panic {
+        file: String::try_from(file).ok()?,
+        line: record.line,
+        message: String::try_from(message).ok()?,
+        uptime: record.uptime,
+    })
+}
+
+/// The panic before the last restart, if that's why it restarted
+pub async fn last() -> Option<Panic> {
+    LAST.lock().await.clone()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    if PANICKING.load(Ordering::Relaxed) {
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+    PANICKING.store(true, Ordering::Relaxed);
+    defmt::error!("{}", defmt::Display2Format(info));
+
+    let (file, line) = info
+        .location()
+        .map_or(("", 0), |location| (location.file(), location.line()));
+    // `PanicInfo` shows the location, then the message on the next line
+    save(file, line, |message| {
+        write!(
+            AfterNewline {
+                inner: message,
+                started: false
+            },
+            "{}",
+            info
+        )
+    });
+    show(
+        "PANIC",
+        code(file, line),
+        Location {
+            file: file_name(file),
+            line,
+        },
+    )
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    if PANICKING.load(Ordering::Relaxed) {
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+    PANICKING.store(true, Ordering::Relaxed);
+    let pc = frame.pc();
+    defmt::error!("HardFault at pc {:#x}", pc);
+
+    save(FAULT_FILE, 0, |message| write!(message, "pc {:#010x}", pc));
+    let mut detail = String::<16>::new();
+    write!(detail, "PC {:08X}", pc).ok();
+    show("FAULT", code(FAULT_FILE, 0), detail)
+}
+
+/// Fill in the record for `init` to find after the restart
+fn save(file: &str, line: u32, message: impl FnOnce(&mut Bytes<'_>) -> fmt::Result) {
+    let record = unsafe { &mut *record() };
+    record.uptime = Instant::now().as_secs() as u32;
+    record.line = line;
+    let mut writer = Bytes {
+        buffer: &mut record.file,
+        len: 0,
+    };
+    writer.write_str(file).ok();
+    record.file_len = writer.len as u32;
+
+    let mut writer = Bytes {
+        buffer: &mut record.message,
+        len: 0,
+    };
+    message(&mut writer).ok();
+    record.message_len = writer.len as u32;
+    record.magic = MAGIC;
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Show `title` and `code` in red with `detail` below, scrolling back and
+/// forth when it's wider than the display, for `SCREEN_TIME` and then
+/// until the watchdog restarts the sign
+fn show(title: &str, code: u16, detail: impl fmt::Display) -> ! {
+    // the watchdog task is never going to run again
+    let mut watchdog = Watchdog::new(unsafe { WATCHDOG::steal() });
+    watchdog.start(watchdog::TIMEOUT);
+    let until = Instant::now() + SCREEN_TIME;
+
+    // not there before `main` hands it over. When drawing is what failed
+    // it is still borrowed, but by a `with_display` that never returns, so
+    // take it past the `RefCell` rather than leave the screen dark
+    DISPLAY.lock(|display| {
+        let display = unsafe { &mut *display.as_ptr() };
+        if let Some(gu) = display.as_mut() {
+            draw_error(gu, &mut watchdog, until, title, code, detail);
+        }
+    });
+    // stop feeding, the watchdog restarts the sign
+    loop {
+        block_for(SCROLL_STEP);
+    }
+}
+
+/// The error screen, scrolling until `until`
+fn draw_error(
+    gu: &mut GalacticUnicorn<'static>,
+    watchdog: &mut Watchdog,
+    until: Instant,
+    title: &str,
+    code: u16,
+    detail: impl fmt::Display,
+) {
+    gu.brightness = BRIGHTNESS;
+
+    let mut heading = String::<16>::new();
+    write!(heading, "{} {:04X}", title, code).ok();
+    let mut text = String::<80>::new();
+    write!(text, "{}", detail).ok();
+    let red = MonoTextStyle::new(&FONT_4X6, Rgb888::RED);
+    let white = MonoTextStyle::new(&FONT_4X6, Rgb888::WHITE);
+
+    let mut graphics = UnicornGraphics::<WIDTH, HEIGHT>::new();
+    let overflow = (text.len() as i32 * 4 - WIDTH as i32).max(0);
+    let mut offset = 0;
+    let mut step = 1;
+    while Instant::now() < until {
+        Rectangle::new(Point::zero(), Size::new(WIDTH as u32, HEIGHT as u32))
+            .into_styled(PrimitiveStyle::with_fill(Rgb888::BLACK))
+            .draw(&mut graphics)
+            .ok();
+        Text::new(&heading, Point::new(0, 4), red)
+            .draw(&mut graphics)
+            .ok();
+        Text::new(&text, Point::new(-offset, 10), white)
+            .draw(&mut graphics)
+            .ok();
+        gu.set_pixels(&graphics);
+
+        watchdog.feed();
+        block_for(SCROLL_STEP);
+        if overflow > 0 {
+            offset += step;
+            if offset == 0 || offset == overflow {
+                step = -step;
+            }
+        }
+    }
+}
+
+/// Fills a byte buffer, cutting the text off at a character boundary
+struct Bytes<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Bytes<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let mut encoded = [0; 4];
+            let encoded = c.encode_utf8(&mut encoded).as_bytes();
+            let end = self.len + encoded.len();
+            if end > self.buffer.len() {
+                return Err(fmt::Error);
+            }
+            self.buffer[self.len..end].copy_from_slice(encoded);
+            self.len = end;
+        }
+        Ok(())
+    }
+}
+
+/// Drops text up to and including the first line break
+struct AfterNewline<'a, W: Write> {
+    inner: &'a mut W,
+    started: bool,
+}
+
+impl<W: Write> Write for AfterNewline<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.started {
+            return self.inner.write_str(s);
+        }
+        if let Some((_, rest)) = s.split_once('\n') {
+            self.started = true;
+            self.inner.write_str(rest)?;
+        }
+        Ok(())
+    }
+}
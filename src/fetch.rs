@@ -1,81 +1,379 @@
 use core::fmt::Write;
-use embassy_net::dns::DnsSocket;
+use embassy_net::dns::{DnsQueryType, DnsSocket};
 use embassy_net::tcp::client::{TcpClient, TcpClientState};
+use embassy_net::tcp::TcpSocket;
 use embassy_net::Stack;
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::Duration;
 use heapless;
 use log::*;
-use reqwless::client::HttpClient;
-use reqwless::request::Method;
+use rand_core::{CryptoRng, RngCore};
+use reqwless::client::{HttpClient, HttpConnection};
+use reqwless::request::{Method, Request, RequestBuilder};
+use serde::de::{DeserializeOwned, IgnoredAny};
 use serde::Deserialize;
 
+use crate::proxy::{self, Endpoint, ForwardProxy, UrlParts};
+use crate::sign::CONFIG;
 use crate::timestamp::Timestamp;
+use crate::tls12::{self, Tls12Connection, TlsError};
 
-pub async fn fetch_time(stack: &'static Stack<cyw43::NetDriver<'static>>) -> Option<Timestamp> {
+/// Records and handshake of the one TLS connection open at a time, too big
+/// for a task's stack. Behind a mutex rather than in a `StaticCell` since
+/// the clock and the arrivals both fetch, and take turns here.
+static TLS_BUFFER: Mutex<ThreadModeRawMutex, [u8; tls12::BUFFER_SIZE]> =
+    Mutex::new([0; tls12::BUFFER_SIZE]);
+
+/// Ways a fetch can fail, from the socket up to the JSON body
+#[derive(Debug)]
+pub enum FetchError {
+    Url,
+    Request,
+    Send,
+    Body,
+    Parse,
+    Proxy,
+    Tls(TlsError),
+    Api(ApiError),
+}
+
+impl FetchError {
+    /// Short uppercase code that fits on one row of the display
+    pub fn short_code(&self) -> &'static str {
+        match self {
+            FetchError::Url => "BAD URL",
+            FetchError::Request | FetchError::Send => "NO CONN",
+            FetchError::Body => "NET ERR",
+            FetchError::Parse => "BAD DATA",
+            FetchError::Proxy => "PROXY",
+            FetchError::Tls(TlsError::PinMismatch | TlsError::BadCertificate) => "BAD CERT",
+            FetchError::Tls(_) => "TLS ERR",
+            FetchError::Api(api_error) => api_error.short_code(),
+        }
+    }
+
+    /// Kind of error, as counted in `metrics::FETCH_FAILURES`
+    pub fn label(&self) -> &'static str {
+        match self {
+            FetchError::Url => "url",
+            FetchError::Request | FetchError::Send => "connect",
+            FetchError::Body => "network",
+            FetchError::Parse => "parse",
+            FetchError::Proxy => "proxy",
+            FetchError::Tls(TlsError::PinMismatch | TlsError::BadCertificate) => "cert",
+            FetchError::Tls(_) => "tls",
+            FetchError::Api(_) => "api",
+        }
+    }
+}
+
+/// First entry of a JSON:API `errors` array
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    pub code: heapless::String<32>,
+    pub detail: heapless::String<64>,
+    pub parameter: heapless::String<32>,
+}
+
+impl ApiError {
+    pub fn short_code(&self) -> &'static str {
+        if self.parameter.contains("stop") {
+            "BAD STOP"
+        } else if self.parameter.contains("route") {
+            "BAD ROUTE"
+        } else if self.status == 404 || self.code == "not_found" {
+            "NOT FOUND"
+        } else if self.status == 403 || self.code == "forbidden" {
+            "NO AUTH"
+        } else if self.status == 429 {
+            "RATE LIM"
+        } else if self.status >= 500 {
+            "API DOWN"
+        } else {
+            "API ERR"
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorDocument<'a> {
+    #[serde(borrow)]
+    errors: heapless::Vec<ErrorObject<'a>, 4>,
+}
+
+#[derive(Deserialize)]
+struct ErrorObject<'a> {
+    status: Option<&'a str>,
+    code: Option<&'a str>,
+    detail: Option<&'a str>,
+    #[serde(borrow)]
+    source: Option<ErrorSource<'a>>,
+}
+
+#[derive(Deserialize)]
+struct ErrorSource<'a> {
+    parameter: Option<&'a str>,
+}
+
+impl From<&ErrorObject<'_>> for ApiError {
+    fn from(value: &ErrorObject<'_>) -> Self {
+        ApiError {
+            status: value.status.and_then(|s| s.parse().ok()).unwrap_or(0),
+            code: truncated(value.code.unwrap_or("")),
+            detail: truncated(value.detail.unwrap_or("")),
+            parameter: truncated(
+                value
+                    .source
+                    .as_ref()
+                    .and_then(|source| source.parameter)
+                    .unwrap_or(""),
+            ),
+        }
+    }
+}
+
+/// copy as much of `value` as fits
+fn truncated<const N: usize>(value: &str) -> heapless::String<N> {
+    let mut string = heapless::String::new();
+    for c in value.chars() {
+        if string.push(c).is_err() {
+            break;
+        }
+    }
+    string
+}
+
+/// Local time in `CONFIG.time_zone` and how many minutes it is ahead of UTC
+pub async fn fetch_time(
+    stack: &'static Stack<cyw43::NetDriver<'static>>,
+    endpoint: &Endpoint,
+) -> Result<(Timestamp, i32), FetchError> {
     #[derive(Deserialize)]
     struct Response<'a> {
         datetime: &'a str,
+        /// seconds, standard time
+        raw_offset: i32,
+        /// seconds added for daylight saving
+        dst_offset: i32,
     }
 
-    let url = "http://worldtimeapi.org/api/timezone/America/New_York";
+    let mut url: heapless::String<URL_SIZE> = heapless::String::new();
+    write!(
+        &mut url,
+        "http://worldtimeapi.org/api/timezone/{}",
+        CONFIG.time_zone
+    )
+    .map_err(|_| FetchError::Url)?;
     let mut rx_buffer = [0; 1024];
 
-    let json = fetch_json::<Response>(stack, url, &mut rx_buffer).await?;
+    let json = fetch_json::<Response>(stack, endpoint, &url, &mut rx_buffer).await?;
     info!("Current time: {:?}", json.datetime);
-    Timestamp::parse(json.datetime)
+    let now = Timestamp::parse(json.datetime).ok_or(FetchError::Parse)?;
+    Ok((now, (json.raw_offset + json.dst_offset) / 60))
 }
 
-const VEC_SIZE: usize = 2;
+const URL_SIZE: usize = 256;
+
+/// Resources requested per page, small enough for one rx buffer
+const PAGE_LIMIT: usize = 2;
+
+#[derive(Deserialize)]
+struct Page<T, const P: usize> {
+    data: heapless::Vec<T, P>,
+    links: Option<Links>,
+}
 
-pub async fn fetch_next_bus(
+#[derive(Deserialize)]
+struct Links {
+    next: Option<IgnoredAny>,
+}
+
+/// Walks a JSON:API collection one `page[offset]` at a time
+pub struct Paginator<'e, const P: usize> {
+    stack: &'static Stack<cyw43::NetDriver<'static>>,
+    endpoint: &'e Endpoint,
+    url: heapless::String<URL_SIZE>,
+    offset: usize,
+    done: bool,
+}
+
+impl<'e, const P: usize> Paginator<'e, P> {
+    /// `url` must already contain a query string, paging parameters are
+    /// appended. Fails with `FetchError::Url` when `url` is too long.
+    pub fn new(
+        stack: &'static Stack<cyw43::NetDriver<'static>>,
+        endpoint: &'e Endpoint,
+        url: &str,
+    ) -> Result<Self, FetchError> {
+        Ok(Paginator {
+            stack,
+            endpoint,
+            url: heapless::String::try_from(url).map_err(|_| FetchError::Url)?,
+            offset: 0,
+            done: false,
+        })
+    }
+
+    /// Fetch the next page, or `None` after the last page or an error
+    pub async fn next_page<T>(&mut self) -> Option<Result<heapless::Vec<T, P>, FetchError>>
+    where
+        T: DeserializeOwned,
+    {
+        if self.done {
+            return None;
+        }
+
+        let mut url: heapless::String<URL_SIZE> = heapless::String::new();
+        if write!(
+            &mut url,
+            "{}&page[limit]={}&page[offset]={}",
+            self.url, P, self.offset
+        )
+        .is_err()
+        {
+            self.done = true;
+            return Some(Err(FetchError::Url));
+        }
+
+        let mut rx_buffer = [0; 2048];
+        let page =
+            match fetch_json::<Page<T, P>>(self.stack, self.endpoint, url.as_str(), &mut rx_buffer)
+                .await
+            {
+                Ok(page) => page,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+
+        self.offset += page.data.len();
+        self.done = page.data.is_empty() || page.links.and_then(|links| links.next).is_none();
+        Some(Ok(page.data))
+    }
+}
+
+/// Predicted arrival of one bus at a stop
+#[derive(Debug)]
+pub struct Arrival {
+    pub route: heapless::String<16>,
+    pub direction: u8,
+    pub time: Timestamp,
+}
+
+/// What `fetch_arrivals` does with each arrival it reads
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Take {
+    Skip,
+    Keep,
+    /// keep it, and it was the last one needed
+    Last,
+}
+
+/// Arrivals at `stop` on any of `routes`, a comma separated list of route
+/// IDs, soonest first. Every route is in the one query, whose pages are
+/// requested one after another until `take` has had all it needs or the
+/// predictions run out. That can take several requests when one route runs
+/// far more often than the others.
+pub async fn fetch_arrivals<const N: usize>(
     stack: &'static Stack<cyw43::NetDriver<'static>>,
-    route: u8,
+    endpoint: &Endpoint,
     stop: &str,
-) -> Option<heapless::Vec<Timestamp, VEC_SIZE>> {
+    routes: &str,
+    mut take: impl FnMut(&Arrival) -> Take,
+) -> Result<heapless::Vec<Arrival, N>, FetchError> {
     #[derive(Deserialize)]
     struct Prediction {
         attributes: Attributes,
+        relationships: Relationships,
     }
 
     #[derive(Deserialize)]
     struct Attributes {
         arrival_time: heapless::String<32>,
+        direction_id: u8,
+    }
+
+    #[derive(Deserialize)]
+    struct Relationships {
+        route: Relationship,
     }
 
     #[derive(Deserialize)]
-    struct Response {
-        data: heapless::Vec<Prediction, VEC_SIZE>,
+    struct Relationship {
+        data: ResourceId,
     }
 
-    let mut url: heapless::String<100> = heapless::String::new();
+    #[derive(Deserialize)]
+    struct ResourceId {
+        id: heapless::String<16>,
+    }
+
+    let mut url: heapless::String<URL_SIZE> = heapless::String::new();
     write!(
         &mut url,
-        "http://{}/predictions?filter[route]={}&filter[stop]={}&page[limit]={}",
-        env!("MBTA_PROXY_IP"),
-        route,
-        stop,
-        VEC_SIZE
+        "{}/predictions?filter[route]={}&filter[stop]={}&fields[prediction]=arrival_time,direction_id&sort=arrival_time",
+        endpoint.upstream, routes, stop
     )
-    .ok()?;
+    .map_err(|_| FetchError::Url)?;
 
-    let mut rx_buffer = [0; 2048];
-    let json = fetch_json::<Response>(stack, url.as_str(), &mut rx_buffer).await?;
-    let arrival_times: heapless::Vec<Timestamp, VEC_SIZE> = json
-        .data
-        .iter()
-        .filter_map(|prediction| Timestamp::parse(prediction.attributes.arrival_time.as_str()))
-        .collect();
+    let mut arrivals = heapless::Vec::new();
+    let mut paginator = Paginator::<PAGE_LIMIT>::new(stack, endpoint, url.as_str())?;
+    while let Some(page) = paginator.next_page::<Prediction>().await {
+        for prediction in page? {
+            let Some(time) = Timestamp::parse(prediction.attributes.arrival_time.as_str()) else {
+                continue;
+            };
+            let arrival = Arrival {
+                route: prediction.relationships.route.data.id,
+                direction: prediction.attributes.direction_id,
+                time,
+            };
+            let take = take(&arrival);
+            if take == Take::Skip {
+                continue;
+            }
+            if arrivals.push(arrival).is_err() || take == Take::Last {
+                return Ok(arrivals);
+            }
+        }
+    }
 
-    Some(arrival_times)
+    Ok(arrivals)
 }
 
 async fn fetch_json<'a, T>(
     stack: &'static Stack<cyw43::NetDriver<'static>>,
+    endpoint: &Endpoint,
     url: &str,
     rx_buffer: &'a mut [u8],
-) -> Option<T>
+) -> Result<T, FetchError>
 where
     T: Deserialize<'a>,
 {
+    let parts = UrlParts::parse(url).ok_or(FetchError::Url)?;
+
+    if let Some(proxy) = endpoint.proxy.as_ref() {
+        return fetch_json_via_proxy(stack, endpoint, proxy, &parts, url, rx_buffer).await;
+    }
+
+    if parts.https {
+        let mut socket_rx_buffer = [0; 1024];
+        let mut socket_tx_buffer = [0; 1024];
+        let socket = open_socket(
+            stack,
+            parts.host(),
+            parts.port(),
+            &mut socket_rx_buffer,
+            &mut socket_tx_buffer,
+        )
+        .await?;
+        return fetch_json_over_tls(socket, endpoint, &parts, rx_buffer).await;
+    }
+
     let client_state = TcpClientState::<1, 1024, 1024>::new();
     let tcp_client = TcpClient::new(stack, &client_state);
     let dns_client = DnsSocket::new(stack);
@@ -88,7 +386,7 @@ where
         Ok(req) => req,
         Err(e) => {
             error!("Failed to make HTTP request: {:?}", e);
-            return None;
+            return Err(FetchError::Request);
         }
     };
 
@@ -96,23 +394,215 @@ where
         Ok(resp) => resp,
         Err(_e) => {
             error!("Failed to send HTTP request");
-            return None;
+            return Err(FetchError::Send);
         }
     };
 
-    let body = match response.body().read_to_end().await {
+    let body: &'a [u8] = match response.body().read_to_end().await {
         Ok(content) => content,
         Err(_e) => {
             error!("Failed to read response body");
-            return None;
+            return Err(FetchError::Body);
         }
     };
 
-    match serde_json_core::de::from_slice::<T>(body) {
-        Ok((json, _used)) => Some(json),
+    parse_body(body)
+}
+
+/// Send the request through a forward proxy, either as an absolute-URI
+/// request or inside a `CONNECT` tunnel
+async fn fetch_json_via_proxy<'a, T>(
+    stack: &'static Stack<cyw43::NetDriver<'static>>,
+    endpoint: &Endpoint,
+    proxy: &ForwardProxy,
+    parts: &UrlParts<'_>,
+    url: &str,
+    rx_buffer: &'a mut [u8],
+) -> Result<T, FetchError>
+where
+    T: Deserialize<'a>,
+{
+    let mut socket_rx_buffer = [0; 1024];
+    let mut socket_tx_buffer = [0; 1024];
+    let mut socket = open_socket(
+        stack,
+        proxy.host,
+        proxy.port,
+        &mut socket_rx_buffer,
+        &mut socket_tx_buffer,
+    )
+    .await
+    .map_err(|_| FetchError::Proxy)?;
+
+    if !proxy.tunnel {
+        info!("requesting {} via proxy", url);
+        return request_json(socket, url, parts.authority, rx_buffer).await;
+    }
+
+    let mut authority: heapless::String<64> = heapless::String::new();
+    write!(&mut authority, "{}:{}", parts.host(), parts.port()).map_err(|_| FetchError::Url)?;
+    proxy::tunnel(&mut socket, authority.as_str()).await?;
+
+    if parts.https {
+        fetch_json_over_tls(socket, endpoint, parts, rx_buffer).await
+    } else {
+        request_json(socket, parts.path, parts.authority, rx_buffer).await
+    }
+}
+
+/// Run the TLS handshake on a connected socket, then send the request
+async fn fetch_json_over_tls<'a, T>(
+    socket: TcpSocket<'_>,
+    endpoint: &Endpoint,
+    parts: &UrlParts<'_>,
+    rx_buffer: &'a mut [u8],
+) -> Result<T, FetchError>
+where
+    T: Deserialize<'a>,
+{
+    let pins = tls12::parse_pins::<4>(endpoint.upstream.tls_pins);
+    if pins.is_empty() {
+        error!("No TLS pins configured for {}", parts.host());
+        return Err(FetchError::Tls(TlsError::PinMismatch));
+    }
+
+    let mut tls_buffer = TLS_BUFFER.lock().await;
+    let connection = Tls12Connection::connect(
+        socket,
+        &mut tls_buffer[..],
+        parts.host(),
+        &pins,
+        &mut RoscCryptoRng,
+    )
+    .await
+    .map_err(|e| {
+        error!("TLS handshake failed: {:?}", e);
+        FetchError::Tls(e)
+    })?;
+
+    request_json(connection, parts.path, parts.authority, rx_buffer).await
+}
+
+/// Resolve `host` and open a TCP connection to it
+pub async fn open_socket<'s>(
+    stack: &'static Stack<cyw43::NetDriver<'static>>,
+    host: &str,
+    port: u16,
+    rx_buffer: &'s mut [u8],
+    tx_buffer: &'s mut [u8],
+) -> Result<TcpSocket<'s>, FetchError> {
+    // IPv6 literals and hosts with only an AAAA record are reached over
+    // IPv6 when router advertisements gave the sign an address
+    let mut query = if host.contains(':') {
+        stack.dns_query(host, DnsQueryType::Aaaa).await
+    } else {
+        stack.dns_query(host, DnsQueryType::A).await
+    };
+    if query.is_err() && stack.config_v6().is_some() {
+        query = stack.dns_query(host, DnsQueryType::Aaaa).await;
+    }
+    let address = match query {
+        Ok(addresses) => *addresses.first().ok_or(FetchError::Request)?,
+        Err(e) => {
+            error!("Failed to resolve {}: {:?}", host, e);
+            return Err(FetchError::Request);
+        }
+    };
+
+    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    socket.set_timeout(Some(Duration::from_secs(10)));
+
+    info!("connecting to {}:{}", host, port);
+    if let Err(e) = socket.connect((address, port)).await {
+        error!("Failed to connect to {}: {:?}", host, e);
+        return Err(FetchError::Request);
+    }
+
+    Ok(socket)
+}
+
+/// Send a GET for `target` over an open connection and parse the reply
+async fn request_json<'a, T, C>(
+    connection: C,
+    target: &str,
+    host: &str,
+    rx_buffer: &'a mut [u8],
+) -> Result<T, FetchError>
+where
+    T: Deserialize<'a>,
+    C: embedded_io_async::Read + embedded_io_async::Write,
+{
+    let request = Request::get(target).host(host).build();
+    let mut connection = HttpConnection::Plain(connection);
+    let response = match connection.send(request, rx_buffer).await {
+        Ok(resp) => resp,
+        Err(_e) => {
+            error!("Failed to send HTTP request");
+            return Err(FetchError::Send);
+        }
+    };
+
+    let body: &'a [u8] = match response.body().read_to_end().await {
+        Ok(content) => content,
+        Err(_e) => {
+            error!("Failed to read response body");
+            return Err(FetchError::Body);
+        }
+    };
+
+    parse_body(body)
+}
+
+/// The RP2040 ring oscillator is the only entropy source on the board
+struct RoscCryptoRng;
+
+impl RngCore for RoscCryptoRng {
+    fn next_u32(&mut self) -> u32 {
+        RoscRng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        RoscRng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RoscRng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        RoscRng.try_fill_bytes(dest)
+    }
+}
+
+impl CryptoRng for RoscCryptoRng {}
+
+/// Deserialize `T`, or the JSON:API error document sent in its place
+fn parse_body<'a, T>(body: &'a [u8]) -> Result<T, FetchError>
+where
+    T: Deserialize<'a>,
+{
+    if let Ok((json, _used)) = serde_json_core::de::from_slice::<T>(body) {
+        return Ok(json);
+    }
+
+    match serde_json_core::de::from_slice::<ErrorDocument>(body) {
+        Ok((document, _used)) => match document.errors.first() {
+            Some(error_object) => {
+                let api_error = ApiError::from(error_object);
+                error!(
+                    "API error {}: {} {} ({})",
+                    api_error.status, api_error.code, api_error.detail, api_error.parameter
+                );
+                Err(FetchError::Api(api_error))
+            }
+            None => {
+                error!("Failed to parse response body");
+                Err(FetchError::Parse)
+            }
+        },
         Err(_e) => {
             error!("Failed to parse response body");
-            None
+            Err(FetchError::Parse)
         }
     }
 }
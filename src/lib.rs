@@ -4,29 +4,56 @@
 use cyw43::{Control, NetDriver, PowerManagementMode, Runner, State};
 use cyw43_pio::PioSpi;
 use embassy_executor::Spawner;
-use embassy_net::{Config, Stack, StackResources};
+use embassy_net::driver::{Driver, HardwareAddress};
+use embassy_net::{Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4};
 use embassy_rp::clocks::RoscRng;
 use embassy_rp::{
     bind_interrupts,
     gpio::{Level, Output},
     peripherals::{DMA_CH1, PIN_23, PIN_24, PIN_25, PIN_29, PIO1, USB},
     pio::{InterruptHandler as PioInterruptHandler, Pio},
-    usb::{Driver, InterruptHandler as UsbInterruptHandler},
+    usb::InterruptHandler as UsbInterruptHandler,
 };
-use embassy_time::{Duration, Timer};
+use embassy_time::Duration;
 use log::*;
 use rand::RngCore;
 use static_cell::StaticCell;
 
+pub mod config;
+pub mod crash;
 pub mod fetch;
+pub mod logger;
+pub mod mdns;
+pub mod metrics;
+pub mod ota;
+pub mod portal;
+pub mod proxy;
 pub mod rtc;
+pub mod schedule;
+pub mod sign;
+pub mod slaac;
+pub mod solar;
+pub mod syslog;
 pub mod timestamp;
 pub mod universe;
+pub mod usb;
+pub mod watchdog;
+pub mod wifi;
 
+pub use bus_sign_protocol::{api, console, mqtt, tls12};
+
+pub use config::*;
 pub use fetch::*;
+pub use mdns::*;
+pub use portal::*;
+pub use proxy::*;
 pub use rtc::*;
+pub use schedule::*;
+pub use sign::*;
 pub use timestamp::*;
 pub use universe::*;
+pub use usb::*;
+pub use wifi::*;
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => UsbInterruptHandler<USB>;
@@ -34,13 +61,13 @@ bind_interrupts!(struct Irqs {
 });
 
 #[embassy_executor::task]
-async fn usb_logger_task(driver: Driver<'static, USB>) {
-    embassy_usb_logger::run!(1024, log::LevelFilter::Info, driver);
+async fn net_task(stack: &'static Stack<NetDriver<'static>>) -> ! {
+    stack.run().await
 }
 
 #[embassy_executor::task]
-async fn net_task(stack: &'static Stack<NetDriver<'static>>) -> ! {
-    stack.run().await
+async fn slaac_task(stack: &'static Stack<NetDriver<'static>>, mac: [u8; 6]) -> ! {
+    slaac::run(stack, mac).await
 }
 
 #[embassy_executor::task]
@@ -54,11 +81,6 @@ pub fn duration_as_minutes(duration: Duration) -> u64 {
     duration.as_secs() / 60
 }
 
-pub fn start_usb_logger(spawner: Spawner, usb: USB) {
-    let driver = Driver::new(usb, Irqs);
-    spawner.spawn(usb_logger_task(driver)).unwrap();
-}
-
 pub struct WiFiPins {
     pub pin_23: PIN_23,
     pub pin_24: PIN_24,
@@ -68,10 +90,10 @@ pub struct WiFiPins {
     pub dma_ch1: DMA_CH1,
 }
 
-pub async fn connect_to_wifi(
+/// Bring up the CYW43 and a network stack addressed as set in `sign.toml`,
+/// without joining a network
+pub async fn start_wifi(
     spawner: Spawner,
-    wifi_ssid: &'static str,
-    wifi_password: &'static str,
     pins: WiFiPins,
 ) -> (&'static Stack<NetDriver<'static>>, Control<'static>) {
     let fw = include_bytes!("../cyw43-firmware/43439A0.bin");
@@ -101,53 +123,62 @@ pub async fn connect_to_wifi(
         .set_power_management(PowerManagementMode::PowerSave)
         .await;
 
-    let config = Config::dhcpv4(Default::default());
+    let net_config = match &CONFIG.network.ipv4 {
+        Some(ipv4) => embassy_net::Config::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::from_bytes(&ipv4.address), ipv4.prefix_len),
+            gateway: ipv4
+                .gateway
+                .map(|gateway| Ipv4Address::from_bytes(&gateway)),
+            dns_servers: ipv4
+                .dns
+                .iter()
+                .map(|server| Ipv4Address::from_bytes(server))
+                .collect(),
+        }),
+        None => embassy_net::Config::dhcpv4(Default::default()),
+    };
+    let HardwareAddress::Ethernet(mac) = net_device.hardware_address() else {
+        unreachable!("the CYW43 is an Ethernet device")
+    };
 
     // Generate random seed
     let mut rng = RoscRng;
     let seed = rng.next_u64();
 
-    // Init network stack
+    // Init network stack. Opening a socket past `SOCKETS` panics, they go to
+    //  1. embassy-net's DNS queries
+    //  2. its DHCPv4 client
+    //  3. SLAAC's raw ICMPv6 socket
+    //  4. syslog's UDP socket
+    //  5. the API listener, or the portal's HTTP server in setup mode
+    //  6. the MQTT session, or the portal's DNS server
+    //  7. a prediction fetch, or the portal's DHCP server
+    //  8. a clock fetch
+    //  9. an mDNS query for mbta-proxy.py
+    // and three spare
+    const SOCKETS: usize = 12;
     static STACK: StaticCell<Stack<NetDriver<'static>>> = StaticCell::new();
-    static RESOURCES: StaticCell<StackResources<5>> = StaticCell::new();
+    static RESOURCES: StaticCell<StackResources<SOCKETS>> = StaticCell::new();
     let stack = &*STACK.init(Stack::new(
         net_device,
-        config,
-        RESOURCES.init(StackResources::<5>::new()),
+        net_config,
+        RESOURCES.init(StackResources::<SOCKETS>::new()),
         seed,
     ));
 
     spawner.spawn(net_task(stack)).unwrap();
 
-    loop {
-        match control.join_wpa2(wifi_ssid, wifi_password).await {
-            Ok(_) => {
-                info!("connected to {}", wifi_ssid);
-                break;
-            }
-            Err(err) => {
-                info!("join failed with status={}", err.status);
-                Timer::after(Duration::from_secs(10)).await;
-            }
+    if CONFIG.network.ipv6 {
+        // router advertisements go to the all-nodes group
+        if control
+            .add_multicast_address(slaac::ALL_NODES_MAC)
+            .await
+            .is_err()
+        {
+            warn!("can't receive router advertisements, no IPv6");
         }
+        spawner.spawn(slaac_task(stack, mac)).unwrap();
     }
 
-    // Wait for DHCP, not necessary when using static IP
-    info!("waiting for DHCP...");
-    while !stack.is_config_up() {
-        Timer::after_millis(100).await;
-    }
-    info!("DHCP is now up!");
-
-    info!("waiting for link up...");
-    while !stack.is_link_up() {
-        Timer::after_millis(500).await;
-    }
-    info!("Link is up!");
-
-    info!("waiting for stack to be up...");
-    stack.wait_config_up().await;
-    info!("Stack is up!");
-
     (stack, control)
 }
@@ -0,0 +1,38 @@
+//! The `log` backend. Every entry goes to the first USB serial port, and to
+//! `syslog` to be sent on when a syslog server is set.
+
+use embassy_usb_logger::UsbLogger;
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::syslog;
+
+/// Buffers entries for `usb::start_usb`'s logger task to write out
+pub static USB: UsbLogger<1024> = UsbLogger::new();
+
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl Log for Logger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        // `log::max_level` already filtered out the rest
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        USB.log(record);
+        syslog::push(record);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger at `level`, before anything logs
+pub fn init(level: LevelFilter) {
+    // the RP2040 can't compare and swap, and nothing else is running yet
+    unsafe {
+        log::set_logger_racy(&LOGGER)
+            .map(|()| log::set_max_level_racy(level))
+            .ok();
+    }
+}
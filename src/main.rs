@@ -2,18 +2,45 @@
 #![no_main]
 #![feature(type_alias_impl_trait)]
 
-use bus_sign::fetch::{fetch_next_bus, fetch_time};
+use bus_sign::api;
+use bus_sign::config::{self, Config, SharedFlash, MAX_ROUTES};
+use bus_sign::console::{self, Output, SetError};
+use bus_sign::crash;
+use bus_sign::fetch::{fetch_arrivals, fetch_time, open_socket, Arrival, Take};
+use bus_sign::mdns;
+use bus_sign::metrics;
+use bus_sign::mqtt;
+use bus_sign::ota;
+use bus_sign::portal::{self, SETUP_SSID};
+use bus_sign::proxy::{ApiMode, Endpoint, Upstream};
+use bus_sign::schedule::Mode;
+use bus_sign::sign::{MqttSettings, CONFIG};
+use bus_sign::syslog;
+use bus_sign::timestamp::Timestamp;
 use bus_sign::universe;
-use bus_sign::{connect_to_wifi, duration_as_minutes, WiFiPins};
-use bus_sign::{rtc, start_usb_logger};
+use bus_sign::watchdog;
+use bus_sign::wifi::{self, SharedControl};
+use bus_sign::{duration_as_minutes, join_wifi, start_wifi, WiFiPins};
+use bus_sign::{rtc, start_usb, UsbSerial};
+use core::cell::RefCell;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use cyw43::NetDriver;
+use defmt_rtt as _;
+use embassy_boot_rp::FirmwareUpdaterError;
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_net::tcp::TcpSocket;
 use embassy_net::Stack;
+use embassy_rp::flash::Flash;
 use embassy_rp::gpio::{Input, Pull};
+use embassy_rp::watchdog::Watchdog;
+use embassy_sync::blocking_mutex;
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
 use embassy_sync::channel::Channel;
-use embassy_time::{Duration, Instant, Timer};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 use embedded_graphics::mono_font::{ascii::FONT_4X6, MonoTextStyle};
 use embedded_graphics::pixelcolor::Rgb888;
 use embedded_graphics::prelude::{Point, Primitive, RgbColor, Size};
@@ -24,195 +51,1320 @@ use galactic_unicorn_embassy::pins::{UnicornButtonPins, UnicornDisplayPins, Unic
 use galactic_unicorn_embassy::GalacticUnicorn;
 use galactic_unicorn_embassy::{HEIGHT, WIDTH};
 use log::*;
+use static_cell::StaticCell;
 use unicorn_graphics::UnicornGraphics;
-use {defmt_rtt as _, panic_probe as _};
+
+/// Rows of text that fit on the display
+const ROWS: usize = 2;
+
+/// How long each page of routes is shown when they don't all fit
+const PAGE_TIME: Duration = Duration::from_secs(5);
 
 #[derive(Copy, Clone)]
-enum Route {
-    EightySeven,
-    EightyEight,
+struct Route {
+    /// position in the route table
+    index: usize,
+    /// MBTA route ID, eg "87"
+    id: &'static str,
+    /// MBTA direction ID, `None` for both
+    direction: Option<u8>,
+    /// MBTA stop ID
+    stop: &'static str,
+    /// shown in place of "BUS"
+    tag: Option<&'static str>,
 }
 
-impl From<Route> for u8 {
-    fn from(val: Route) -> Self {
-        match val {
-            Route::EightySeven => 87,
-            Route::EightyEight => 88,
+impl Route {
+    fn page(&self) -> usize {
+        self.index / ROWS
+    }
+
+    fn baseline(&self) -> i32 {
+        4 + 6 * (self.index % ROWS) as i32
+    }
+
+    /// Whether `arrival` is a bus this row is watching for
+    fn serves(&self, arrival: &Arrival) -> bool {
+        arrival.route == self.id && self.direction.map_or(true, |d| d == arrival.direction)
+    }
+
+    /// The row as reported by the API
+    fn status(&self, content: RowContent) -> api::RouteStatus {
+        api::RouteStatus {
+            route: self.id,
+            direction: self.direction,
+            stop: self.stop,
+            minutes: match content {
+                RowContent::Minutes(minutes) => Some(minutes),
+                _ => None,
+            },
+            error: match content {
+                RowContent::Error(code) => Some(code),
+                _ => None,
+            },
         }
     }
 }
 
-enum DisplayCommand {
-    Off,
-    On,
-    Message(DisplayMessage),
+/// What a route's row shows after its name
+#[derive(Copy, Clone)]
+enum RowContent {
+    /// nothing fetched yet
+    Pending,
+    /// minutes until the next bus
+    Minutes(u8),
+    /// no upcoming bus predicted
+    NoBus,
+    /// short error code from the last fetch
+    Error(&'static str),
 }
 
-struct DisplayMessage {
-    pub route: Route,
-    pub value: u8,
+/// Routes of the active profile, in display order
+#[derive(Clone)]
+struct RouteTable {
+    /// profile name, shown when switching to it
+    name: &'static str,
+    routes: heapless::Vec<Route, MAX_ROUTES>,
 }
 
+enum DisplayCommand {
+    /// 0 turns the display off
+    Brightness(u8),
+    Row(usize, RowContent),
+    Routes(RouteTable),
+    /// false while the Wi-Fi link is down and being rejoined
+    Network(bool),
+    /// shown instead of the routes, empty to go back to them
+    Message(heapless::String<MESSAGE_SIZE>),
+}
+
+/// Upcoming arrivals fetched per route
+const ARRIVALS: usize = 2;
+
+/// How long a profile's name is shown after switching to it
+const BANNER_TIME: Duration = Duration::from_secs(2);
+
+/// How often `clock_task` sets the clock from the time server
+const CLOCK_SYNC: Duration = Duration::from_secs(6 * 3600);
+
+/// How long the display says the sign restarted after a panic
+const PANIC_NOTICE: Duration = Duration::from_secs(5);
+
+/// Longest a task waiting on something else goes without checking in with
+/// the watchdog
+const IDLE_CHECK: Duration = Duration::from_secs(10);
+
+/// How often the supervisor checks the Wi-Fi link
+const LINK_CHECK: Duration = Duration::from_secs(5);
+
+/// How often the supervisor scans for the signal strength
+const RSSI_CHECK: Duration = Duration::from_secs(300);
+
+/// Longest wait between attempts to rejoin
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Characters of a message that fit, two rows of 13
+const MESSAGE_SIZE: usize = 26;
+
+/// How often `mqtt_task` publishes what changed
+const MQTT_PUBLISH: Duration = Duration::from_secs(10);
+
+/// Seconds between MQTT pings
+const MQTT_KEEP_ALIVE: u16 = 60;
+
+/// How long new firmware has to confirm itself when `[ota]` is left out of
+/// its `sign.toml`
+const CONFIRM_WITHIN: u32 = 600;
+
+/// How long new firmware keeps checking in with the watchdog after its
+/// first good fetch before it's confirmed
+const TRIAL_SETTLE: Duration = Duration::from_secs(30);
+
 static CHANNEL: Channel<ThreadModeRawMutex, DisplayCommand, 8> = Channel::new();
 
+/// Set by `schedule_task` while the display is off and fetching stops
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+/// What `display_task` last set the brightness to
+static BRIGHTNESS: AtomicU8 = AtomicU8::new(0);
+
+/// Raised by `schedule_task` when fetching resumes
+static RESUME: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Raised by the console to fetch every route before its timer runs out
+static FETCH_NOW: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Raised by `profile_task` with the routes to fetch and show
+static ROUTE_TABLE: Signal<ThreadModeRawMutex, RouteTable> = Signal::new();
+
+/// Raised by the API to hold a display mode, `None` to follow the schedule
+static MODE_OVERRIDE: Signal<ThreadModeRawMutex, Option<Mode>> = Signal::new();
+
+/// Raised by every successful prediction fetch, which firmware on trial
+/// waits for
+static FETCHED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Rows and fetch result as `next_bus_task` last left them, for the API
+struct Snapshot {
+    routes: heapless::Vec<api::RouteStatus, MAX_ROUTES>,
+    /// with when it finished
+    last_fetch: Option<(api::FetchStatus, Instant)>,
+}
+
+static SNAPSHOT: Mutex<ThreadModeRawMutex, Snapshot> = Mutex::new(Snapshot {
+    routes: heapless::Vec::new(),
+    last_fetch: None,
+});
+
+/// Why `sleep` returned
+enum Wake {
+    Timeout,
+    FetchNow,
+    Routes(RouteTable),
+}
+
+/// Sleep for `duration`, or until the console asks for a fetch or the
+/// profile changes
+async fn sleep(duration: Duration) -> Wake {
+    match select3(Timer::after(duration), FETCH_NOW.wait(), ROUTE_TABLE.wait()).await {
+        Either3::First(()) => Wake::Timeout,
+        Either3::Second(()) => Wake::FetchNow,
+        Either3::Third(table) => Wake::Routes(table),
+    }
+}
+
 #[embassy_executor::task]
-async fn display_task(
-    mut gu: GalacticUnicorn<'static>,
-    mut graphics: UnicornGraphics<WIDTH, HEIGHT>,
-) -> ! {
-    let mut string = heapless::String::<16>::new();
-    let cyan = MonoTextStyle::new(&FONT_4X6, Rgb888::CYAN);
-    let yellow = MonoTextStyle::new(&FONT_4X6, Rgb888::YELLOW);
-    let white = MonoTextStyle::new(&FONT_4X6, Rgb888::WHITE);
+async fn display_task(mut graphics: UnicornGraphics<WIDTH, HEIGHT>) -> ! {
+    let mut routes = heapless::Vec::<Route, MAX_ROUTES>::new();
+    let mut pages = 1;
+    let mut page = 0;
+    let mut contents = [RowContent::Pending; MAX_ROUTES];
+    // profile name is showing until the next page turn
+    let mut banner = false;
+    // network is down, rows are kept but not drawn
+    let mut offline = false;
+    // also covers the rows while not empty
+    let mut message = heapless::String::<MESSAGE_SIZE>::new();
+
+    let mut next_page = Instant::now() + PAGE_TIME;
+    loop {
+        watchdog::beat(watchdog::Task::Display);
+        let command = if pages > 1 || banner {
+            match select(CHANNEL.receive(), Timer::at(next_page)).await {
+                Either::First(command) => command,
+                Either::Second(()) => {
+                    page = (page + 1) % pages;
+                    banner = false;
+                    next_page += PAGE_TIME;
+                    if !offline && message.is_empty() {
+                        draw_page(&mut graphics, &routes, &contents, page);
+                        crash::with_display(|gu| gu.set_pixels(&graphics));
+                    }
+                    continue;
+                }
+            }
+        } else {
+            // wake now and then to check in with the watchdog
+            match select(CHANNEL.receive(), Timer::after(IDLE_CHECK)).await {
+                Either::First(command) => command,
+                Either::Second(()) => continue,
+            }
+        };
+
+        match command {
+            DisplayCommand::Brightness(brightness) => {
+                crash::with_display(|gu| {
+                    gu.brightness = brightness;
+                    gu.set_pixels(&graphics);
+                });
+                BRIGHTNESS.store(brightness, Ordering::Relaxed);
+                metrics::DISPLAY_BRIGHTNESS.set(brightness as i32);
+            }
+            DisplayCommand::Row(index, content) => {
+                contents[index] = content;
+                if !banner && !offline && message.is_empty() && routes[index].page() == page {
+                    draw_page(&mut graphics, &routes, &contents, page);
+                    crash::with_display(|gu| gu.set_pixels(&graphics));
+                }
+            }
+            DisplayCommand::Routes(table) => {
+                // the first table is the one picked at boot, don't announce it
+                let first = routes.is_empty();
+                routes = table.routes;
+                pages = routes.len().div_ceil(ROWS).max(1);
+                contents = [RowContent::Pending; MAX_ROUTES];
+                if offline || !message.is_empty() {
+                    page = 0;
+                    next_page = Instant::now() + PAGE_TIME;
+                    continue;
+                }
+                if first {
+                    page = 0;
+                    next_page = Instant::now() + PAGE_TIME;
+                    draw_page(&mut graphics, &routes, &contents, page);
+                } else {
+                    // the next page turn wraps around to the first page
+                    page = pages - 1;
+                    banner = true;
+                    next_page = Instant::now() + BANNER_TIME;
+                    draw_banner(&mut graphics, table.name);
+                }
+                crash::with_display(|gu| gu.set_pixels(&graphics));
+            }
+            DisplayCommand::Network(up) => {
+                offline = !up;
+                banner = false;
+                if offline {
+                    draw_offline(&mut graphics);
+                } else if !message.is_empty() {
+                    draw_message(&mut graphics, &message);
+                } else {
+                    draw_page(&mut graphics, &routes, &contents, page);
+                }
+                crash::with_display(|gu| gu.set_pixels(&graphics));
+            }
+            DisplayCommand::Message(text) => {
+                message = text;
+                banner = false;
+                if offline {
+                    continue;
+                }
+                if message.is_empty() {
+                    draw_page(&mut graphics, &routes, &contents, page);
+                } else {
+                    draw_message(&mut graphics, &message);
+                }
+                crash::with_display(|gu| gu.set_pixels(&graphics));
+            }
+        }
+    }
+}
+
+/// Message from Home Assistant, wrapped onto both rows
+fn draw_message(graphics: &mut UnicornGraphics<WIDTH, HEIGHT>, message: &str) {
+    let value_color = MonoTextStyle::new(&FONT_4X6, CONFIG.colors.value);
+    Rectangle::new(Point::zero(), Size::new(WIDTH as u32, HEIGHT as u32))
+        .into_styled(PrimitiveStyle::with_fill(Rgb888::BLACK))
+        .draw(graphics)
+        .unwrap();
+    let split = message.len().min(MESSAGE_SIZE / 2);
+    let (first, second) = message.split_at(split);
+    Text::new(first, Point::new(0, 4), value_color)
+        .draw(graphics)
+        .unwrap();
+    Text::new(second, Point::new(0, 10), value_color)
+        .draw(graphics)
+        .unwrap();
+}
+
+/// Shown instead of the routes while the Wi-Fi link is down
+fn draw_offline(graphics: &mut UnicornGraphics<WIDTH, HEIGHT>) {
+    let label_color = MonoTextStyle::new(&FONT_4X6, CONFIG.colors.label);
+    let error_color = MonoTextStyle::new(&FONT_4X6, CONFIG.colors.error);
+    Rectangle::new(Point::zero(), Size::new(WIDTH as u32, HEIGHT as u32))
+        .into_styled(PrimitiveStyle::with_fill(Rgb888::BLACK))
+        .draw(graphics)
+        .unwrap();
+    Text::new("WIFI DOWN", Point::new(0, 4), error_color)
+        .draw(graphics)
+        .unwrap();
+    Text::new("RECONNECTING", Point::new(0, 10), label_color)
+        .draw(graphics)
+        .unwrap();
+}
+
+/// Announce a profile switch
+fn draw_banner(graphics: &mut UnicornGraphics<WIDTH, HEIGHT>, name: &str) {
+    let label_color = MonoTextStyle::new(&FONT_4X6, CONFIG.colors.label);
+    let route_color = MonoTextStyle::new(&FONT_4X6, CONFIG.colors.route);
+    Rectangle::new(Point::zero(), Size::new(WIDTH as u32, HEIGHT as u32))
+        .into_styled(PrimitiveStyle::with_fill(Rgb888::BLACK))
+        .draw(graphics)
+        .unwrap();
+    Text::new("PROFILE", Point::new(0, 4), label_color)
+        .draw(graphics)
+        .unwrap();
+    Text::new(name, Point::new(0, 10), route_color)
+        .draw(graphics)
+        .unwrap();
+}
+
+/// Redraw every row on `page`
+fn draw_page(
+    graphics: &mut UnicornGraphics<WIDTH, HEIGHT>,
+    routes: &[Route],
+    contents: &[RowContent],
+    page: usize,
+) {
+    let route_color = MonoTextStyle::new(&FONT_4X6, CONFIG.colors.route);
+    let label_color = MonoTextStyle::new(&FONT_4X6, CONFIG.colors.label);
+    let value_color = MonoTextStyle::new(&FONT_4X6, CONFIG.colors.value);
+    let error_color = MonoTextStyle::new(&FONT_4X6, CONFIG.colors.error);
     let black = PrimitiveStyle::with_fill(Rgb888::BLACK);
 
-    fn draw_label(
-        route: &str,
-        route_color: MonoTextStyle<Rgb888>,
-        baseline: i32,
-        label_color: MonoTextStyle<Rgb888>,
-        graphics: &mut UnicornGraphics<WIDTH, HEIGHT>,
-    ) {
-        Text::new(route, Point::new(0, baseline), route_color)
-            .draw(graphics)
-            .unwrap();
-        Text::new("BUS", Point::new(9, baseline), label_color)
-            .draw(graphics)
-            .unwrap();
-        Text::new("IN", Point::new(22, baseline), label_color)
+    Rectangle::new(Point::zero(), Size::new(WIDTH as u32, HEIGHT as u32))
+        .into_styled(black)
+        .draw(graphics)
+        .unwrap();
+
+    let mut string = heapless::String::<4>::new();
+    for route in routes.iter().filter(|route| route.page() == page) {
+        let baseline = route.baseline();
+        Text::new(route.id, Point::new(0, baseline), route_color)
             .draw(graphics)
             .unwrap();
+        // errors and "BUS" start after the route, which may be wider than
+        // two characters
+        let after_id = 4 * route.id.len().max(2) as i32 + 1;
+
+        let value = match contents[route.index] {
+            RowContent::Error(code) => {
+                Text::new(code, Point::new(after_id, baseline), error_color)
+                    .draw(graphics)
+                    .unwrap();
+                continue;
+            }
+            RowContent::Pending => None,
+            RowContent::NoBus => Some(("--", 32)),
+            RowContent::Minutes(value) => {
+                string.clear();
+                write!(&mut string, "{value}").unwrap();
+                let x = if value > 9 { 32 } else { 36 };
+                Some((string.as_str(), x))
+            }
+        };
+
+        // "BUS" and "IN" fit after a two character route. A longer one
+        // drops "BUS", and "IN" as well when that makes room for its tag
+        let label = match route.tag {
+            Some(tag) => Some(tag),
+            None if route.id.len() <= 2 => Some("BUS"),
+            None => None,
+        }
+        .filter(|label| after_id + 4 * label.len() as i32 <= 32);
+        let label_end = after_id + label.map_or(0, |label| 4 * label.len() as i32);
+        if let Some(label) = label {
+            Text::new(label, Point::new(after_id, baseline), label_color)
+                .draw(graphics)
+                .unwrap();
+        }
+        if label_end <= 22 {
+            Text::new("IN", Point::new(22, baseline), label_color)
+                .draw(graphics)
+                .unwrap();
+        }
+        if let Some((value, x)) = value {
+            Text::new(value, Point::new(x, baseline), value_color)
+                .draw(graphics)
+                .unwrap();
+        }
         Text::new("MIN", Point::new(42, baseline), label_color)
             .draw(graphics)
             .unwrap();
     }
+}
+
+/// When a route is next fetched and what it showed last
+#[derive(Copy, Clone)]
+struct RouteState {
+    next_fetch: Instant,
+    content: RowContent,
+    next_bus: Option<Instant>,
+    /// arrival predicted when `next_bus` became the next bus
+    first_prediction: Option<Instant>,
+}
+
+/// Fetch predictions for every route, each on its own schedule, and keep
+/// their rows up to date
+#[embassy_executor::task]
+async fn next_bus_task(
+    stack: &'static Stack<NetDriver<'static>>,
+    endpoint: &'static Endpoint,
+) -> ! {
+    let channel = CHANNEL.sender();
+    let idle = RouteState {
+        next_fetch: Instant::from_ticks(0),
+        content: RowContent::Pending,
+        next_bus: None,
+        first_prediction: None,
+    };
+    let mut states = [idle; MAX_ROUTES];
 
-    draw_label("87", cyan, 4, yellow, &mut graphics);
-    draw_label("88", cyan, 10, yellow, &mut graphics);
-    gu.set_pixels(&graphics);
+    let mut table = ROUTE_TABLE.wait().await;
+    channel.send(DisplayCommand::Routes(table.clone())).await;
 
     loop {
-        match CHANNEL.receive().await {
-            DisplayCommand::Off => {
-                gu.brightness = 0;
-                gu.set_pixels(&graphics);
+        watchdog::beat(watchdog::Task::NextBus);
+        if let Some(next) = ROUTE_TABLE.try_take() {
+            table = next;
+            states = [idle; MAX_ROUTES];
+            channel.send(DisplayCommand::Routes(table.clone())).await;
+        }
+        if SUSPENDED.load(Ordering::Relaxed) {
+            // wake now and then to check in with the watchdog
+            if let Either::First(()) = select(RESUME.wait(), Timer::after(IDLE_CHECK)).await {
+                for state in states.iter_mut() {
+                    state.next_fetch = Instant::from_ticks(0);
+                }
             }
-            DisplayCommand::On => {
-                gu.brightness = 100;
-                gu.set_pixels(&graphics);
+            continue;
+        }
+        let routes = &table.routes;
+        let current_time = rtc::now().await;
+
+        // a due route fetches its whole stop, so the other routes there
+        // are rescheduled along with it. Nothing is fetched while
+        // `link_supervisor_task` rejoins, rows keep counting down.
+        let now = Instant::from(current_time);
+        let online = wifi::ipv4_up(stack);
+        for index in 0..routes.len() {
+            if online && now >= states[index].next_fetch {
+                let stop = routes[index].stop;
+                fetch_stop(stack, endpoint, routes, &mut states, stop, now).await;
+                watchdog::beat(watchdog::Task::NextBus);
             }
-            DisplayCommand::Message(display_message) => {
-                let value = display_message.value;
-                let x = if value > 9 { 32 } else { 36 };
-                string.clear();
-                write!(&mut string, "{value}").unwrap();
+        }
 
-                match display_message.route {
-                    Route::EightySeven => {
-                        Rectangle::new(Point::new(31, 0), Size::new(9, 5))
-                            .into_styled(black)
-                            .draw(&mut graphics)
-                            .unwrap();
-
-                        Text::new(&string, Point::new(x, 4), white)
-                            .draw(&mut graphics)
-                            .unwrap();
-                        gu.set_pixels(&graphics);
-                    }
-                    Route::EightyEight => {
-                        Rectangle::new(Point::new(31, 6), Size::new(9, 5))
-                            .into_styled(black)
-                            .draw(&mut graphics)
-                            .unwrap();
-
-                        Text::new(&string, Point::new(x, 10), white)
-                            .draw(&mut graphics)
-                            .unwrap();
-                        gu.set_pixels(&graphics);
-                    }
+        for (route, state) in routes.iter().zip(states.iter_mut()) {
+            if let Some(next_bus) = state.next_bus {
+                let value = duration_as_minutes(next_bus.saturating_duration_since(now)) as u8;
+                info!("Route {}: time to next bus: {} min", route.id, value);
+                state.content = RowContent::Minutes(value);
+            }
+            channel
+                .send(DisplayCommand::Row(route.index, state.content))
+                .await;
+        }
+        SNAPSHOT.lock().await.routes = routes
+            .iter()
+            .zip(states.iter())
+            .map(|(route, state)| route.status(state.content))
+            .collect();
+
+        match sleep(Duration::from_secs(10)).await {
+            Wake::Timeout => {}
+            Wake::FetchNow => {
+                for state in states.iter_mut() {
+                    state.next_fetch = Instant::from_ticks(0);
                 }
             }
+            Wake::Routes(next) => {
+                table = next;
+                states = [idle; MAX_ROUTES];
+                channel.send(DisplayCommand::Routes(table.clone())).await;
+            }
         }
     }
 }
 
-#[embassy_executor::task(pool_size = 2)]
-async fn next_bus_task(
+/// Set the clock from the time server every few hours, which also picks
+/// up daylight saving changes
+#[embassy_executor::task]
+async fn clock_task(stack: &'static Stack<NetDriver<'static>>, endpoint: &'static Endpoint) -> ! {
+    loop {
+        Timer::after(CLOCK_SYNC).await;
+        if !wifi::ipv4_up(stack) {
+            continue;
+        }
+        match fetch_time(stack, endpoint).await {
+            Ok((now, utc_offset)) => rtc::sync(now, utc_offset).await,
+            Err(e) => warn!("Failed to sync the clock: {:?}", e),
+        }
+    }
+}
+
+/// Watch the Wi-Fi link and rejoin, with a fresh DHCP lease, when it
+/// drops, backing off between failed attempts, and keep the signal
+/// strength current while it's up. `control` is only locked for each
+/// leave, scan or join, never across the waits, so the console and the
+/// LED keep working while the link is down.
+#[embassy_executor::task]
+async fn link_supervisor_task(
     stack: &'static Stack<NetDriver<'static>>,
-    route: Route,
-    stop: &'static str,
+    control: &'static SharedControl,
+    config: &'static Config,
 ) -> ! {
-    let one_minute = Duration::from_secs(60);
     let channel = CHANNEL.sender();
-    let route_u8 = u8::from(route);
-    'fetch_again: loop {
-        let Some(arrival_times) = fetch_next_bus(stack, route_u8, stop).await else {
-            Timer::after(one_minute).await;
+    let mut rssi_checked = Instant::now();
+    loop {
+        Timer::after(LINK_CHECK).await;
+        if stack.is_link_up() && wifi::ipv4_up(stack) {
+            if rssi_checked.elapsed() >= RSSI_CHECK {
+                wifi::refresh_rssi(control).await;
+                rssi_checked = Instant::now();
+            }
             continue;
-        };
+        }
 
-        for arrival_time in arrival_times.iter() {
-            info!(
-                "Route {}: next bus arrives at: {:?}",
-                route_u8, arrival_time
-            );
+        let disconnects = wifi::record_disconnect().await;
+        warn!("Wi-Fi link lost, {} disconnects so far", disconnects);
+        channel.send(DisplayCommand::Network(false)).await;
+
+        let mut backoff = Duration::from_secs(5);
+        loop {
+            control.lock().await.leave().await;
+            if join_wifi(stack, control, config).await {
+                break;
+            }
+            info!("Rejoin failed, retrying in {}s", backoff.as_secs());
+            Timer::after(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+        info!("Wi-Fi link restored");
+        // the join measured it
+        rssi_checked = Instant::now();
+        channel.send(DisplayCommand::Network(true)).await;
+    }
+}
+
+/// Profile covering `now`, the first listed wins, and the seconds until
+/// that may change
+fn scheduled_profile(now: &Timestamp) -> (Option<usize>, u32) {
+    let mut active = None;
+    let mut wait = 24 * 60 * 60;
+    for (index, profile) in CONFIG.profiles.iter().enumerate() {
+        let (inside, until) =
+            CONFIG
+                .schedule
+                .between(profile.from, profile.to, now, rtc::utc_offset());
+        if inside && active.is_none() {
+            active = Some(index);
+        }
+        wait = wait.min(until);
+    }
+    (active, wait)
+}
+
+/// Routes of `profile`, or the stored routes for `None`
+fn route_table(config: &'static Config, profile: Option<usize>) -> RouteTable {
+    let stop = config.bus_stop.as_str();
+    match profile {
+        Some(profile) => {
+            let profile = &CONFIG.profiles[profile];
+            RouteTable {
+                name: profile.name,
+                routes: profile
+                    .routes
+                    .iter()
+                    .enumerate()
+                    .map(|(index, route)| Route {
+                        index,
+                        id: route.id,
+                        direction: route.direction,
+                        stop: route.stop.unwrap_or(stop),
+                        tag: route.tag,
+                    })
+                    .collect(),
+            }
+        }
+        None => RouteTable {
+            name: "DEFAULT",
+            routes: config
+                .routes()
+                .enumerate()
+                .map(|(index, spec)| Route {
+                    index,
+                    id: spec.id,
+                    direction: spec.direction,
+                    stop: spec.stop.unwrap_or(stop),
+                    tag: spec.tag,
+                })
+                .collect(),
+        },
+    }
+}
+
+/// Switch profiles as their windows start and end. Pressing `button` steps
+/// through the default routes and each profile, until the schedule next
+/// switches on its own.
+#[embassy_executor::task]
+async fn profile_task(config: &'static Config, mut button: Input<'static>) -> ! {
+    let mut scheduled = None;
+    let mut shown = None;
+    loop {
+        let now = rtc::now().await;
+        let (profile, wait) = scheduled_profile(&now);
+        if scheduled != Some(profile) {
+            scheduled = Some(profile);
+            shown = profile;
+            let table = route_table(config, shown);
+            info!("Profile: {} for {} s", table.name, wait);
+            ROUTE_TABLE.signal(table);
+        }
+
+        if let Either::Second(()) = select(
+            Timer::after_secs(wait as u64),
+            button.wait_for_falling_edge(),
+        )
+        .await
+        {
+            shown = match shown {
+                None if !CONFIG.profiles.is_empty() => Some(0),
+                Some(index) if index + 1 < CONFIG.profiles.len() => Some(index + 1),
+                _ => None,
+            };
+            let table = route_table(config, shown);
+            info!("Profile: {} chosen with the button", table.name);
+            ROUTE_TABLE.signal(table);
+            // let the switch stop bouncing
+            Timer::after_millis(200).await;
+        }
+    }
+}
+
+/// Follow `CONFIG.schedule`, setting the display brightness and suspending
+/// fetches while the display is off. Wakes at each window edge and at
+/// midnight, when the kind of day may change. A mode set from the API holds
+/// until the next edge.
+#[embassy_executor::task]
+async fn schedule_task() -> ! {
+    let channel = CHANNEL.sender();
+    let mut current = None;
+    let mut held = None;
+    loop {
+        let now = rtc::now().await;
+        let (scheduled, wait) = CONFIG.schedule.mode_at(&now, rtc::utc_offset());
+        let mode = held.unwrap_or(scheduled);
+        if current != Some(mode) {
+            info!("Schedule: display {:?} for {} s", mode, wait);
+            SUSPENDED.store(mode == Mode::Off, Ordering::Relaxed);
+            if mode != Mode::Off {
+                RESUME.signal(());
+            }
+            channel
+                .send(DisplayCommand::Brightness(CONFIG.schedule.brightness(mode)))
+                .await;
+            current = Some(mode);
+        }
+        match select(Timer::after_secs(wait as u64), MODE_OVERRIDE.wait()).await {
+            Either::First(()) => held = None,
+            Either::Second(mode) => held = mode,
+        }
+    }
+}
 
-            let next_bus_time = Instant::from(arrival_time);
-            let now = Instant::from(rtc::now().await);
-            let delta = next_bus_time.saturating_duration_since(now);
-            if duration_as_minutes(delta) < 1 {
-                info!("Skipping to next bus time");
+/// Fetch the next bus for every route at `stop` in one request, and
+/// schedule each route's following fetch for half way to its arrival
+async fn fetch_stop(
+    stack: &'static Stack<NetDriver<'static>>,
+    endpoint: &Endpoint,
+    routes: &[Route],
+    states: &mut [RouteState],
+    stop: &'static str,
+    now: Instant,
+) {
+    let one_minute = Duration::from_secs(60);
+    let at_stop = || routes.iter().filter(move |route| route.stop == stop);
+
+    // no longer than `Config::routes`
+    let mut ids = heapless::String::<192>::new();
+    for route in at_stop() {
+        if !ids.split(',').any(|id| id == route.id) {
+            if !ids.is_empty() {
+                ids.push(',').ok();
+            }
+            ids.push_str(route.id).ok();
+        }
+    }
+
+    // every route gets its own ARRIVALS, so a frequent one can't crowd the
+    // others out of the pages
+    let mut wanted = [0; MAX_ROUTES];
+    for route in at_stop() {
+        wanted[route.index] = ARRIVALS;
+    }
+    let started = Instant::now();
+    let arrivals = fetch_arrivals::<{ ARRIVALS * MAX_ROUTES }>(
+        stack,
+        endpoint,
+        stop,
+        ids.as_str(),
+        |arrival| {
+            let mut kept = false;
+            for route in at_stop().filter(|route| route.serves(arrival)) {
+                if wanted[route.index] > 0 {
+                    wanted[route.index] -= 1;
+                    kept = true;
+                }
+            }
+            match (kept, wanted.iter().all(|&count| count == 0)) {
+                (false, _) => Take::Skip,
+                (true, false) => Take::Keep,
+                (true, true) => Take::Last,
+            }
+        },
+    )
+    .await;
+    metrics::FETCHES.inc();
+    metrics::FETCH_DURATION.observe(started.elapsed().as_millis() as u32);
+
+    if let Err(err) = &arrivals {
+        error!("Stop {}: fetch failed: {:?}", stop, err);
+        metrics::FETCH_FAILURES.inc(err.label());
+    }
+    let fetch = api::FetchStatus {
+        stop,
+        ok: arrivals.is_ok(),
+        error: arrivals.as_ref().err().map(|err| err.short_code()),
+        seconds_ago: 0,
+    };
+    SNAPSHOT.lock().await.last_fetch = Some((fetch, Instant::now()));
+    if arrivals.is_ok() {
+        FETCHED.signal(());
+    }
+
+    for route in at_stop() {
+        let state = &mut states[route.index];
+        let arrivals = match &arrivals {
+            Ok(arrivals) => arrivals,
+            Err(err) => {
+                state.content = RowContent::Error(err.short_code());
+                state.next_bus = None;
+                state.next_fetch = now + one_minute;
                 continue;
             }
-            let wait_time = core::cmp::max(delta / 2, one_minute);
-            let next_fetch_time = now + wait_time;
-            info!(
-                "Route {}: waiting {} min to fetch again",
-                route_u8,
-                duration_as_minutes(wait_time)
-            );
+        };
+
+        // skip buses arriving within the minute
+        let previous = state.next_bus;
+        state.next_bus = arrivals
+            .iter()
+            .filter(|arrival| route.serves(arrival))
+            .map(|arrival| Instant::from(&arrival.time))
+            .find(|arrival| duration_as_minutes(arrival.saturating_duration_since(now)) >= 1);
+
+        // once a due bus leaves the row, its last prediction stands in
+        // for when it actually came
+        let moved_on = match previous {
+            Some(previous) => state
+                .next_bus
+                .map_or(true, |next| next > previous + one_minute),
+            None => true,
+        };
+        if moved_on {
+            if let (Some(previous), Some(first)) = (previous, state.first_prediction) {
+                if previous <= now + one_minute * 2 {
+                    let error = previous.max(first) - previous.min(first);
+                    metrics::PREDICTION_ERROR.observe(error.as_millis() as u32);
+                }
+            }
+            state.first_prediction = state.next_bus;
+        }
+
+        match state.next_bus {
+            Some(next_bus) => {
+                info!("Route {}: next bus arrives at: {:?}", route.id, next_bus);
+                let wait_time =
+                    core::cmp::max(next_bus.saturating_duration_since(now) / 2, one_minute);
+                info!(
+                    "Route {}: waiting {} min to fetch again",
+                    route.id,
+                    duration_as_minutes(wait_time)
+                );
+                state.next_fetch = now + wait_time;
+            }
+            None => {
+                info!("Route {}: no upcoming buses at {}", route.id, stop);
+                state.content = RowContent::NoBus;
+                state.next_fetch = now + one_minute;
+            }
+        }
+    }
+}
+
+/// The sign as seen from the USB console
+struct Device {
+    stack: &'static Stack<NetDriver<'static>>,
+    control: &'static SharedControl,
+    /// last saved configuration, applied on the next boot
+    config: Config,
+}
 
-            loop {
-                let current_time = rtc::now().await;
+impl console::Sign for Device {
+    type Config = Config;
 
-                // sleep for 12 hours after 6 pm with display off
-                if current_time.hour >= 18 {
-                    info!("Turning display off");
-                    channel.send(DisplayCommand::Off).await;
+    async fn status(&mut self, out: &mut Output) {
+        let uptime = Instant::now().as_secs();
+        writeln!(out, "uptime {}h {}m", uptime / 3600, uptime / 60 % 60).ok();
+        let link = if self.stack.is_link_up() {
+            "up"
+        } else {
+            "down"
+        };
+        let status = wifi::link_status().await;
+        writeln!(
+            out,
+            "wifi {} link {} disconnects {}",
+            status.ssid, link, status.disconnects
+        )
+        .ok();
+        match self.stack.config_v4() {
+            Some(net_config) => writeln!(out, "address {}", net_config.address).ok(),
+            None => writeln!(out, "no address").ok(),
+        };
+        if let Some(net_config) = self.stack.config_v6() {
+            writeln!(out, "address {}", net_config.address).ok();
+        }
+        writeln!(
+            out,
+            "stop {} routes {}",
+            self.config.bus_stop, self.config.routes
+        )
+        .ok();
+        if let Some(panic) = crash::last().await {
+            writeln!(
+                out,
+                "restarted after {} {:04X} at {}",
+                panic.title(),
+                panic.code(),
+                panic.location()
+            )
+            .ok();
+        }
+        if let Some(task) = watchdog::last_missed() {
+            writeln!(
+                out,
+                "restarted by the watchdog, {} task stalled",
+                task.name()
+            )
+            .ok();
+        }
+    }
 
-                    info!("Sleeping for 12 hours");
-                    Timer::after_secs(12 * 60 * 60).await;
+    async fn config(&mut self) -> Config {
+        self.config.clone()
+    }
+
+    async fn save_config(&mut self, config: &Config) -> Result<(), SetError> {
+        config::save(config).await.map_err(|e| {
+            error!("Failed to save config: {:?}", e);
+            SetError::from(e)
+        })?;
+        self.config = config.clone();
+        Ok(())
+    }
 
-                    info!("Turning display on");
-                    channel.send(DisplayCommand::On).await;
-                    continue 'fetch_again;
+    async fn scan_wifi(&mut self, out: &mut Output) {
+        let mut control = self.control.lock().await;
+        let mut scanner = control.scan(Default::default()).await;
+        while let Some(bss) = scanner.next().await {
+            let ssid = core::str::from_utf8(&bss.ssid[..bss.ssid_len as usize]).unwrap_or("?");
+            writeln!(out, "{:>4} dBm  {}", bss.rssi, ssid).ok();
+        }
+    }
+
+    fn fetch_now(&mut self) {
+        FETCH_NOW.signal(());
+    }
+
+    async fn time(&mut self, out: &mut Output) {
+        match rtc::try_now().await {
+            Some(now) => writeln!(
+                out,
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                now.year, now.month, now.day, now.hour, now.minute, now.second
+            )
+            .ok(),
+            None => writeln!(out, "clock not set yet").ok(),
+        };
+    }
+
+    fn reboot(&mut self) -> ! {
+        cortex_m::peripheral::SCB::sys_reset()
+    }
+
+    async fn syslog(&mut self, out: &mut Output) {
+        match syslog::target().await {
+            Some(target) => writeln!(out, "syslog to {} level {}", target, syslog::level()).ok(),
+            None => writeln!(out, "syslog off").ok(),
+        };
+    }
+
+    async fn set_syslog(&mut self, target: Option<(&str, u16)>) -> bool {
+        let target = match target {
+            Some((host, port)) => match syslog::Target::new(host, port) {
+                Some(target) => Some(target),
+                None => return false,
+            },
+            None => None,
+        };
+        syslog::set_target(target).await;
+        true
+    }
+
+    fn set_syslog_level(&mut self, level: LevelFilter) {
+        syslog::set_level(level);
+    }
+}
+
+impl api::Sign for Device {
+    async fn status(&mut self) -> api::Status {
+        let link = wifi::link_status().await;
+        let local = rtc::try_now().await.map(|now| {
+            let mut local = heapless::String::new();
+            write!(
+                local,
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                now.year, now.month, now.day, now.hour, now.minute, now.second
+            )
+            .ok();
+            local
+        });
+        let snapshot = SNAPSHOT.lock().await;
+        api::Status {
+            uptime: Instant::now().as_secs(),
+            ssid: link.ssid,
+            rssi: link.rssi,
+            time: api::TimeStatus {
+                synced: local.is_some(),
+                local,
+                utc_offset: rtc::utc_offset(),
+            },
+            last_fetch: snapshot.last_fetch.map(|(fetch, at)| api::FetchStatus {
+                seconds_ago: at.elapsed().as_secs(),
+                ..fetch
+            }),
+            routes: snapshot.routes.clone(),
+            last_panic: crash::last().await.map(|panic| {
+                let mut location = heapless::String::new();
+                write!(location, "{}", panic.location()).ok();
+                api::PanicStatus {
+                    location,
+                    message: panic.message,
+                    uptime: panic.uptime,
                 }
+            }),
+        }
+    }
+
+    async fn set_brightness(&mut self, brightness: u8) {
+        CHANNEL.send(DisplayCommand::Brightness(brightness)).await;
+    }
+
+    fn set_mode(&mut self, mode: Option<Mode>) {
+        MODE_OVERRIDE.signal(mode);
+    }
+
+    fn refresh(&mut self) {
+        FETCH_NOW.signal(());
+    }
+
+    fn metrics(&mut self, out: &mut impl core::fmt::Write) -> core::fmt::Result {
+        metrics::render(out)
+    }
+
+    fn firmware_capacity(&self) -> Option<u32> {
+        CONFIG.ota.as_ref().map(|_| ota::capacity())
+    }
+
+    async fn write_firmware(&mut self, offset: u32, block: &[u8]) -> Result<(), api::UpdateError> {
+        ota::write(offset, block).await.map_err(update_error)
+    }
+
+    async fn finish_firmware(
+        &mut self,
+        size: u32,
+        signature: &[u8; 64],
+    ) -> Result<(), api::UpdateError> {
+        ota::finish(size, signature).await.map_err(update_error)
+    }
+}
+
+fn update_error(e: FirmwareUpdaterError) -> api::UpdateError {
+    error!("Firmware update failed: {:?}", e);
+    match e {
+        FirmwareUpdaterError::BadState => api::UpdateError::Busy,
+        FirmwareUpdaterError::Signature(_) => api::UpdateError::BadSignature,
+        FirmwareUpdaterError::Flash(_) => api::UpdateError::Flash,
+    }
+}
+
+/// Keep firmware on trial once the sign is healthy, or restart within
+/// `deadline` so the bootloader rolls back to the previous image. Healthy
+/// is a successful prediction fetch followed by `TRIAL_SETTLE` of every
+/// task checking in with the watchdog.
+#[embassy_executor::task]
+async fn trial_task(deadline: Duration) {
+    let healthy = async {
+        FETCHED.wait().await;
+        watchdog::settled(TRIAL_SETTLE).await;
+    };
+    match with_timeout(deadline, healthy).await {
+        Ok(()) => ota::confirm().await,
+        Err(_) => {
+            error!("New firmware not healthy in time, rolling back");
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+    }
+}
 
-                let now = Instant::from(current_time);
-                if now > next_fetch_time {
-                    continue 'fetch_again;
+/// Keep a session with the MQTT broker for Home Assistant, reconnecting
+/// with backoff when it drops
+#[embassy_executor::task]
+async fn mqtt_task(
+    stack: &'static Stack<NetDriver<'static>>,
+    config: &'static Config,
+    settings: &'static MqttSettings,
+) -> ! {
+    let availability = mqtt::topic(settings.topic, format_args!("availability"));
+    let options = mqtt::ConnectOptions {
+        client_id: settings.client_id,
+        username: settings.username,
+        password: settings.password,
+        keep_alive: MQTT_KEEP_ALIVE,
+        will: Some((&availability, b"offline")),
+    };
+    // every row any profile can show gets a sensor
+    let rows = CONFIG
+        .profiles
+        .iter()
+        .map(|profile| profile.routes.len())
+        .fold(config.routes().count(), usize::max);
+
+    let mut rx_buffer = [0; mqtt::BUFFER_SIZE];
+    let mut tx_buffer = [0; mqtt::BUFFER_SIZE];
+    let mut backoff = Duration::from_secs(5);
+    loop {
+        if let Ok(mut socket) = open_socket(
+            stack,
+            settings.host,
+            settings.port,
+            &mut rx_buffer,
+            &mut tx_buffer,
+        )
+        .await
+        {
+            socket.set_timeout(Some(Duration::from_secs(2 * MQTT_KEEP_ALIVE as u64)));
+            let mut client = mqtt::Client::new(socket);
+            match client.connect(&options).await {
+                Ok(()) => {
+                    info!("MQTT connected to {}", settings.host);
+                    backoff = Duration::from_secs(5);
+                    if let Err(e) = mqtt_session(&mut client, settings, rows).await {
+                        warn!("MQTT session ended: {:?}", e);
+                    }
                 }
+                Err(e) => warn!("MQTT connect failed: {:?}", e),
+            }
+        }
+        Timer::after(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
 
-                let delta = next_bus_time.saturating_duration_since(now);
-                let value = duration_as_minutes(delta) as u8;
+/// Announce the sign to Home Assistant, then publish changes and act on
+/// commands until the connection fails
+async fn mqtt_session(
+    client: &mut mqtt::Client<TcpSocket<'_>>,
+    settings: &MqttSettings,
+    rows: usize,
+) -> Result<(), mqtt::Error<embassy_net::tcp::Error>> {
+    let prefix = settings.topic;
+    let discovery = mqtt::Discovery {
+        discovery_prefix: settings.discovery_prefix,
+        prefix,
+        node_id: settings.client_id,
+    };
+    let (topic, config) = discovery.light().ok_or(mqtt::Error::TooLarge)?;
+    client.publish(&topic, config.as_bytes(), true).await?;
+    let (topic, config) = discovery
+        .message(MESSAGE_SIZE)
+        .ok_or(mqtt::Error::TooLarge)?;
+    client.publish(&topic, config.as_bytes(), true).await?;
+    for row in 1..=rows {
+        let (topic, config) = discovery.row(row).ok_or(mqtt::Error::TooLarge)?;
+        client.publish(&topic, config.as_bytes(), true).await?;
+    }
+    for suffix in ["set", "brightness/set", "message/set"] {
+        client
+            .subscribe(&mqtt::topic(prefix, format_args!("{suffix}")))
+            .await?;
+    }
+    let availability = mqtt::topic(prefix, format_args!("availability"));
+    client.publish(&availability, b"online", true).await?;
 
-                info!("Route {}: time to next bus: {} min", route_u8, value);
+    // as last published, everything goes out on the first round
+    let mut fresh = true;
+    let mut power = false;
+    let mut brightness = 0;
+    let mut published = [None; MAX_ROUTES];
+    let mut message = heapless::String::<MESSAGE_SIZE>::new();
+    let mut message_changed = true;
 
-                channel
-                    .send(DisplayCommand::Message(DisplayMessage { route, value }))
-                    .await;
+    let mut next_ping = Instant::now() + Duration::from_secs(MQTT_KEEP_ALIVE as u64);
+    loop {
+        let on = BRIGHTNESS.load(Ordering::Relaxed) > 0 && !SUSPENDED.load(Ordering::Relaxed);
+        if fresh || on != power {
+            power = on;
+            let state: &[u8] = if on { b"ON" } else { b"OFF" };
+            let topic = mqtt::topic(prefix, format_args!("state"));
+            client.publish(&topic, state, true).await?;
+        }
+        let level = BRIGHTNESS.load(Ordering::Relaxed);
+        if fresh || level != brightness {
+            brightness = level;
+            let mut payload = heapless::String::<4>::new();
+            write!(payload, "{}", level).ok();
+            let topic = mqtt::topic(prefix, format_args!("brightness"));
+            client.publish(&topic, payload.as_bytes(), true).await?;
+        }
+        if message_changed {
+            message_changed = false;
+            let topic = mqtt::topic(prefix, format_args!("message"));
+            client.publish(&topic, message.as_bytes(), true).await?;
+        }
+        let routes = SNAPSHOT.lock().await.routes.clone();
+        for (row, last) in published.iter_mut().enumerate().take(rows) {
+            let current = routes.get(row).copied();
+            if !fresh && current == *last {
+                continue;
+            }
+            *last = current;
+            let mut payload = [0; 160];
+            let len = match &current {
+                Some(status) => serde_json_core::to_slice(status, &mut payload).unwrap_or(0),
+                None => {
+                    let empty = b"{\"minutes\":null}";
+                    payload[..empty.len()].copy_from_slice(empty);
+                    empty.len()
+                }
+            };
+            let topic = mqtt::topic(prefix, format_args!("row/{}", row + 1));
+            client.publish(&topic, &payload[..len], true).await?;
+        }
+        fresh = false;
 
-                Timer::after_secs(10).await;
+        match select3(
+            client.receive(),
+            Timer::at(next_ping),
+            Timer::after(MQTT_PUBLISH),
+        )
+        .await
+        {
+            Either3::First(packet) => {
+                let mqtt::Packet::Publish { topic, payload } = packet? else {
+                    continue;
+                };
+                match mqtt::Command::parse(prefix, topic, payload) {
+                    Some(mqtt::Command::Power(true)) => {
+                        // Home Assistant sends ON before a new brightness
+                        if !on {
+                            MODE_OVERRIDE.signal(Some(Mode::On));
+                        }
+                    }
+                    Some(mqtt::Command::Power(false)) => MODE_OVERRIDE.signal(Some(Mode::Off)),
+                    Some(mqtt::Command::Brightness(level)) => {
+                        CHANNEL.send(DisplayCommand::Brightness(level)).await
+                    }
+                    Some(mqtt::Command::Message(text)) => {
+                        message.clear();
+                        for c in text.chars().filter(char::is_ascii).take(MESSAGE_SIZE) {
+                            message.push(c).ok();
+                        }
+                        message_changed = true;
+                        CHANNEL.send(DisplayCommand::Message(message.clone())).await;
+                    }
+                    None => {}
+                }
             }
+            Either3::Second(()) => {
+                client.ping().await?;
+                next_ping += Duration::from_secs(MQTT_KEEP_ALIVE as u64);
+            }
+            Either3::Third(()) => {}
+        }
+    }
+}
+
+/// Serve the HTTP API, one connection at a time
+#[embassy_executor::task]
+async fn api_task(stack: &'static Stack<NetDriver<'static>>, mut device: Device) -> ! {
+    let mut rx_buffer = [0; api::REQUEST_SIZE];
+    let mut tx_buffer = [0; api::RESPONSE_SIZE + 128];
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(e) = socket.accept(api::PORT).await {
+            warn!("Failed to accept API connection: {:?}", e);
+            continue;
+        }
+        if let Err(e) = api::serve(&mut socket, &mut device).await {
+            info!("API connection failed: {:?}", e);
+        }
+        socket.close();
+        socket.flush().await.ok();
+        if ota::update_ready() {
+            info!("Restarting into the new firmware");
+            Timer::after(Duration::from_secs(1)).await;
+            cortex_m::peripheral::SCB::sys_reset();
         }
     }
 }
 
+#[embassy_executor::task]
+async fn watchdog_task(watchdog: Watchdog) -> ! {
+    watchdog::run(watchdog).await
+}
+
+#[embassy_executor::task]
+async fn syslog_task(stack: &'static Stack<NetDriver<'static>>) -> ! {
+    syslog::run(stack).await
+}
+
+#[embassy_executor::task]
+async fn console_task(mut serial: UsbSerial, mut device: Device) -> ! {
+    loop {
+        serial.wait_connection().await;
+        info!("Console connected");
+        if let Err(e) = console::run(&mut serial, &mut device).await {
+            info!("Console disconnected: {:?}", e);
+        }
+    }
+}
+
+/// Say where the panic before the restart happened, with the code the
+/// error screen showed
+fn draw_panic(graphics: &mut UnicornGraphics<WIDTH, HEIGHT>, panic: &crash::Panic) {
+    let error_color = MonoTextStyle::new(&FONT_4X6, CONFIG.colors.error);
+    let value_color = MonoTextStyle::new(&FONT_4X6, CONFIG.colors.value);
+    let mut title = heapless::String::<16>::new();
+    write!(title, "{} {:04X}", panic.title(), panic.code()).ok();
+    let mut location = heapless::String::<80>::new();
+    write!(location, "{}", panic.short_location()).ok();
+    Text::new(&title, Point::new(0, 4), error_color)
+        .draw(graphics)
+        .unwrap();
+    Text::new(&location, Point::new(0, 10), value_color)
+        .draw(graphics)
+        .unwrap();
+}
+
+/// Show the Wi-Fi state, or how to reach the setup portal, before the
+/// display task runs
+fn draw_wifi(graphics: &mut UnicornGraphics<WIDTH, HEIGHT>, title: &str, detail: &str) {
+    let label_color = MonoTextStyle::new(&FONT_4X6, CONFIG.colors.label);
+    let route_color = MonoTextStyle::new(&FONT_4X6, CONFIG.colors.route);
+    *graphics = UnicornGraphics::new();
+    Text::new(title, Point::new(0, 4), label_color)
+        .draw(graphics)
+        .unwrap();
+    Text::new(detail, Point::new(0, 10), route_color)
+        .draw(graphics)
+        .unwrap();
+    crash::with_display(|gu| gu.set_pixels(graphics));
+}
+
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
 
-    start_usb_logger(spawner, p.USB);
+    let serial = start_usb(spawner, p.USB);
+    syslog::init().await;
+    crash::init().await;
+
+    let mut watchdog = Watchdog::new(p.WATCHDOG);
+    watchdog::init(&mut watchdog);
+    spawner.spawn(watchdog_task(watchdog)).unwrap();
 
     let display_pins = UnicornDisplayPins {
         column_clock: p.PIN_13,
@@ -241,16 +1393,43 @@ async fn main(spawner: Spawner) {
         sleep: Input::new(p.PIN_27, Pull::Up),
     };
 
+    static FLASH: StaticCell<SharedFlash> = StaticCell::new();
+    let flash = &*FLASH.init(blocking_mutex::Mutex::new(RefCell::new(
+        Flash::new_blocking(p.FLASH),
+    )));
+    let config = config::init(flash).await;
+    let on_trial = ota::init(flash).await;
+    static STORED_CONFIG: StaticCell<Config> = StaticCell::new();
+    let config = &*STORED_CONFIG.init(config);
+
     let mut gu = GalacticUnicorn::new(p.PIO0, display_pins, sensor_pins, p.ADC, p.DMA_CH0);
 
     if button_pins.switch_a.is_low() {
         universe::run(gu).await;
         return;
     }
-
-    let graphics = UnicornGraphics::<WIDTH, HEIGHT>::new();
+    // shared with the panic screen from here on
     gu.brightness = 100;
-    gu.set_pixels(&graphics);
+    crash::keep_display(gu);
+
+    if on_trial {
+        let seconds = CONFIG
+            .ota
+            .as_ref()
+            .map_or(CONFIRM_WITHIN, |ota| ota.confirm_within);
+        spawner
+            .spawn(trial_task(Duration::from_secs(seconds as u64)))
+            .unwrap();
+    }
+
+    let mut graphics = UnicornGraphics::<WIDTH, HEIGHT>::new();
+    if let Some(panic) = crash::last().await {
+        draw_panic(&mut graphics, &panic);
+        crash::with_display(|gu| gu.set_pixels(&graphics));
+        Timer::after(PANIC_NOTICE).await;
+        graphics = UnicornGraphics::new();
+    }
+    crash::with_display(|gu| gu.set_pixels(&graphics));
 
     let wifi_pins = WiFiPins {
         pin_23: p.PIN_23,
@@ -260,34 +1439,114 @@ async fn main(spawner: Spawner) {
         pio_1: p.PIO1,
         dma_ch1: p.DMA_CH1,
     };
-    let (stack, mut control) =
-        connect_to_wifi(spawner, env!("WIFI_SSID"), env!("WIFI_PASSWORD"), wifi_pins).await;
+    let (stack, control) = start_wifi(spawner, wifi_pins).await;
+    static CONTROL: StaticCell<SharedControl> = StaticCell::new();
+    let control = &*CONTROL.init(Mutex::new(control));
+    spawner.spawn(syslog_task(stack)).unwrap();
+
+    spawner
+        .spawn(console_task(
+            serial,
+            Device {
+                stack,
+                control,
+                config: config.clone(),
+            },
+        ))
+        .unwrap();
+
+    // The portal only opens without any known network, or with button D
+    // held at boot or pressed while joining is retried, so a router that
+    // comes back after the sign does isn't missed
+    let mut setup_button = button_pins.switch_d;
+    let mut joined = false;
+    if setup_button.is_high() && wifi::has_networks(config) {
+        let mut backoff = Duration::from_secs(5);
+        joined = loop {
+            if join_wifi(stack, control, config).await {
+                break true;
+            }
+            draw_wifi(&mut graphics, "WIFI RETRY", "D: SETUP");
+            info!(
+                "No known network joined, retrying in {}s",
+                backoff.as_secs()
+            );
+            let pressed = select(Timer::after(backoff), setup_button.wait_for_low()).await;
+            if let Either::Second(()) = pressed {
+                break false;
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        };
+    }
+    if !joined {
+        draw_wifi(&mut graphics, "WIFI SETUP", SETUP_SSID);
+        portal::start_ap(stack, &mut *control.lock().await).await;
+        portal::run_setup(stack, config).await;
+    }
+
+    // Proxy mode looks for mbta-proxy.py first, so a new lease on its host
+    // needs no reconfiguring, and falls back to the stored address. Direct
+    // mode never looks, so nothing on the network can talk a sign out of
+    // pinned HTTPS.
+    let mut endpoint = Endpoint::new(config);
+    if CONFIG.mbta.mode == ApiMode::Proxy {
+        let mut backoff = Duration::from_secs(5);
+        loop {
+            if let Some(service) = mdns::discover_proxy(stack).await {
+                endpoint.upstream = Upstream::discovered(service, endpoint.upstream.tls_pins);
+                break;
+            }
+            if !endpoint.upstream.host.is_empty() {
+                info!("No proxy discovered, using {}", endpoint.upstream);
+                break;
+            }
+            info!("No proxy discovered, retrying in {}s", backoff.as_secs());
+            Timer::after(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+    static ENDPOINT: StaticCell<Endpoint> = StaticCell::new();
+    let endpoint = &*ENDPOINT.init(endpoint);
 
     let mut wait = Duration::from_secs(2);
-    let now = loop {
-        if let Some(now) = fetch_time(stack).await {
-            break now;
+    let (now, utc_offset) = loop {
+        if let Ok(time) = fetch_time(stack, endpoint).await {
+            break time;
         }
         Timer::after(wait).await;
         wait *= 2;
     };
 
-    rtc::init(p.RTC, now).await;
-
-    spawner.spawn(display_task(gu, graphics)).unwrap();
+    rtc::init(p.RTC, now, utc_offset).await;
 
+    spawner.spawn(display_task(graphics)).unwrap();
+    spawner.spawn(schedule_task()).unwrap();
     spawner
-        .spawn(next_bus_task(stack, Route::EightySeven, env!("BUS_STOP")))
+        .spawn(profile_task(config, button_pins.switch_b))
         .unwrap();
-
+    spawner.spawn(next_bus_task(stack, endpoint)).unwrap();
+    spawner.spawn(clock_task(stack, endpoint)).unwrap();
+    spawner
+        .spawn(link_supervisor_task(stack, control, config))
+        .unwrap();
+    if let Some(settings) = &CONFIG.mqtt {
+        spawner.spawn(mqtt_task(stack, config, settings)).unwrap();
+    }
     spawner
-        .spawn(next_bus_task(stack, Route::EightyEight, env!("BUS_STOP")))
+        .spawn(api_task(
+            stack,
+            Device {
+                stack,
+                control,
+                config: config.clone(),
+            },
+        ))
         .unwrap();
 
     loop {
-        control.gpio_set(0, true).await;
+        control.lock().await.gpio_set(0, true).await;
         Timer::after_secs(1).await;
-        control.gpio_set(0, false).await;
+        control.lock().await.gpio_set(0, false).await;
         Timer::after_secs(1).await;
     }
 }
@@ -0,0 +1,299 @@
+use core::fmt::Write;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{Ipv4Address, Stack};
+use embassy_time::{with_timeout, Duration, Instant};
+use log::*;
+
+use crate::sign::CONFIG;
+
+const MDNS_ADDRESS: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+/// DNS-SD service type advertised by `mbta-proxy.py`
+pub const PROXY_SERVICE: &str = "_mbta-proxy._tcp.local";
+
+/// Host name tried when nothing advertises the service
+pub const PROXY_HOSTNAME: &str = CONFIG.mbta.proxy_hostname;
+
+type Name = heapless::String<64>;
+
+/// Address and port of a service found on the local network
+#[derive(Debug, Copy, Clone)]
+pub struct Service {
+    pub address: Ipv4Address,
+    pub port: u16,
+}
+
+/// Browse for `PROXY_SERVICE`, then fall back to resolving `PROXY_HOSTNAME`
+/// on port 80
+pub async fn discover_proxy(stack: &'static Stack<cyw43::NetDriver<'static>>) -> Option<Service> {
+    let timeout = Duration::from_secs(3);
+
+    if let Some(service) = browse(stack, PROXY_SERVICE, timeout).await {
+        info!(
+            "found {} at {}:{}",
+            PROXY_SERVICE, service.address, service.port
+        );
+        return Some(service);
+    }
+
+    let address = resolve(stack, PROXY_HOSTNAME, timeout).await?;
+    info!("resolved {} to {}", PROXY_HOSTNAME, address);
+    Some(Service { address, port: 80 })
+}
+
+/// Find the first instance of `service_type` and resolve its host
+pub async fn browse(
+    stack: &'static Stack<cyw43::NetDriver<'static>>,
+    service_type: &str,
+    timeout: Duration,
+) -> Option<Service> {
+    let mut records = Records::new(service_type);
+    query(
+        stack,
+        service_type,
+        TYPE_PTR,
+        timeout,
+        &mut records,
+        |records| records.service(),
+    )
+    .await
+}
+
+/// Resolve a `.local` host name to its IPv4 address
+pub async fn resolve(
+    stack: &'static Stack<cyw43::NetDriver<'static>>,
+    hostname: &str,
+    timeout: Duration,
+) -> Option<Ipv4Address> {
+    let mut records = Records::new("");
+    query(stack, hostname, TYPE_A, timeout, &mut records, |records| {
+        records.address(hostname)
+    })
+    .await
+}
+
+/// Records gathered from every response received so far
+struct Records<'a> {
+    service_type: &'a str,
+    /// first instance of `service_type` from a PTR record
+    instance: Option<Name>,
+    /// port and target host of the first SRV record for `service_type`
+    srv: Option<(u16, Name)>,
+    addresses: heapless::Vec<(Name, Ipv4Address), 4>,
+}
+
+impl<'a> Records<'a> {
+    fn new(service_type: &'a str) -> Self {
+        Records {
+            service_type,
+            instance: None,
+            srv: None,
+            addresses: heapless::Vec::new(),
+        }
+    }
+
+    fn address(&self, hostname: &str) -> Option<Ipv4Address> {
+        self.addresses
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(hostname))
+            .map(|(_, address)| *address)
+    }
+
+    fn service(&self) -> Option<Service> {
+        let (port, target) = self.srv.as_ref()?;
+        Some(Service {
+            address: self.address(target)?,
+            port: *port,
+        })
+    }
+}
+
+/// Send a one-shot query and collect records until `done` finds an answer.
+/// Queries come from an ephemeral port, so responders reply with unicast
+/// and no multicast group membership is needed.
+async fn query<T>(
+    stack: &'static Stack<cyw43::NetDriver<'static>>,
+    name: &str,
+    qtype: u16,
+    timeout: Duration,
+    records: &mut Records<'_>,
+    done: impl Fn(&Records<'_>) -> Option<T>,
+) -> Option<T> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1500];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; 256];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(0) {
+        error!("Failed to bind mDNS socket: {:?}", e);
+        return None;
+    }
+
+    let mut packet = [0; 256];
+    let len = write_query(&mut packet, name, qtype)?;
+    socket
+        .send_to(&packet[..len], (MDNS_ADDRESS, MDNS_PORT))
+        .await
+        .ok()?;
+    // responders usually put the SRV and A records of a service in the
+    // additional section, ask for them separately if not
+    let mut asked_for_srv = false;
+    let mut asked_for_target = false;
+
+    let deadline = Instant::now() + timeout;
+    let mut response = [0; 1500];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let Ok(Ok((len, _from))) = with_timeout(remaining, socket.recv_from(&mut response)).await
+        else {
+            warn!("no mDNS answer for {}", name);
+            return None;
+        };
+        parse_response(&response[..len], records);
+
+        if let Some(answer) = done(records) {
+            return Some(answer);
+        }
+
+        let follow_up = match (records.instance.as_ref(), records.srv.as_ref()) {
+            (Some(instance), None) if !asked_for_srv => {
+                asked_for_srv = true;
+                write_query(&mut packet, instance, TYPE_SRV)
+            }
+            (_, Some((_, target))) if !asked_for_target => {
+                asked_for_target = true;
+                write_query(&mut packet, target, TYPE_A)
+            }
+            _ => None,
+        };
+        if let Some(len) = follow_up {
+            socket
+                .send_to(&packet[..len], (MDNS_ADDRESS, MDNS_PORT))
+                .await
+                .ok()?;
+        }
+    }
+}
+
+fn write_query(packet: &mut [u8], name: &str, qtype: u16) -> Option<usize> {
+    // id, flags, one question, no answers
+    let header = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    packet.get_mut(..header.len())?.copy_from_slice(&header);
+    let mut len = header.len();
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        *packet.get_mut(len)? = label.len() as u8;
+        packet
+            .get_mut(len + 1..len + 1 + label.len())?
+            .copy_from_slice(label.as_bytes());
+        len += 1 + label.len();
+    }
+
+    let question = [0, (qtype >> 8) as u8, qtype as u8, 0, CLASS_IN as u8];
+    packet
+        .get_mut(len..len + question.len())?
+        .copy_from_slice(&question);
+    Some(len + question.len())
+}
+
+/// Pick PTR, SRV and A records out of every section of a response
+fn parse_response(packet: &[u8], records: &mut Records<'_>) -> Option<()> {
+    let count = |index: usize| u16::from_be_bytes([packet[index], packet[index + 1]]) as usize;
+    if packet.len() < 12 || packet[2] & 0x80 == 0 {
+        return None;
+    }
+    let questions = count(4);
+    let answers = count(6) + count(8) + count(10);
+
+    let mut offset = 12;
+    let mut name = Name::new();
+    for _ in 0..questions {
+        offset = read_name(packet, offset, &mut name)? + 4;
+    }
+
+    for _ in 0..answers {
+        offset = read_name(packet, offset, &mut name)?;
+        let header = packet.get(offset..offset + 10)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdlength = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let rdata = offset + 10;
+        offset = rdata + rdlength;
+        let data = packet.get(rdata..offset)?;
+
+        match rtype {
+            TYPE_A if rdlength == 4 => {
+                let address = Ipv4Address::from_bytes(data);
+                if records.address(&name).is_none() {
+                    records.addresses.push((name.clone(), address)).ok();
+                }
+            }
+            TYPE_PTR
+                if records.instance.is_none()
+                    && name.eq_ignore_ascii_case(records.service_type) =>
+            {
+                let mut instance = Name::new();
+                read_name(packet, rdata, &mut instance)?;
+                records.instance = Some(instance);
+            }
+            TYPE_SRV
+                if rdlength > 6
+                    && records.srv.is_none()
+                    && ends_with_ignore_case(&name, records.service_type) =>
+            {
+                let port = u16::from_be_bytes([data[4], data[5]]);
+                let mut target = Name::new();
+                read_name(packet, rdata + 6, &mut target)?;
+                records.srv = Some((port, target));
+            }
+            _ => {}
+        }
+    }
+    Some(())
+}
+
+fn ends_with_ignore_case(name: &str, suffix: &str) -> bool {
+    name.len() >= suffix.len()
+        && name.as_bytes()[name.len() - suffix.len()..].eq_ignore_ascii_case(suffix.as_bytes())
+}
+
+/// Decode a possibly compressed name at `offset` into `name`, returning the
+/// offset just past it
+fn read_name(packet: &[u8], mut offset: usize, name: &mut Name) -> Option<usize> {
+    name.clear();
+    let mut end = None;
+    // bound the number of pointers followed so a loop can't hang us
+    for _ in 0..32 {
+        let len = *packet.get(offset)? as usize;
+        if len == 0 {
+            return Some(end.unwrap_or(offset + 1));
+        }
+        if len & 0xc0 == 0xc0 {
+            let pointer = ((len & 0x3f) << 8) | *packet.get(offset + 1)? as usize;
+            end.get_or_insert(offset + 2);
+            offset = pointer;
+            continue;
+        }
+        let label = packet.get(offset + 1..offset + 1 + len)?;
+        if !name.is_empty() {
+            name.push('.').ok()?;
+        }
+        write!(name, "{}", core::str::from_utf8(label).ok()?).ok()?;
+        offset += 1 + len;
+    }
+    None
+}
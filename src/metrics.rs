@@ -0,0 +1,281 @@
+//! Counters, gauges and histograms that the fetch, clock, Wi-Fi and display
+//! code update as they go, exported by the HTTP API at `/metrics` in the
+//! Prometheus text format. The RP2040's core only has atomic loads and
+//! stores, so the values are portable-atomic's, which fall back to a
+//! critical section.
+
+use core::fmt::{self, Write};
+use portable_atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering};
+
+/// Prediction fetches, one per stop, whatever the outcome
+pub static FETCHES: Counter = Counter::new();
+
+/// Failed prediction fetches, by `FetchError::label`
+pub static FETCH_FAILURES: CounterFamily<8> = CounterFamily::new(
+    "error",
+    [
+        "url", "connect", "network", "parse", "proxy", "tls", "cert", "api",
+    ],
+);
+
+/// How long a stop's predictions took to fetch, every page of them
+pub static FETCH_DURATION: Histogram<6> = Histogram::new([250, 500, 1000, 2000, 5000, 10000]);
+
+/// Signal strength of the joined network at the last scan, dBm
+pub static WIFI_RSSI: Gauge = Gauge::new();
+
+/// Times the link dropped and was rejoined
+pub static WIFI_RECONNECTS: Counter = Counter::new();
+
+/// Seconds the clock was ahead of the time server at the last sync
+pub static TIME_DRIFT: Gauge = Gauge::new();
+
+/// How far the prediction shown when a bus became a route's next bus was
+/// from its last prediction, made within a couple of minutes of arriving
+pub static PREDICTION_ERROR: Histogram<6> =
+    Histogram::new([15_000, 30_000, 60_000, 120_000, 300_000, 600_000]);
+
+/// Brightness the display was last set to, 0 to 255
+pub static DISPLAY_BRIGHTNESS: Gauge = Gauge::new();
+
+/// Everything `render` exports
+static REGISTRY: [Entry; 8] = [
+    Entry {
+        name: "bus_sign_fetches_total",
+        help: "Prediction fetches, one per stop",
+        metric: &FETCHES,
+    },
+    Entry {
+        name: "bus_sign_fetch_failures_total",
+        help: "Failed prediction fetches by error",
+        metric: &FETCH_FAILURES,
+    },
+    Entry {
+        name: "bus_sign_fetch_duration_seconds",
+        help: "Time to fetch a stop's predictions",
+        metric: &FETCH_DURATION,
+    },
+    Entry {
+        name: "bus_sign_wifi_rssi_dbm",
+        help: "Signal strength of the joined network at the last scan",
+        metric: &WIFI_RSSI,
+    },
+    Entry {
+        name: "bus_sign_wifi_reconnects_total",
+        help: "Times the Wi-Fi link dropped",
+        metric: &WIFI_RECONNECTS,
+    },
+    Entry {
+        name: "bus_sign_time_drift_seconds",
+        help: "Seconds the clock was ahead of the time server at the last sync",
+        metric: &TIME_DRIFT,
+    },
+    Entry {
+        name: "bus_sign_prediction_error_seconds",
+        help: "Difference between a bus's first and last prediction",
+        metric: &PREDICTION_ERROR,
+    },
+    Entry {
+        name: "bus_sign_display_brightness",
+        help: "Display brightness, 0 to 255",
+        metric: &DISPLAY_BRIGHTNESS,
+    },
+];
+
+/// Every metric in the Prometheus text exposition format
+pub fn render(out: &mut impl Write) -> fmt::Result {
+    for entry in &REGISTRY {
+        writeln!(out, "# HELP {} {}", entry.name, entry.help)?;
+        writeln!(out, "# TYPE {} {}", entry.name, entry.metric.kind())?;
+        entry.metric.write(entry.name, out)?;
+    }
+    Ok(())
+}
+
+struct Entry {
+    name: &'static str,
+    help: &'static str,
+    metric: &'static (dyn Metric + Sync),
+}
+
+trait Metric {
+    /// Prometheus metric type
+    fn kind(&self) -> &'static str;
+    /// The samples, one per line
+    fn write(&self, name: &str, out: &mut dyn Write) -> fmt::Result;
+}
+
+#[derive(Default)]
+pub struct Counter(AtomicU32);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Counter(AtomicU32::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Counter {
+    fn kind(&self) -> &'static str {
+        "counter"
+    }
+
+    fn write(&self, name: &str, out: &mut dyn Write) -> fmt::Result {
+        writeln!(out, "{} {}", name, self.get())
+    }
+}
+
+/// Counters told apart by the value of one label, eg the error type
+pub struct CounterFamily<const N: usize> {
+    label: &'static str,
+    values: [&'static str; N],
+    counters: [Counter; N],
+}
+
+impl<const N: usize> CounterFamily<N> {
+    pub const fn new(label: &'static str, values: [&'static str; N]) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: Counter = Counter::new();
+        CounterFamily {
+            label,
+            values,
+            counters: [ZERO; N],
+        }
+    }
+
+    /// Count one for `value`, ignored if it isn't one of the family's
+    pub fn inc(&self, value: &str) {
+        if let Some(index) = self.values.iter().position(|v| *v == value) {
+            self.counters[index].inc();
+        }
+    }
+}
+
+impl<const N: usize> Metric for CounterFamily<N> {
+    fn kind(&self) -> &'static str {
+        "counter"
+    }
+
+    fn write(&self, name: &str, out: &mut dyn Write) -> fmt::Result {
+        for (value, counter) in self.values.iter().zip(&self.counters) {
+            writeln!(
+                out,
+                "{}{{{}=\"{}\"}} {}",
+                name,
+                self.label,
+                value,
+                counter.get()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI32);
+
+impl Gauge {
+    pub const fn new() -> Self {
+        Gauge(AtomicI32::new(0))
+    }
+
+    pub fn set(&self, value: i32) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Gauge {
+    fn kind(&self) -> &'static str {
+        "gauge"
+    }
+
+    fn write(&self, name: &str, out: &mut dyn Write) -> fmt::Result {
+        writeln!(out, "{} {}", name, self.get())
+    }
+}
+
+/// Durations counted into fixed buckets by their upper bounds in
+/// milliseconds, exported in seconds
+pub struct Histogram<const N: usize> {
+    bounds: [u32; N],
+    /// observations in each bucket alone, added up when exported
+    buckets: [AtomicU32; N],
+    count: AtomicU32,
+    /// milliseconds, wide enough not to wrap, unlike 32 bits after 50 days
+    /// of durations
+    sum: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    /// `bounds` must be in increasing order
+    pub const fn new(bounds: [u32; N]) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU32 = AtomicU32::new(0);
+        Histogram {
+            bounds,
+            buckets: [ZERO; N],
+            count: ZERO,
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, milliseconds: u32) {
+        if let Some(index) = self.bounds.iter().position(|bound| milliseconds <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(milliseconds.into(), Ordering::Relaxed);
+    }
+}
+
+impl<const N: usize> Metric for Histogram<N> {
+    fn kind(&self) -> &'static str {
+        "histogram"
+    }
+
+    fn write(&self, name: &str, out: &mut dyn Write) -> fmt::Result {
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                Seconds((*bound).into()),
+                cumulative
+            )?;
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count)?;
+        let sum = self.sum.load(Ordering::Relaxed);
+        writeln!(out, "{}_sum {}", name, Seconds(sum))?;
+        writeln!(out, "{}_count {}", name, count)
+    }
+}
+
+/// Milliseconds shown as seconds, without trailing zeros
+struct Seconds(u64);
+
+impl fmt::Display for Seconds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (seconds, milliseconds) = (self.0 / 1000, self.0 % 1000);
+        write!(f, "{}", seconds)?;
+        if milliseconds == 0 {
+            return Ok(());
+        }
+        let mut digits = heapless::String::<4>::new();
+        write!(digits, ".{:03}", milliseconds)?;
+        f.write_str(digits.trim_end_matches('0'))
+    }
+}
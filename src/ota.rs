@@ -0,0 +1,105 @@
+//! Firmware updates over the network with embassy-boot. The bootloader in
+//! `bootloader/` sits in front of two partitions laid out in `memory.x`:
+//! ACTIVE, which the sign runs from, and DFU, one sector larger, which a
+//! new image is written to as it arrives. Once the whole image checks out
+//! against its ed25519 signature the bootloader swaps the two on the next
+//! boot. The new firmware then runs on trial, and unless it calls `confirm`
+//! the bootloader swaps the old one back on the boot after.
+
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_boot_rp::{
+    AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterConfig, FirmwareUpdaterError, State,
+};
+use embassy_embedded_hal::flash::partition::BlockingPartition;
+use embassy_rp::flash::{Blocking, Flash};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+use log::*;
+use static_cell::StaticCell;
+
+use crate::config::{SharedFlash, FLASH_SIZE};
+use crate::sign::CONFIG;
+
+type Partition =
+    BlockingPartition<'static, ThreadModeRawMutex, Flash<'static, FLASH, Blocking, FLASH_SIZE>>;
+
+static UPDATER: Mutex<
+    ThreadModeRawMutex,
+    Option<BlockingFirmwareUpdater<'static, Partition, Partition>>,
+> = Mutex::new(None);
+
+/// Set once an image is written and verified, the sign should restart
+static UPDATE_READY: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    static __bootloader_active_start: u32;
+    static __bootloader_active_end: u32;
+}
+
+/// Get ready for updates, returns true when this firmware is on trial
+pub async fn init(flash: &'static SharedFlash) -> bool {
+    static ALIGNED: StaticCell<AlignedBuffer<1>> = StaticCell::new();
+    let aligned = ALIGNED.init(AlignedBuffer([0; 1]));
+    let config = FirmwareUpdaterConfig::from_linkerfile_blocking(flash);
+    let mut updater = BlockingFirmwareUpdater::new(config, &mut aligned.0);
+
+    let on_trial = matches!(updater.get_state(), Ok(State::Swap));
+    if on_trial {
+        warn!("Running a new firmware image on trial");
+    }
+    *(UPDATER.lock().await) = Some(updater);
+    on_trial
+}
+
+/// Keep this firmware, the bootloader won't roll it back any more
+pub async fn confirm() {
+    let mut updater_locked = UPDATER.lock().await;
+    let Some(updater) = updater_locked.as_mut() else {
+        return;
+    };
+    match updater.mark_booted() {
+        Ok(()) => info!("Firmware confirmed"),
+        Err(e) => error!("Failed to confirm firmware: {:?}", e),
+    }
+}
+
+/// Largest image, the size of the ACTIVE partition
+pub fn capacity() -> u32 {
+    let start = unsafe { addr_of!(__bootloader_active_start) } as u32;
+    let end = unsafe { addr_of!(__bootloader_active_end) } as u32;
+    end - start
+}
+
+/// Write one block of a new image at `offset`, erasing the sectors first.
+/// Blocks start on a sector boundary and cover whole sectors.
+pub async fn write(offset: u32, block: &[u8]) -> Result<(), FirmwareUpdaterError> {
+    let mut updater_locked = UPDATER.lock().await;
+    let updater = updater_locked
+        .as_mut()
+        .ok_or(FirmwareUpdaterError::BadState)?;
+    if offset == 0 {
+        info!("Receiving a firmware image");
+    }
+    updater.write_firmware(offset as usize, block)
+}
+
+/// Check the first `size` bytes written against `signature` and mark the
+/// image for the bootloader to swap in on the next boot
+pub async fn finish(size: u32, signature: &[u8; 64]) -> Result<(), FirmwareUpdaterError> {
+    let settings = CONFIG.ota.as_ref().ok_or(FirmwareUpdaterError::BadState)?;
+    let mut updater_locked = UPDATER.lock().await;
+    let updater = updater_locked
+        .as_mut()
+        .ok_or(FirmwareUpdaterError::BadState)?;
+    updater.verify_and_mark_updated(&settings.public_key, signature, size)?;
+    info!("Firmware image of {} bytes verified", size);
+    UPDATE_READY.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// A verified image is waiting for the sign to restart
+pub fn update_ready() -> bool {
+    UPDATE_READY.load(Ordering::Relaxed)
+}
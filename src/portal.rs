@@ -0,0 +1,440 @@
+use core::fmt::Write as _;
+use cyw43::{Control, NetDriver};
+use embassy_futures::select::{select3, Either3};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{ConfigV4, Ipv4Address, Ipv4Cidr, Stack, StaticConfigV4};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use log::*;
+
+use crate::config::{self, Config};
+use crate::sign::CONFIG;
+
+/// Open network started by the sign when it can't join one
+pub const SETUP_SSID: &str = CONFIG.wifi.setup_ssid;
+
+const SETUP_CHANNEL: u8 = 6;
+
+const ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 4, 1);
+const NETMASK: [u8; 4] = [255, 255, 255, 0];
+/// Clients are handed 192.168.4.10 and up
+const FIRST_LEASE: u8 = 10;
+const LEASES: usize = 8;
+const LEASE_SECONDS: u32 = 60 * 60;
+
+/// Leave any network and start the open setup access point
+pub async fn start_ap(stack: &'static Stack<NetDriver<'static>>, control: &mut Control<'static>) {
+    control.leave().await;
+    control.start_ap_open(SETUP_SSID, SETUP_CHANNEL).await;
+    stack.set_config_v4(ConfigV4::Static(StaticConfigV4 {
+        address: Ipv4Cidr::new(ADDRESS, 24),
+        gateway: Some(ADDRESS),
+        dns_servers: heapless::Vec::new(),
+    }));
+    info!("setup portal up on {} at http://{}", SETUP_SSID, ADDRESS);
+}
+
+/// Serve the setup form until it is saved, then reboot into the new
+/// configuration
+pub async fn run_setup(stack: &'static Stack<NetDriver<'static>>, config: &Config) -> ! {
+    match select3(
+        dhcp_server(stack),
+        dns_server(stack),
+        http_server(stack, config),
+    )
+    .await
+    {
+        Either3::First(never) | Either3::Second(never) | Either3::Third(never) => never,
+    }
+}
+
+/// Just enough DHCP to hand out addresses on the setup network
+async fn dhcp_server(stack: &'static Stack<NetDriver<'static>>) -> ! {
+    const DISCOVER: u8 = 1;
+    const OFFER: u8 = 2;
+    const REQUEST: u8 = 3;
+    const ACK: u8 = 5;
+    const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+    const OPTIONS: usize = 240;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(67).unwrap();
+
+    // client hardware addresses, a lease is its index plus FIRST_LEASE
+    let mut leases: heapless::Vec<[u8; 6], LEASES> = heapless::Vec::new();
+    let mut next_evicted = 0;
+
+    let mut packet = [0; 576];
+    loop {
+        let Ok((len, _from)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        let request = &packet[..len];
+        if len < OPTIONS || request[0] != 1 || request[OPTIONS - 4..OPTIONS] != MAGIC_COOKIE {
+            continue;
+        }
+
+        let message_type = dhcp_option(&request[OPTIONS..], 53).and_then(|value| value.first());
+        let reply_type = match message_type {
+            Some(&DISCOVER) => OFFER,
+            Some(&REQUEST) => ACK,
+            _ => continue,
+        };
+
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&request[28..34]);
+        let index = match leases.iter().position(|lease| *lease == mac) {
+            Some(index) => index,
+            None if leases.push(mac).is_ok() => leases.len() - 1,
+            None => {
+                let index = next_evicted;
+                leases[index] = mac;
+                next_evicted = (next_evicted + 1) % LEASES;
+                index
+            }
+        };
+        let mut client = ADDRESS.0;
+        client[3] = FIRST_LEASE + index as u8;
+
+        let mut reply = [0; OPTIONS + 32];
+        reply[0] = 2; // BOOTREPLY
+        reply[1..4].copy_from_slice(&request[1..4]); // htype, hlen, hops
+        reply[4..8].copy_from_slice(&request[4..8]); // xid
+        reply[10..12].copy_from_slice(&request[10..12]); // flags
+        reply[16..20].copy_from_slice(&client); // yiaddr
+        reply[20..24].copy_from_slice(&ADDRESS.0); // siaddr
+        reply[28..44].copy_from_slice(&request[28..44]); // chaddr
+        reply[OPTIONS - 4..OPTIONS].copy_from_slice(&MAGIC_COOKIE);
+
+        let lease = LEASE_SECONDS.to_be_bytes();
+        let address = ADDRESS.0;
+        let options: [&[u8]; 7] = [
+            &[53, 1, reply_type],
+            &[54, 4, address[0], address[1], address[2], address[3]],
+            &[51, 4, lease[0], lease[1], lease[2], lease[3]],
+            &[1, 4, NETMASK[0], NETMASK[1], NETMASK[2], NETMASK[3]],
+            &[3, 4, address[0], address[1], address[2], address[3]],
+            &[6, 4, address[0], address[1], address[2], address[3]],
+            &[255],
+        ];
+        let mut len = OPTIONS;
+        for option in options {
+            reply[len..len + option.len()].copy_from_slice(option);
+            len += option.len();
+        }
+
+        debug!(
+            "DHCP {} 192.168.4.{}",
+            if reply_type == OFFER { "offer" } else { "ack" },
+            client[3]
+        );
+        // the client has no address yet, so broadcast the reply
+        if let Err(e) = socket
+            .send_to(&reply[..len], (Ipv4Address::BROADCAST, 68))
+            .await
+        {
+            warn!("Failed to send DHCP reply: {:?}", e);
+        }
+    }
+}
+
+/// Value of DHCP option `code`
+fn dhcp_option(mut options: &[u8], code: u8) -> Option<&[u8]> {
+    loop {
+        match *options.first()? {
+            255 => return None,
+            0 => options = &options[1..],
+            tag => {
+                let len = *options.get(1)? as usize;
+                let value = options.get(2..2 + len)?;
+                if tag == code {
+                    return Some(value);
+                }
+                options = &options[2 + len..];
+            }
+        }
+    }
+}
+
+/// Answer every A query with our own address, so phones and laptops open
+/// the setup form as a captive portal
+async fn dns_server(stack: &'static Stack<NetDriver<'static>>) -> ! {
+    const TYPE_A: u16 = 1;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(53).unwrap();
+
+    let mut packet = [0; 512];
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        // queries only, with at least one question
+        if len < 12 || packet[2] & 0x80 != 0 || packet[4..6] == [0, 0] {
+            continue;
+        }
+
+        // skip the labels of the first question
+        let mut end = 12;
+        while end < len && packet[end] != 0 {
+            end += 1 + packet[end] as usize;
+        }
+        end += 5;
+        if end > len {
+            continue;
+        }
+        let qtype = u16::from_be_bytes([packet[end - 4], packet[end - 3]]);
+        let answers = (qtype == TYPE_A) as u8;
+
+        packet[2] = 0x80 | (packet[2] & 0x01); // response, keep RD
+        packet[3] = 0x80; // RA, no error
+        packet[4..12].copy_from_slice(&[0, 1, 0, answers, 0, 0, 0, 0]);
+
+        let mut reply_len = end;
+        if answers > 0 {
+            // pointer back to the question name, IN A, 60 s TTL
+            let mut answer = [0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 0, 0, 0, 0];
+            answer[12..].copy_from_slice(&ADDRESS.0);
+            if reply_len + answer.len() > packet.len() {
+                continue;
+            }
+            packet[reply_len..reply_len + answer.len()].copy_from_slice(&answer);
+            reply_len += answer.len();
+        }
+
+        if let Err(e) = socket.send_to(&packet[..reply_len], from).await {
+            warn!("Failed to send DNS reply: {:?}", e);
+        }
+    }
+}
+
+/// Serve the setup form on every path and save it on `POST /save`
+async fn http_server(stack: &'static Stack<NetDriver<'static>>, config: &Config) -> ! {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut request = [0; 1024];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(e) = socket.accept(80).await {
+            warn!("Failed to accept setup connection: {:?}", e);
+            continue;
+        }
+
+        let Some((head, body)) = read_request(&mut socket, &mut request).await else {
+            socket.abort();
+            continue;
+        };
+
+        if head.starts_with("POST /save ") {
+            match update_config(config, body) {
+                Ok(updated) => match config::save(&updated).await {
+                    Ok(()) => {
+                        info!("setup saved, rebooting");
+                        respond(&mut socket, &updated, Some("Saved, rebooting...")).await;
+                        socket.close();
+                        socket.flush().await.ok();
+                        Timer::after_secs(1).await;
+                        cortex_m::peripheral::SCB::sys_reset();
+                    }
+                    Err(e) => {
+                        error!("Failed to save config: {:?}", e);
+                        respond(&mut socket, &updated, Some("Could not save settings")).await;
+                    }
+                },
+                Err(problem) => respond(&mut socket, config, Some(problem)).await,
+            }
+        } else {
+            respond(&mut socket, config, None).await;
+        }
+
+        socket.close();
+        socket.flush().await.ok();
+    }
+}
+
+/// Read a request into `buffer`, returning its head and body
+async fn read_request<'b>(
+    socket: &mut TcpSocket<'_>,
+    buffer: &'b mut [u8],
+) -> Option<(&'b str, &'b str)> {
+    let mut len = 0;
+    let head_end = loop {
+        if let Some(index) = buffer[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            break index + 4;
+        }
+        if len == buffer.len() {
+            return None;
+        }
+        match socket.read(&mut buffer[len..]).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => len += n,
+        }
+    };
+
+    let head = core::str::from_utf8(&buffer[..head_end]).ok()?;
+    let content_length = head
+        .split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    let end = head_end + content_length;
+    if end > buffer.len() {
+        return None;
+    }
+    while len < end {
+        match socket.read(&mut buffer[len..end]).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => len += n,
+        }
+    }
+
+    let (head, body) = buffer[..end].split_at(head_end);
+    Some((
+        core::str::from_utf8(head).ok()?,
+        core::str::from_utf8(body).ok()?,
+    ))
+}
+
+/// Copy of `config` with the submitted form applied, or what's wrong with it
+fn update_config(config: &Config, form: &str) -> Result<Config, &'static str> {
+    let mut updated = config.clone();
+    updated.wifi_ssid = form_value(form, "ssid").ok_or("Network name is too long")?;
+    updated.wifi_password = form_value(form, "password").ok_or("Password is too long")?;
+    // the page never shows the stored password, so a blank one keeps it
+    if updated.wifi_password.is_empty() && updated.wifi_ssid == config.wifi_ssid {
+        updated.wifi_password = config.wifi_password.clone();
+    }
+    updated.bus_stop = form_value(form, "stop").ok_or("Stop ID is too long")?;
+    updated.routes = form_value(form, "routes").ok_or("Too many routes")?;
+
+    if updated.wifi_ssid.is_empty() {
+        return Err("Network name is required");
+    }
+    if !updated.wifi_password.is_empty() && !config::password_valid(&updated.wifi_password) {
+        return Err("Password must be 8 to 63 characters, or 64 hex digits");
+    }
+    let is_id = |c: char| c.is_ascii_alphanumeric() || c == '-';
+    if !updated.bus_stop.chars().all(is_id) {
+        return Err("Stop ID must be letters, numbers or dashes");
+    }
+    if !updated.routes_valid() {
+        return Err("Routes must be a comma separated list of up to 8, eg 87,88/1@2580");
+    }
+    if updated.bus_stop.is_empty() && updated.routes().any(|route| route.stop.is_none()) {
+        return Err("Stop ID is required for routes without their own @stop");
+    }
+    Ok(updated)
+}
+
+/// Decode field `name` of an `application/x-www-form-urlencoded` body,
+/// `None` if it doesn't fit
+fn form_value<const N: usize>(form: &str, name: &str) -> Option<heapless::String<N>> {
+    let mut value = heapless::String::new();
+    let Some(encoded) = form
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, encoded)| encoded)
+    else {
+        return Some(value);
+    };
+
+    let mut bytes: heapless::Vec<u8, N> = heapless::Vec::new();
+    let mut input = encoded.bytes();
+    while let Some(byte) = input.next() {
+        let decoded = match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [input.next()?, input.next()?];
+                u8::from_str_radix(core::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        };
+        bytes.push(decoded).ok()?;
+    }
+    value.push_str(core::str::from_utf8(&bytes).ok()?).ok()?;
+    Some(value)
+}
+
+/// Send the setup form filled in from `config`, with an optional notice
+async fn respond(socket: &mut TcpSocket<'_>, config: &Config, notice: Option<&str>) {
+    let mut page: heapless::String<2048> = heapless::String::new();
+    let written = write!(
+        page,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/html; charset=utf-8\r\n\
+         Cache-Control: no-store\r\n\
+         Connection: close\r\n\r\n\
+         <!DOCTYPE html><html><head>\
+         <meta name=\"viewport\" content=\"width=device-width\">\
+         <title>Bus sign setup</title></head><body>\
+         <h1>Bus sign setup</h1><p>{}</p>\
+         <form method=\"post\" action=\"/save\">\
+         <p><label>Wi-Fi network<br><input name=\"ssid\" maxlength=\"32\" value=\"{}\"></label></p>\
+         <p><label>Wi-Fi password<br><input name=\"password\" type=\"password\" maxlength=\"64\" placeholder=\"{}\"></label></p>\
+         <p><label>MBTA stop ID<br><input name=\"stop\" maxlength=\"16\" value=\"{}\"></label></p>\
+         <p><label>Routes<br><input name=\"routes\" maxlength=\"192\" value=\"{}\"></label></p>\
+         <p><button>Save and reboot</button></p>\
+         </form></body></html>",
+        Escaped(notice.unwrap_or("")),
+        Escaped(&config.wifi_ssid),
+        if config.wifi_password.is_empty() {
+            ""
+        } else {
+            "unchanged if left blank"
+        },
+        Escaped(&config.bus_stop),
+        Escaped(&config.routes),
+    );
+    if written.is_err() {
+        error!("Setup page doesn't fit its buffer");
+        return;
+    }
+
+    if let Err(e) = socket.write_all(page.as_bytes()).await {
+        warn!("Failed to send setup page: {:?}", e);
+    }
+}
+
+/// Formats a value for use in HTML text or a quoted attribute
+struct Escaped<'a>(&'a str);
+
+impl core::fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&#39;")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
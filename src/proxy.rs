@@ -0,0 +1,208 @@
+use core::fmt;
+use embassy_net::tcp::TcpSocket;
+use embedded_io_async::Write;
+use log::*;
+
+use crate::config::Config;
+use crate::fetch::FetchError;
+use crate::mdns::Service;
+use crate::sign::CONFIG;
+
+/// How the sign reaches the MBTA API, `mode` under `[mbta]` in `sign.toml`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ApiMode {
+    /// through `mbta-proxy.py` at the stored `proxy_ip`, or discovered when
+    /// that's empty
+    Proxy,
+    /// straight to `api_host` over HTTPS, trusting the pinned keys
+    Direct,
+}
+
+/// Where API requests are sent and how they get there
+pub struct Endpoint {
+    pub upstream: Upstream,
+    pub proxy: Option<ForwardProxy>,
+}
+
+/// Server that answers the API requests, either the MBTA itself or
+/// the path-rewriting `mbta-proxy.py`
+pub struct Upstream {
+    pub https: bool,
+    pub host: heapless::String<64>,
+    pub port: u16,
+    /// comma separated hex SHA-256 hashes of the accepted server keys
+    pub tls_pins: &'static str,
+}
+
+/// Standard HTTP forward proxy, such as squid or tinyproxy
+#[derive(Copy, Clone)]
+pub struct ForwardProxy {
+    pub host: &'static str,
+    pub port: u16,
+    /// open a `CONNECT` tunnel instead of sending absolute-URI requests
+    pub tunnel: bool,
+}
+
+impl Endpoint {
+    /// In proxy mode the stored `proxy_ip` is the host running
+    /// `mbta-proxy.py`, left empty until it is discovered when not set. In
+    /// direct mode the MBTA API is used over HTTPS. The mode, API host, key
+    /// pins and forward proxy come from the `[mbta]` and `[forward_proxy]`
+    /// sections of `sign.toml`.
+    pub fn new(config: &Config) -> Self {
+        let tls_pins = CONFIG.mbta.tls_pins;
+
+        let upstream = match CONFIG.mbta.mode {
+            ApiMode::Direct => {
+                if !config.proxy_ip.is_empty() {
+                    warn!("Ignoring proxy_ip {}, in direct mode", config.proxy_ip);
+                }
+                Upstream {
+                    https: true,
+                    host: heapless::String::try_from(CONFIG.mbta.api_host).unwrap(),
+                    port: 443,
+                    tls_pins,
+                }
+            }
+            ApiMode::Proxy => Upstream {
+                https: false,
+                host: config.proxy_ip.clone(),
+                port: 80,
+                tls_pins,
+            },
+        };
+
+        Endpoint {
+            upstream,
+            proxy: CONFIG.mbta.forward_proxy,
+        }
+    }
+}
+
+impl Upstream {
+    /// `mbta-proxy.py` found on the local network
+    pub fn discovered(service: Service, tls_pins: &'static str) -> Self {
+        let mut host = heapless::String::new();
+        fmt::Write::write_fmt(&mut host, format_args!("{}", service.address)).unwrap();
+        Upstream {
+            https: false,
+            host,
+            port: service.port,
+            tls_pins,
+        }
+    }
+}
+
+/// Formats as a base URL, eg "https://api-v3.mbta.com"
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (scheme, default_port) = if self.https {
+            ("https", 443)
+        } else {
+            ("http", 80)
+        };
+        if self.host.contains(':') {
+            write!(f, "{}://[{}]", scheme, self.host)?;
+        } else {
+            write!(f, "{}://{}", scheme, self.host)?;
+        }
+        if self.port != default_port {
+            write!(f, ":{}", self.port)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parts of an absolute URL needed to address a request
+pub(crate) struct UrlParts<'a> {
+    pub https: bool,
+    /// host with optional port, eg "api-v3.mbta.com:443"
+    pub authority: &'a str,
+    /// path and query, eg "/predictions?filter[stop]=1"
+    pub path: &'a str,
+}
+
+impl<'a> UrlParts<'a> {
+    pub fn parse(url: &'a str) -> Option<Self> {
+        let (https, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return None;
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        Some(UrlParts {
+            https,
+            authority,
+            path,
+        })
+    }
+
+    /// Host without the port, or the brackets of an IPv6 literal
+    pub fn host(&self) -> &'a str {
+        let (host, _port) = self.split();
+        host.strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host)
+    }
+
+    pub fn port(&self) -> u16 {
+        match self.split() {
+            (_host, Some(port)) => port.parse().unwrap_or(0),
+            (_host, None) if self.https => 443,
+            (_host, None) => 80,
+        }
+    }
+
+    /// Authority split at the port, minding the colons of "[fd00::1]:8080"
+    fn split(&self) -> (&'a str, Option<&'a str>) {
+        match self.authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, Some(port)),
+            _ => (self.authority, None),
+        }
+    }
+}
+
+/// Ask the proxy to tunnel `socket` through to `authority`
+pub(crate) async fn tunnel(socket: &mut TcpSocket<'_>, authority: &str) -> Result<(), FetchError> {
+    let mut request: heapless::String<128> = heapless::String::new();
+    fmt::Write::write_fmt(
+        &mut request,
+        format_args!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n\r\n"),
+    )
+    .map_err(|_| FetchError::Url)?;
+
+    if socket.write_all(request.as_bytes()).await.is_err() {
+        error!("Failed to send CONNECT request");
+        return Err(FetchError::Proxy);
+    }
+
+    // read up to the end of the response head, the tunnel starts after it
+    let mut head = [0; 256];
+    let mut len = 0;
+    while !head[..len].ends_with(b"\r\n\r\n") {
+        if len == head.len() {
+            error!("CONNECT response head too long");
+            return Err(FetchError::Proxy);
+        }
+        match socket.read(&mut head[len..len + 1]).await {
+            Ok(0) | Err(_) => {
+                error!("Proxy closed connection during CONNECT");
+                return Err(FetchError::Proxy);
+            }
+            Ok(n) => len += n,
+        }
+    }
+
+    // "HTTP/1.1 200 Connection established"
+    if len < 12 || !head.starts_with(b"HTTP/1.") || &head[9..12] != b"200" {
+        error!("Proxy refused CONNECT to {}", authority);
+        return Err(FetchError::Proxy);
+    }
+
+    Ok(())
+}
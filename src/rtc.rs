@@ -1,24 +1,60 @@
+use core::sync::atomic::{AtomicI32, Ordering};
 use embassy_rp::peripherals;
 use embassy_rp::rtc;
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
 use embassy_sync::mutex::Mutex;
+use embassy_time::Instant;
 use log::*;
 
+use crate::metrics;
 use crate::timestamp::Timestamp;
 
 static RTC: Mutex<ThreadModeRawMutex, Option<rtc::Rtc<'static, peripherals::RTC>>> =
     Mutex::new(None);
 
-pub async fn init(peripheral: peripherals::RTC, timestamp: Timestamp) {
+/// Minutes the RTC's local time is ahead of UTC
+static UTC_OFFSET: AtomicI32 = AtomicI32::new(0);
+
+pub async fn init(peripheral: peripherals::RTC, timestamp: Timestamp, utc_offset: i32) {
     let mut rtc = rtc::Rtc::new(peripheral);
-    info!("Setting RTC to {:?}", timestamp);
+    info!("Setting RTC to {:?}, UTC{:+} min", timestamp, utc_offset);
     rtc.set_datetime(timestamp.into()).unwrap();
+    UTC_OFFSET.store(utc_offset, Ordering::Relaxed);
     *(RTC.lock().await) = Some(rtc);
 }
 
+/// Set the clock again from the time server, recording how far it had
+/// drifted in `metrics::TIME_DRIFT`
+pub async fn sync(timestamp: Timestamp, utc_offset: i32) {
+    let mut rtc_locked = RTC.lock().await;
+    let Some(rtc) = rtc_locked.as_mut() else {
+        return;
+    };
+    if let Ok(datetime) = rtc.now() {
+        let clock = Instant::from(Timestamp::from(datetime)).as_secs() as i64;
+        let drift = clock - Instant::from(&timestamp).as_secs() as i64;
+        info!("Clock was {} s ahead, setting it to {:?}", drift, timestamp);
+        metrics::TIME_DRIFT.set(drift as i32);
+    }
+    rtc.set_datetime(timestamp.into()).unwrap();
+    UTC_OFFSET.store(utc_offset, Ordering::Relaxed);
+}
+
+/// Minutes local time is ahead of UTC, as of `init` or the last `sync`
+pub fn utc_offset() -> i32 {
+    UTC_OFFSET.load(Ordering::Relaxed)
+}
+
 pub async fn now() -> Timestamp {
     let rtc_locked = RTC.lock().await;
     let rtc_ref = rtc_locked.as_ref().unwrap();
     let datetime = rtc_ref.now().unwrap();
     Timestamp::from(datetime)
 }
+
+/// Current time, or `None` before `init`
+pub async fn try_now() -> Option<Timestamp> {
+    let rtc_locked = RTC.lock().await;
+    let datetime = rtc_locked.as_ref()?.now().ok()?;
+    Some(Timestamp::from(datetime))
+}
@@ -0,0 +1,176 @@
+use crate::solar;
+use crate::timestamp::Timestamp;
+
+/// Defined with the API, which can hold one
+pub use bus_sign_protocol::api::Mode;
+
+const DAY: u32 = 24 * 60;
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct TimeOfDay {
+    pub hour: u8,
+    pub minute: u8,
+}
+
+/// Start or end of a window
+#[derive(Copy, Clone, PartialEq)]
+pub enum Edge {
+    At(TimeOfDay),
+    /// minutes after sunrise, negative for before
+    Sunrise(i16),
+    /// minutes after sunset, negative for before
+    Sunset(i16),
+}
+
+/// Stretch of one day, it wraps past midnight when `from` is later than
+/// `to`, covering both the start and the end of that same day
+pub struct Window {
+    pub from: Edge,
+    pub to: Edge,
+    pub mode: Mode,
+}
+
+/// A date that uses the holiday profile, every year unless `year` is given
+pub struct Holiday {
+    pub year: Option<u16>,
+    pub month: u8,
+    pub day: u8,
+}
+
+/// Display windows by kind of day, the display is on outside of them
+pub struct Schedule {
+    /// degrees north, for sunrise and sunset
+    pub latitude: f64,
+    /// degrees east
+    pub longitude: f64,
+    pub brightness: u8,
+    pub dim_brightness: u8,
+    /// Monday to Friday
+    pub weekday: &'static [Window],
+    /// Saturday and Sunday
+    pub weekend: &'static [Window],
+    /// any date in `holidays`, whatever the day of the week
+    pub holiday: &'static [Window],
+    pub holidays: &'static [Holiday],
+}
+
+impl TimeOfDay {
+    fn minutes(&self) -> u32 {
+        self.hour as u32 * 60 + self.minute as u32
+    }
+}
+
+/// Local sunrise and sunset on one day, minutes after midnight
+struct SunTimes {
+    sunrise: u32,
+    sunset: u32,
+}
+
+impl Edge {
+    fn minutes(&self, sun: &SunTimes) -> u32 {
+        let (base, offset) = match *self {
+            Edge::At(time) => return time.minutes(),
+            Edge::Sunrise(offset) => (sun.sunrise, offset),
+            Edge::Sunset(offset) => (sun.sunset, offset),
+        };
+        (base as i32 + offset as i32).rem_euclid(DAY as i32) as u32
+    }
+}
+
+impl Window {
+    fn contains(&self, minute: u32, sun: &SunTimes) -> bool {
+        let (from, to) = (self.from.minutes(sun), self.to.minutes(sun));
+        match from.cmp(&to) {
+            core::cmp::Ordering::Less => from <= minute && minute < to,
+            core::cmp::Ordering::Greater => minute >= from || minute < to,
+            core::cmp::Ordering::Equal => false,
+        }
+    }
+}
+
+impl Holiday {
+    fn matches(&self, date: &Timestamp) -> bool {
+        self.month == date.month
+            && self.day == date.day
+            && self.year.map_or(true, |year| year == date.year)
+    }
+}
+
+/// Seconds from `now` until the first of `edges` (minutes after midnight)
+/// still to come today, or until midnight
+fn seconds_until(edges: impl Iterator<Item = u32>, now: &Timestamp) -> u32 {
+    let minute = now.hour as u32 * 60 + now.minute as u32;
+    let next_edge = edges.filter(|&edge| edge > minute).fold(DAY, u32::min);
+    (next_edge - minute) * 60 - now.second as u32
+}
+
+impl Schedule {
+    /// Windows that apply on the day of `now`
+    fn windows(&self, now: &Timestamp) -> &'static [Window] {
+        if self.holidays.iter().any(|holiday| holiday.matches(now)) {
+            self.holiday
+        } else if now.weekday() >= 5 {
+            self.weekend
+        } else {
+            self.weekday
+        }
+    }
+
+    /// Sunrise and sunset on the day of `now`, local time being
+    /// `utc_offset` minutes ahead of UTC. Falls back to 06:00 and 18:00
+    /// when the sun doesn't rise or set.
+    fn sun_times(&self, now: &Timestamp, utc_offset: i32) -> SunTimes {
+        let local = |utc: f64| (utc as i32 + utc_offset).rem_euclid(DAY as i32) as u32;
+        match solar::sunrise_sunset(now.year, now.month, now.day, self.latitude, self.longitude) {
+            Some((sunrise, sunset)) => SunTimes {
+                sunrise: local(sunrise),
+                sunset: local(sunset),
+            },
+            None => SunTimes {
+                sunrise: 6 * 60,
+                sunset: 18 * 60,
+            },
+        }
+    }
+
+    /// Mode at `now` and the seconds until the next window edge or
+    /// midnight, when it may change. `utc_offset` is in minutes.
+    pub fn mode_at(&self, now: &Timestamp, utc_offset: i32) -> (Mode, u32) {
+        let windows = self.windows(now);
+        let sun = self.sun_times(now, utc_offset);
+        let minute = now.hour as u32 * 60 + now.minute as u32;
+
+        let mode = windows
+            .iter()
+            .filter(|window| window.contains(minute, &sun))
+            .map(|window| window.mode)
+            .fold(Mode::On, |a, b| if b > a { b } else { a });
+
+        let edges = windows
+            .iter()
+            .flat_map(|window| [window.from.minutes(&sun), window.to.minutes(&sun)]);
+        (mode, seconds_until(edges, now))
+    }
+
+    /// Whether `now` falls between `from` and `to` on its day, and the
+    /// seconds until either edge or midnight, like `mode_at`
+    pub fn between(&self, from: Edge, to: Edge, now: &Timestamp, utc_offset: i32) -> (bool, u32) {
+        let sun = self.sun_times(now, utc_offset);
+        let minute = now.hour as u32 * 60 + now.minute as u32;
+        let window = Window {
+            from,
+            to,
+            mode: Mode::On,
+        };
+        let edges = [from.minutes(&sun), to.minutes(&sun)].into_iter();
+        (window.contains(minute, &sun), seconds_until(edges, now))
+    }
+
+    pub fn brightness(&self, mode: Mode) -> u8 {
+        match mode {
+            Mode::On => self.brightness,
+            Mode::Dim => self.dim_brightness,
+            Mode::Off => 0,
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use embedded_graphics::pixelcolor::Rgb888;
+use log::LevelFilter;
+
+use crate::proxy::{ApiMode, ForwardProxy};
+use crate::schedule::{Edge, Schedule};
+use crate::wifi::Network;
+
+/// Installation settings read from `sign.toml` at build time. `build.rs`
+/// checks the file and generates `CONFIG` from it.
+pub struct SignConfig {
+    pub wifi: WifiSettings,
+    pub network: NetworkSettings,
+    /// MBTA stop ID for routes that don't name their own, empty to set it
+    /// at setup
+    pub stop: &'static str,
+    /// one display row each, in order, when no profile is active. Only the
+    /// first boot default, after that they come from `Config::routes`.
+    pub routes: &'static [RouteSettings],
+    /// routes to show instead during parts of the day
+    pub profiles: &'static [ProfileSettings],
+    pub colors: Colors,
+    /// when the display is on, dimmed or off
+    pub schedule: Schedule,
+    /// IANA time zone of the sign, eg "America/New_York"
+    pub time_zone: &'static str,
+    pub mbta: MbtaSettings,
+    /// broker to publish to, for Home Assistant
+    pub mqtt: Option<MqttSettings>,
+    /// firmware updates over the network, `None` to turn them off
+    pub ota: Option<OtaSettings>,
+    pub syslog: SyslogSettings,
+}
+
+pub struct WifiSettings {
+    /// network joined on first boot, empty to start the setup portal
+    pub ssid: &'static str,
+    pub password: &'static str,
+    /// access point started by the setup portal
+    pub setup_ssid: &'static str,
+    /// also known, tried alongside the stored network
+    pub networks: &'static [Network<'static>],
+}
+
+pub struct NetworkSettings {
+    /// static address, `None` for DHCP
+    pub ipv4: Option<StaticIpv4>,
+    /// take an IPv6 address from router advertisements
+    pub ipv6: bool,
+}
+
+pub struct StaticIpv4 {
+    pub address: [u8; 4],
+    pub prefix_len: u8,
+    pub gateway: Option<[u8; 4]>,
+    /// up to three
+    pub dns: &'static [[u8; 4]],
+}
+
+pub struct RouteSettings {
+    /// MBTA route ID, eg "87"
+    pub id: &'static str,
+    /// MBTA direction ID, 0 or 1, `None` for both
+    pub direction: Option<u8>,
+    /// MBTA stop ID, `None` for the sign's `stop`
+    pub stop: Option<&'static str>,
+    /// up to three characters shown in place of "BUS"
+    pub tag: Option<&'static str>,
+}
+
+/// Named set of routes shown between `from` and `to`, the first profile
+/// covering the current time wins
+pub struct ProfileSettings {
+    /// shown when the profile is switched on, up to 12 characters
+    pub name: &'static str,
+    pub from: Edge,
+    pub to: Edge,
+    pub routes: &'static [RouteSettings],
+}
+
+pub struct Colors {
+    pub route: Rgb888,
+    /// "BUS", "IN" and "MIN"
+    pub label: Rgb888,
+    /// minutes until the next bus
+    pub value: Rgb888,
+    pub error: Rgb888,
+}
+
+pub struct MbtaSettings {
+    pub mode: ApiMode,
+    pub api_host: &'static str,
+    /// host running `mbta-proxy.py` in proxy mode, empty to discover it
+    pub proxy_ip: &'static str,
+    /// `.local` name tried when nothing advertises the proxy service
+    pub proxy_hostname: &'static str,
+    /// comma separated hex SHA-256 hashes of the accepted server keys
+    pub tls_pins: &'static str,
+    pub forward_proxy: Option<ForwardProxy>,
+}
+
+pub struct MqttSettings {
+    pub host: &'static str,
+    pub port: u16,
+    /// empty for none
+    pub username: &'static str,
+    pub password: &'static str,
+    /// also the Home Assistant node ID
+    pub client_id: &'static str,
+    /// prefix of the sign's own topics, eg "bus-sign/brightness/set"
+    pub topic: &'static str,
+    pub discovery_prefix: &'static str,
+}
+
+pub struct OtaSettings {
+    /// ed25519 key that images must be signed with
+    pub public_key: [u8; 32],
+    /// seconds a new image has to prove itself before it is rolled back
+    pub confirm_within: u32,
+}
+
+pub struct SyslogSettings {
+    /// server the log is sent to from boot, empty for none until one is
+    /// set from the console
+    pub host: &'static str,
+    pub port: u16,
+    /// entries above it aren't sent
+    pub level: LevelFilter,
+    /// RFC 5424 HOSTNAME the sign sends as
+    pub hostname: &'static str,
+}
+
+include!(concat!(env!("OUT_DIR"), "/sign.rs"));
@@ -0,0 +1,327 @@
+//! IPv6 stateless address autoconfiguration (RFC 4862), which embassy-net
+//! doesn't do itself. Router advertisements are read from a raw ICMPv6
+//! socket: the first autonomous /64 prefix and the MAC address make the
+//! address, the router is the gateway and RDNSS options (RFC 8106) give the
+//! DNS servers.
+//!
+//! Routers are solicited every time the link comes up, and the address and
+//! gateway are dropped when their lifetimes run out or the link goes down.
+//! Duplicate address detection isn't done: the address is used as soon as
+//! it's formed, which only goes wrong if something else on the link has the
+//! sign's MAC address.
+
+use cyw43::NetDriver;
+use embassy_net::raw::{IpProtocol, IpVersion, PacketMetadata, RawSocket};
+use embassy_net::{ConfigV6, Ipv6Address, Ipv6Cidr, Stack, StaticConfigV6};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use heapless::Vec;
+use log::*;
+
+/// Ethernet address of the all-nodes group, ff02::1, that unsolicited
+/// advertisements are sent to
+pub const ALL_NODES_MAC: [u8; 6] = [0x33, 0x33, 0, 0, 0, 1];
+
+const ALL_ROUTERS: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+
+const IPV6_HEADER: usize = 40;
+const ICMPV6: u8 = 58;
+const ROUTER_SOLICITATION: u8 = 133;
+const ROUTER_ADVERTISEMENT: u8 = 134;
+
+const PREFIX_INFORMATION: u8 = 3;
+const RDNSS: u8 = 25;
+/// prefix flag, addresses may be formed from it
+const AUTONOMOUS: u8 = 0x40;
+
+/// Solicitations sent when the link comes up, and the time between them
+/// (RFC 4861 MAX_RTR_SOLICITATIONS and RTR_SOLICITATION_INTERVAL)
+const SOLICITATIONS: u8 = 3;
+const SOLICIT_INTERVAL: Duration = Duration::from_secs(4);
+/// Ask again after this long without an advertisement
+const RESOLICIT_AFTER: Duration = Duration::from_secs(600);
+/// How often the link state is checked
+const LINK_POLL: Duration = Duration::from_secs(1);
+/// Lifetime an unauthenticated advertisement can't cut a valid address's
+/// below (RFC 4862 section 5.5.3 e)
+const MIN_VALID_LIFETIME: Duration = Duration::from_secs(2 * 60 * 60);
+/// Prefix lifetime meaning forever
+const INFINITE: u32 = u32::MAX;
+
+/// Keep the IPv6 configuration up to date for as long as the sign runs
+pub async fn run(stack: &'static Stack<NetDriver<'static>>, mac: [u8; 6]) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; 64];
+    let socket = RawSocket::new(
+        stack,
+        IpVersion::Ipv6,
+        IpProtocol::Icmpv6,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    let interface_id = interface_id(mac);
+    let mut packet = [0; 1024];
+    loop {
+        while !stack.is_link_up() {
+            Timer::after(LINK_POLL).await;
+        }
+
+        // a burst of solicitations on every link-up, then one whenever the
+        // routers have been quiet for a while
+        let mut state = Autoconf::default();
+        let mut solicitations = 0;
+        let mut next_solicitation = Instant::now();
+        while stack.is_link_up() {
+            let now = Instant::now();
+            if now >= next_solicitation {
+                socket.send(&solicitation()).await;
+                solicitations += 1;
+                next_solicitation = now
+                    + if solicitations < SOLICITATIONS {
+                        SOLICIT_INTERVAL
+                    } else {
+                        RESOLICIT_AFTER
+                    };
+            }
+
+            let mut wait = next_solicitation.min(now + LINK_POLL);
+            if let Some(expiry) = state.next_expiry() {
+                wait = wait.min(expiry);
+            }
+            let received = with_timeout(
+                wait.saturating_duration_since(now),
+                socket.recv(&mut packet),
+            )
+            .await;
+
+            let now = Instant::now();
+            let mut changed = false;
+            if let Ok(Ok(len)) = received {
+                if let Some(advertisement) = parse_advertisement(&packet[..len], &interface_id) {
+                    // the burst is over once a router has answered
+                    solicitations = SOLICITATIONS;
+                    next_solicitation = now + RESOLICIT_AFTER;
+                    changed = state.update(&advertisement, now);
+                }
+            }
+            changed |= state.expire(now);
+            if changed {
+                state.apply(stack);
+            }
+        }
+
+        if state.address.is_some() {
+            info!("Wi-Fi link down, dropping the IPv6 address");
+        }
+        stack.set_config_v6(ConfigV6::None);
+    }
+}
+
+/// What the advertisements so far have configured, and until when
+#[derive(Default)]
+struct Autoconf {
+    /// address and when it expires, `None` for never
+    address: Option<(Ipv6Cidr, Option<Instant>)>,
+    gateway: Option<(Ipv6Address, Instant)>,
+    dns_servers: Vec<Ipv6Address, 3>,
+}
+
+impl Autoconf {
+    /// Take in an advertisement received at `now`, true if the
+    /// configuration changed
+    fn update(&mut self, advertisement: &Advertisement, now: Instant) -> bool {
+        let mut changed = false;
+        if let Some((address, valid_lifetime)) = advertisement.prefix {
+            let expiry = (valid_lifetime != INFINITE)
+                .then(|| now + Duration::from_secs(valid_lifetime.into()));
+            match &mut self.address {
+                Some((current, current_expiry)) if *current == address => {
+                    // an advertisement we can't authenticate may only cut
+                    // the lifetime left down to two hours
+                    let floor = match *current_expiry {
+                        Some(current) => current.min(now + MIN_VALID_LIFETIME),
+                        None => now + MIN_VALID_LIFETIME,
+                    };
+                    *current_expiry = expiry.map(|expiry| expiry.max(floor));
+                }
+                _ if valid_lifetime > 0 => {
+                    self.address = Some((address, expiry));
+                    changed = true;
+                }
+                _ => {}
+            }
+        }
+
+        let router = advertisement.router;
+        if advertisement.router_lifetime > 0 {
+            let expiry = now + Duration::from_secs(advertisement.router_lifetime.into());
+            changed |= self.gateway.map(|(gateway, _)| gateway) != Some(router);
+            self.gateway = Some((router, expiry));
+        } else if self.gateway.is_some_and(|(gateway, _)| gateway == router) {
+            self.gateway = None;
+            changed = true;
+        }
+
+        if !advertisement.dns_servers.is_empty() && advertisement.dns_servers != self.dns_servers {
+            self.dns_servers = advertisement.dns_servers.clone();
+            changed = true;
+        }
+        changed
+    }
+
+    /// Drop whatever has outlived its lifetime, true if anything did
+    fn expire(&mut self, now: Instant) -> bool {
+        let mut changed = false;
+        if self
+            .address
+            .is_some_and(|(_, expiry)| expiry.is_some_and(|expiry| expiry <= now))
+        {
+            info!("IPv6 address expired");
+            self.address = None;
+            changed = true;
+        }
+        if self.gateway.is_some_and(|(_, expiry)| expiry <= now) {
+            info!("IPv6 router expired");
+            self.gateway = None;
+            changed = true;
+        }
+        changed
+    }
+
+    /// When the address or the gateway next runs out
+    fn next_expiry(&self) -> Option<Instant> {
+        let address = self.address.and_then(|(_, expiry)| expiry);
+        let gateway = self.gateway.map(|(_, expiry)| expiry);
+        match (address, gateway) {
+            (Some(address), Some(gateway)) => Some(address.min(gateway)),
+            (address, gateway) => address.or(gateway),
+        }
+    }
+
+    fn apply(&self, stack: &Stack<NetDriver<'static>>) {
+        let gateway = self.gateway.map(|(gateway, _)| gateway);
+        match self.address {
+            Some((address, _)) => {
+                info!("IPv6 address {} via {:?}", address, gateway);
+                stack.set_config_v6(ConfigV6::Static(StaticConfigV6 {
+                    address,
+                    gateway,
+                    dns_servers: self.dns_servers.clone(),
+                }));
+            }
+            None => stack.set_config_v6(ConfigV6::None),
+        }
+    }
+}
+
+/// Modified EUI-64 interface identifier from the MAC address
+fn interface_id(mac: [u8; 6]) -> [u8; 8] {
+    [
+        mac[0] ^ 0x02,
+        mac[1],
+        mac[2],
+        0xff,
+        0xfe,
+        mac[3],
+        mac[4],
+        mac[5],
+    ]
+}
+
+/// Router solicitation to all routers from the unspecified address, so
+/// without options
+fn solicitation() -> [u8; IPV6_HEADER + 8] {
+    let mut packet = [0; IPV6_HEADER + 8];
+    packet[0] = 0x60; // version
+    packet[5] = 8; // payload length
+    packet[6] = ICMPV6;
+    packet[7] = 255; // hop limit
+    packet[24..40].copy_from_slice(&ALL_ROUTERS);
+    packet[IPV6_HEADER] = ROUTER_SOLICITATION;
+    let checksum = checksum(&packet);
+    packet[IPV6_HEADER + 2..IPV6_HEADER + 4].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+/// ICMPv6 checksum of an IPv6 packet without extension headers, over the
+/// pseudo-header and the message
+fn checksum(packet: &[u8]) -> u16 {
+    let message = &packet[IPV6_HEADER..];
+    let mut sum = ICMPV6 as u32 + message.len() as u32;
+    for chunk in packet[8..IPV6_HEADER].chunks(2).chain(message.chunks(2)) {
+        sum += u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The parts of a router advertisement the sign uses
+struct Advertisement {
+    router: Ipv6Address,
+    /// seconds, 0 if it isn't a default router
+    router_lifetime: u16,
+    /// address from the first autonomous /64 prefix and its valid lifetime
+    /// in seconds
+    prefix: Option<(Ipv6Cidr, u32)>,
+    dns_servers: Vec<Ipv6Address, 3>,
+}
+
+/// Router advertisement from `packet`, `None` for other packets
+fn parse_advertisement(packet: &[u8], interface_id: &[u8; 8]) -> Option<Advertisement> {
+    let message = packet.get(IPV6_HEADER..)?;
+    // only a router on the link can send one, with the hop limit untouched
+    if packet[0] >> 4 != 6
+        || packet[6] != ICMPV6
+        || packet[7] != 255
+        || message.len() < 16
+        || message[0] != ROUTER_ADVERTISEMENT
+    {
+        return None;
+    }
+    let router = Ipv6Address::from_bytes(&packet[8..24]);
+    let router_lifetime = u16::from_be_bytes([message[6], message[7]]);
+
+    let mut prefix = None;
+    let mut dns_servers = Vec::new();
+    let mut options = &message[16..];
+    while options.len() >= 8 {
+        let len = options[1] as usize * 8;
+        if len == 0 || len > options.len() {
+            break;
+        }
+        let option = &options[..len];
+        match option[0] {
+            PREFIX_INFORMATION if len == 32 && prefix.is_none() => {
+                let valid_lifetime =
+                    u32::from_be_bytes([option[4], option[5], option[6], option[7]]);
+                if option[2] == 64 && option[3] & AUTONOMOUS != 0 {
+                    let mut bytes = [0; 16];
+                    bytes[..8].copy_from_slice(&option[16..24]);
+                    bytes[8..].copy_from_slice(interface_id);
+                    let address = Ipv6Cidr::new(Ipv6Address::from_bytes(&bytes), 64);
+                    prefix = Some((address, valid_lifetime));
+                }
+            }
+            RDNSS => {
+                for server in option[8..].chunks_exact(16) {
+                    dns_servers.push(Ipv6Address::from_bytes(server)).ok();
+                }
+            }
+            _ => {}
+        }
+        options = &options[len..];
+    }
+
+    Some(Advertisement {
+        router,
+        router_lifetime,
+        prefix,
+        dns_servers,
+    })
+}
@@ -0,0 +1,81 @@
+//! Sunrise and sunset from the NOAA solar calculator equations,
+//! <https://gml.noaa.gov/grad/solcalc/calcdetails.html>. Uses `f64`, the
+//! Julian century doesn't fit in `f32` with useful precision.
+
+use libm::{acos, asin, cos, sin, tan};
+
+/// Sun below the horizon including refraction and the size of its disc
+const ZENITH: f64 = 90.833;
+
+fn radians(degrees: f64) -> f64 {
+    degrees * core::f64::consts::PI / 180.0
+}
+
+fn degrees(radians: f64) -> f64 {
+    radians * 180.0 / core::f64::consts::PI
+}
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar
+fn days_from_civil(year: i32, month: u32, day: u32) -> i32 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era =
+        year_of_era as u32 * 365 + year_of_era as u32 / 4 - year_of_era as u32 / 100 + day_of_year;
+    era * 146097 + day_of_era as i32 - 719468
+}
+
+/// Sunrise and sunset on a date at `latitude` and `longitude` (degrees,
+/// north and east positive), in minutes after midnight UTC. `None` when
+/// the sun doesn't rise or doesn't set that day.
+pub fn sunrise_sunset(
+    year: u16,
+    month: u8,
+    day: u8,
+    latitude: f64,
+    longitude: f64,
+) -> Option<(f64, f64)> {
+    // Julian century of noon UTC on the date, from J2000.0
+    let days = days_from_civil(year as i32, month as u32, day as u32) - days_from_civil(2000, 1, 1);
+    let century = days as f64 / 36525.0;
+
+    let mean_longitude = (280.46646 + century * (36000.76983 + century * 0.0003032)) % 360.0;
+    let mean_anomaly = 357.52911 + century * (35999.05029 - 0.0001537 * century);
+    let eccentricity = 0.016708634 - century * (0.000042037 + 0.0000001267 * century);
+
+    let anomaly = radians(mean_anomaly);
+    let center = sin(anomaly) * (1.914602 - century * (0.004817 + 0.000014 * century))
+        + sin(2.0 * anomaly) * (0.019993 - 0.000101 * century)
+        + sin(3.0 * anomaly) * 0.000289;
+    let omega = radians(125.04 - 1934.136 * century);
+    let apparent_longitude = mean_longitude + center - 0.00569 - 0.00478 * sin(omega);
+
+    let mean_obliquity = 23.0
+        + (26.0 + (21.448 - century * (46.815 + century * (0.00059 - century * 0.001813))) / 60.0)
+            / 60.0;
+    let obliquity = radians(mean_obliquity + 0.00256 * cos(omega));
+    let declination = asin(sin(obliquity) * sin(radians(apparent_longitude)));
+
+    let y = tan(obliquity / 2.0) * tan(obliquity / 2.0);
+    let l0 = radians(mean_longitude);
+    let equation_of_time = 4.0
+        * degrees(
+            y * sin(2.0 * l0) - 2.0 * eccentricity * sin(anomaly)
+                + 4.0 * eccentricity * y * sin(anomaly) * cos(2.0 * l0)
+                - 0.5 * y * y * sin(4.0 * l0)
+                - 1.25 * eccentricity * eccentricity * sin(2.0 * anomaly),
+        );
+
+    let latitude = radians(latitude);
+    let cos_hour_angle = cos(radians(ZENITH)) / (cos(latitude) * cos(declination))
+        - tan(latitude) * tan(declination);
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = degrees(acos(cos_hour_angle));
+
+    let solar_noon = 720.0 - 4.0 * longitude - equation_of_time;
+    Some((solar_noon - 4.0 * hour_angle, solar_noon + 4.0 * hour_angle))
+}
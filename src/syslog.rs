@@ -0,0 +1,308 @@
+//! Sends the log to a syslog server over UDP in the RFC 5424 format, so
+//! there's a log to read once the sign is on the wall. `logger` queues
+//! entries as they're made, and `run` sends them whenever the network is up.
+//! The queue is bounded, under pressure the oldest entries are dropped and
+//! counted in a warning sent after the rest.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use cyw43::NetDriver;
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Stack};
+use embassy_sync::blocking_mutex;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{Deque, String};
+use log::{Level, LevelFilter, Record};
+
+use crate::rtc;
+use crate::sign::CONFIG;
+use crate::timestamp::Timestamp;
+use crate::wifi;
+
+/// Longest server name
+pub const HOST_SIZE: usize = 64;
+
+/// Entries kept while they wait to be sent
+const QUEUE_SIZE: usize = 32;
+
+/// Longest message, the rest is cut off
+const MESSAGE_SIZE: usize = 192;
+
+/// Longest MSGID allowed by RFC 5424, the module that logged the entry
+const MODULE_SIZE: usize = 32;
+
+/// Room for the header and the message
+const PACKET_SIZE: usize = 320;
+
+/// Wait before looking up a server that couldn't be found again
+const RESOLVE_RETRY: Duration = Duration::from_secs(30);
+
+/// Facility of every entry, local0
+const FACILITY: u8 = 16;
+
+/// RFC 5424 APP-NAME
+const APP_NAME: &str = "bus-sign";
+
+/// Where entries are sent
+#[derive(Clone, PartialEq)]
+pub struct Target {
+    pub host: String<HOST_SIZE>,
+    pub port: u16,
+}
+
+impl Target {
+    /// `None` when `host` is too long
+    pub fn new(host: &str, port: u16) -> Option<Self> {
+        Some(Target {
+            host: String::try_from(host).ok()?,
+            port,
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+struct Entry {
+    level: Level,
+    /// when it was logged, the clock may not be set yet
+    at: Instant,
+    module: String<MODULE_SIZE>,
+    message: String<MESSAGE_SIZE>,
+}
+
+struct Queue {
+    entries: Deque<Entry, QUEUE_SIZE>,
+    /// entries pushed out since the last were sent
+    dropped: u32,
+}
+
+/// Filled from wherever something logs, so behind a critical section
+static QUEUE: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<Queue>> =
+    blocking_mutex::Mutex::new(RefCell::new(Queue {
+        entries: Deque::new(),
+        dropped: 0,
+    }));
+
+static TARGET: Mutex<ThreadModeRawMutex, Option<Target>> = Mutex::new(None);
+
+/// A target is set, checked before queueing so nothing piles up without one
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// `LevelFilter` as a number, entries above it aren't sent
+static LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+
+/// Entries were queued or the target changed
+static WAKE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Start with the target and level from `sign.toml`
+pub async fn init() {
+    let settings = &CONFIG.syslog;
+    set_level(settings.level);
+    if !settings.host.is_empty() {
+        set_target(Target::new(settings.host, settings.port)).await;
+    }
+}
+
+/// Send entries to `target` from now on, or stop sending them with `None`
+pub async fn set_target(target: Option<Target>) {
+    ENABLED.store(target.is_some(), Ordering::Relaxed);
+    if target.is_none() {
+        QUEUE.lock(|queue| {
+            let mut queue = queue.borrow_mut();
+            queue.entries.clear();
+            queue.dropped = 0;
+        });
+    }
+    *(TARGET.lock().await) = target;
+    WAKE.signal(());
+}
+
+pub async fn target() -> Option<Target> {
+    TARGET.lock().await.clone()
+}
+
+/// Send only entries at `level` and below. The log's own max level still
+/// applies, so this can only make syslog quieter than the USB log.
+pub fn set_level(level: LevelFilter) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+pub fn level() -> LevelFilter {
+    let level = LEVEL.load(Ordering::Relaxed);
+    LevelFilter::iter().nth(level).unwrap_or(LevelFilter::Info)
+}
+
+/// Queue `record` to be sent, called by the logger
+pub fn push(record: &Record) {
+    if !ENABLED.load(Ordering::Relaxed) || record.level() > level() {
+        return;
+    }
+    let mut entry = Entry {
+        level: record.level(),
+        at: Instant::now(),
+        module: String::new(),
+        message: String::new(),
+    };
+    // both are cut short when they don't fit
+    write!(Truncate(&mut entry.module), "{}", record.target()).ok();
+    write!(Truncate(&mut entry.message), "{}", record.args()).ok();
+
+    QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+        if queue.entries.is_full() {
+            queue.entries.pop_front();
+            queue.dropped += 1;
+        }
+        queue.entries.push_back(entry).ok();
+    });
+    WAKE.signal(());
+}
+
+/// The oldest entry, and how many were dropped since the last one taken
+fn pop() -> (Option<Entry>, u32) {
+    QUEUE.lock(|queue| {
+        let mut queue = queue.borrow_mut();
+        let dropped = core::mem::take(&mut queue.dropped);
+        (queue.entries.pop_front(), dropped)
+    })
+}
+
+/// Send queued entries to the target as they come. Nothing here logs, that
+/// would only queue more.
+pub async fn run(stack: &'static Stack<NetDriver<'static>>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; PACKET_SIZE * 4];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(0).unwrap();
+
+    // the target last resolved, and its address
+    let mut resolved: Option<(Target, IpEndpoint)> = None;
+    let mut packet = String::<PACKET_SIZE>::new();
+    loop {
+        WAKE.wait().await;
+        let Some(target) = target().await else {
+            resolved = None;
+            continue;
+        };
+        wifi::wait_ipv4_up(stack).await;
+
+        let endpoint = match &resolved {
+            Some((last, endpoint)) if *last == target => *endpoint,
+            _ => match resolve(stack, &target).await {
+                Some(endpoint) => {
+                    resolved = Some((target, endpoint));
+                    endpoint
+                }
+                // try again with the next entry, but not right away
+                None => {
+                    Timer::after(RESOLVE_RETRY).await;
+                    continue;
+                }
+            },
+        };
+
+        loop {
+            let (entry, dropped) = pop();
+            let now = rtc::try_now().await;
+            if dropped > 0 {
+                packet.clear();
+                write_header(&mut packet, Level::Warn, now, "syslog").ok();
+                write!(packet, "{} log entries dropped", dropped).ok();
+                socket.send_to(packet.as_bytes(), endpoint).await.ok();
+            }
+            let Some(entry) = entry else {
+                break;
+            };
+            let at = now.map(|now| now.earlier(entry.at.elapsed().as_secs() as u32));
+            packet.clear();
+            write_header(&mut packet, entry.level, at, &entry.module).ok();
+            write!(Truncate(&mut packet), "{}", entry.message).ok();
+            socket.send_to(packet.as_bytes(), endpoint).await.ok();
+        }
+    }
+}
+
+async fn resolve(stack: &'static Stack<NetDriver<'static>>, target: &Target) -> Option<IpEndpoint> {
+    let query = if target.host.contains(':') {
+        DnsQueryType::Aaaa
+    } else {
+        DnsQueryType::A
+    };
+    let addresses = stack.dns_query(&target.host, query).await.ok()?;
+    let address: IpAddress = *addresses.first()?;
+    Some(IpEndpoint::new(address, target.port))
+}
+
+/// `<PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID SD `, with the
+/// local time and its offset from UTC or "-" before the clock is set
+fn write_header(
+    out: &mut impl Write,
+    level: Level,
+    at: Option<Timestamp>,
+    module: &str,
+) -> fmt::Result {
+    let severity = match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    write!(out, "<{}>1 ", FACILITY * 8 + severity)?;
+    match at {
+        Some(at) => {
+            let offset = rtc::utc_offset();
+            write!(
+                out,
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02} ",
+                at.year,
+                at.month,
+                at.day,
+                at.hour,
+                at.minute,
+                at.second,
+                if offset < 0 { '-' } else { '+' },
+                offset.abs() / 60,
+                offset.abs() % 60
+            )?;
+        }
+        None => out.write_str("- ")?,
+    }
+    write!(
+        out,
+        "{} {} - {} - ",
+        CONFIG.syslog.hostname, APP_NAME, module
+    )
+}
+
+/// Keeps as much of the text as fits
+struct Truncate<'a, const N: usize>(&'a mut String<N>);
+
+impl<const N: usize> Write for Truncate<'_, N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.0.push(c).map_err(|()| fmt::Error)?;
+        }
+        Ok(())
+    }
+}
@@ -59,6 +59,56 @@ impl Timestamp {
         })
     }
 
+    /// Day of the week, 0 for Monday through 6 for Sunday
+    pub fn weekday(&self) -> u8 {
+        // Sakamoto's method, which counts from Sunday
+        const OFFSETS: [u16; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let year = if self.month < 3 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let sunday_based = (year + year / 4 - year / 100
+            + year / 400
+            + OFFSETS[(self.month as usize + 11) % 12]
+            + self.day as u16)
+            % 7;
+        ((sunday_based + 6) % 7) as u8
+    }
+
+    /// The time `seconds` before this one
+    pub fn earlier(&self, seconds: u32) -> Timestamp {
+        // days since 1970-01-01 and back, from Howard Hinnant's algorithms
+        let year = self.year as u32 - (self.month <= 2) as u32;
+        let (era, year_of_era) = (year / 400, year % 400);
+        let shifted_month = (self.month as u32 + 9) % 12;
+        let day_of_year = (153 * shifted_month + 2) / 5 + self.day as u32 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+        let secs = (days as u64 * 86400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64)
+            .saturating_sub(seconds as u64);
+
+        let days = (secs / 86400) as u32 + 719468;
+        let time = (secs % 86400) as u32;
+        let (era, day_of_era) = (days / 146097, days % 146097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let month = (shifted_month + 2) % 12 + 1;
+        Timestamp {
+            year: (era * 400 + year_of_era + (month <= 2) as u32) as u16,
+            month: month as u8,
+            day: (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
     fn as_secs(&self) -> u64 {
         self.year as u64 * 31536000
             + (match self.month as u64 {
@@ -0,0 +1,128 @@
+use embassy_executor::Spawner;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::{Builder, UsbDevice};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use static_cell::StaticCell;
+
+use crate::{logger, Irqs};
+
+/// Full speed bulk endpoints carry 64 bytes per packet
+const PACKET_SIZE: usize = 64;
+
+#[embassy_executor::task]
+async fn usb_task(mut device: UsbDevice<'static, Driver<'static, USB>>) -> ! {
+    device.run().await
+}
+
+#[embassy_executor::task]
+async fn usb_logger_task(class: CdcAcmClass<'static, Driver<'static, USB>>) {
+    logger::USB.create_future_from_class(class).await
+}
+
+/// Start a composite USB device with two serial ports, the first carries
+/// the log and the second is returned for the console
+pub fn start_usb(spawner: Spawner, usb: USB) -> UsbSerial {
+    logger::init(log::LevelFilter::Info);
+    let driver = Driver::new(usb, Irqs);
+
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("mogenson");
+    config.product = Some("bus-sign");
+    config.max_power = 100;
+    config.max_packet_size_0 = PACKET_SIZE as u8;
+    // two CDC functions need interface association descriptors
+    config.device_class = 0xef;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.composite_with_iads = true;
+
+    static DEVICE_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    let mut builder = Builder::new(
+        driver,
+        config,
+        DEVICE_DESCRIPTOR.init([0; 256]),
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [], // no msos descriptors
+        CONTROL_BUF.init([0; 64]),
+    );
+
+    static LOGGER_STATE: StaticCell<State> = StaticCell::new();
+    static CONSOLE_STATE: StaticCell<State> = StaticCell::new();
+    let logger = CdcAcmClass::new(
+        &mut builder,
+        LOGGER_STATE.init(State::new()),
+        PACKET_SIZE as u16,
+    );
+    let console = CdcAcmClass::new(
+        &mut builder,
+        CONSOLE_STATE.init(State::new()),
+        PACKET_SIZE as u16,
+    );
+
+    spawner.spawn(usb_task(builder.build())).unwrap();
+    spawner.spawn(usb_logger_task(logger)).unwrap();
+
+    UsbSerial {
+        class: console,
+        packet: [0; PACKET_SIZE],
+        start: 0,
+        end: 0,
+    }
+}
+
+/// Byte stream over a CDC-ACM port, for `console::run`
+pub struct UsbSerial {
+    class: CdcAcmClass<'static, Driver<'static, USB>>,
+    /// received packet not yet read
+    packet: [u8; PACKET_SIZE],
+    start: usize,
+    end: usize,
+}
+
+impl UsbSerial {
+    /// Wait for a terminal to open the port
+    pub async fn wait_connection(&mut self) {
+        self.class.wait_connection().await;
+        self.start = 0;
+        self.end = 0;
+    }
+}
+
+impl ErrorType for UsbSerial {
+    type Error = ErrorKind;
+}
+
+impl Read for UsbSerial {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        // skip zero length packets, an empty read means end of stream
+        while self.start == self.end {
+            self.end = self
+                .class
+                .read_packet(&mut self.packet)
+                .await
+                .map_err(|_| ErrorKind::NotConnected)?;
+            self.start = 0;
+        }
+        let len = buf.len().min(self.end - self.start);
+        buf[..len].copy_from_slice(&self.packet[self.start..self.start + len]);
+        self.start += len;
+        Ok(len)
+    }
+}
+
+impl Write for UsbSerial {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let len = buf.len().min(PACKET_SIZE);
+        self.class
+            .write_packet(&buf[..len])
+            .await
+            .map_err(|_| ErrorKind::NotConnected)?;
+        Ok(len)
+    }
+}
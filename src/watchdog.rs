@@ -0,0 +1,128 @@
+//! Restarts the sign when a task stops making progress. Tasks call `beat`
+//! as they go round their loops, and `run` only feeds the RP2040's watchdog
+//! while every task that has beaten once did so again within its deadline.
+//! The task that missed is written to a watchdog scratch register, which
+//! survives the reset, for `init` to report on the way back up.
+
+use core::future::pending;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use embassy_rp::watchdog::Watchdog;
+use embassy_time::{Duration, Instant, Timer};
+use log::*;
+
+/// The hardware resets the sign this long after the last feed, close to
+/// the RP2040's longest
+pub const TIMEOUT: Duration = Duration::from_secs(8);
+
+/// How often tasks are checked on and the watchdog fed
+const CHECK: Duration = Duration::from_secs(1);
+
+/// Scratch register holding `MISSED` and the task
+const SCRATCH: usize = 0;
+
+/// Marks `SCRATCH` as written by `run`, with the task in the low byte
+const MISSED: u32 = 0x4842_0000;
+
+/// Tasks that check in with `beat`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Task {
+    Display,
+    NextBus,
+}
+
+impl Task {
+    const ALL: [Task; 2] = [Task::Display, Task::NextBus];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Task::Display => "display",
+            Task::NextBus => "next bus",
+        }
+    }
+
+    /// Longest the task may go between beats
+    fn deadline(self) -> Duration {
+        match self {
+            // wakes at least every page turn or idle check
+            Task::Display => Duration::from_secs(30),
+            // beats between stops, so this covers one slow fetch
+            Task::NextBus => Duration::from_secs(180),
+        }
+    }
+}
+
+/// Uptime in seconds of each task's last beat plus one, 0 before the first
+static LAST_BEAT: [AtomicU32; Task::ALL.len()] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+/// Index of the task that missed before the last restart plus one, 0 for
+/// none
+static LAST_MISSED: AtomicU8 = AtomicU8::new(0);
+
+/// Check in for `task`, which is watched from its first beat on
+pub fn beat(task: Task) {
+    LAST_BEAT[task as usize].store(Instant::now().as_secs() as u32 + 1, Ordering::Relaxed);
+}
+
+/// A task that went past its deadline
+fn overdue() -> Option<Task> {
+    let now = Instant::now().as_secs();
+    Task::ALL.into_iter().find(|task| {
+        let last = LAST_BEAT[*task as usize].load(Ordering::Relaxed);
+        last != 0 && now + 1 - last as u64 > task.deadline().as_secs()
+    })
+}
+
+/// Report and forget the task that made the watchdog restart the sign, if
+/// that's why it restarted
+pub fn init(watchdog: &mut Watchdog) {
+    let scratch = watchdog.get_scratch(SCRATCH);
+    watchdog.set_scratch(SCRATCH, 0);
+    if scratch & 0xffff_ff00 != MISSED {
+        return;
+    }
+    if let Some(task) = Task::ALL.get((scratch & 0xff) as usize) {
+        error!(
+            "Restarted by the watchdog, {} task missed its heartbeat",
+            task.name()
+        );
+        LAST_MISSED.store(*task as u8 + 1, Ordering::Relaxed);
+    }
+}
+
+/// The task that made the watchdog restart the sign last time
+pub fn last_missed() -> Option<Task> {
+    let index = LAST_MISSED.load(Ordering::Relaxed).checked_sub(1)?;
+    Task::ALL.get(index as usize).copied()
+}
+
+/// Wait out `period`, and then for every task to have checked in since the
+/// wait began, so a task that only ran at startup doesn't pass for a
+/// healthy one. A task missing its deadline meanwhile restarts the sign
+/// through `run` instead.
+pub async fn settled(period: Duration) {
+    let since = Instant::now().as_secs() as u32 + 1;
+    Timer::after(period).await;
+    while Task::ALL
+        .iter()
+        .any(|task| LAST_BEAT[*task as usize].load(Ordering::Relaxed) < since)
+    {
+        Timer::after(CHECK).await;
+    }
+}
+
+/// Start the watchdog and feed it until a task misses its deadline
+pub async fn run(mut watchdog: Watchdog) -> ! {
+    // hold off while a debugger has the core stopped
+    watchdog.pause_on_debug(true);
+    watchdog.start(TIMEOUT);
+    loop {
+        if let Some(task) = overdue() {
+            watchdog.set_scratch(SCRATCH, MISSED | task as u32);
+            error!("{} task missed its heartbeat, restarting", task.name());
+            // stop feeding, the watchdog restarts the sign
+            pending::<()>().await;
+        }
+        watchdog.feed();
+        Timer::after(CHECK).await;
+    }
+}
@@ -0,0 +1,255 @@
+use cyw43::{Control, NetDriver};
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{with_timeout, Duration, Timer};
+use log::*;
+
+use crate::config::Config;
+use crate::metrics;
+use crate::sign::CONFIG;
+
+/// The radio, shared by the tasks that scan, join and blink its LED
+pub type SharedControl = Mutex<ThreadModeRawMutex, Control<'static>>;
+
+/// Most networks listed in `sign.toml`
+pub const MAX_NETWORKS: usize = 8;
+
+/// Join attempts on one network before failing over to the next
+const JOIN_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Security {
+    Open,
+    Wpa2,
+    Wpa3,
+}
+
+/// Network the sign knows how to join
+#[derive(Copy, Clone)]
+pub struct Network<'a> {
+    pub ssid: &'a str,
+    pub password: &'a str,
+    pub security: Security,
+    /// higher is tried first, the strongest signal breaks ties
+    pub priority: u8,
+}
+
+/// What is known about the connection, for the console
+#[derive(Clone)]
+pub struct LinkStatus {
+    /// network last joined
+    pub ssid: heapless::String<32>,
+    /// its signal strength in dBm at the last scan, `None` if the scan
+    /// didn't see it
+    pub rssi: Option<i16>,
+    /// times the link was lost since boot
+    pub disconnects: u32,
+}
+
+static STATUS: Mutex<ThreadModeRawMutex, LinkStatus> = Mutex::new(LinkStatus {
+    ssid: heapless::String::new(),
+    rssi: None,
+    disconnects: 0,
+});
+
+pub async fn link_status() -> LinkStatus {
+    STATUS.lock().await.clone()
+}
+
+/// Count a lost link, returns the count so far
+pub async fn record_disconnect() -> u32 {
+    let mut status = STATUS.lock().await;
+    status.disconnects += 1;
+    metrics::WIFI_RECONNECTS.inc();
+    status.disconnects
+}
+
+/// An IPv4 address is configured, from DHCP or `sign.toml`. Unlike
+/// `Stack::is_config_up` this ignores IPv6, which can come from a router
+/// advertisement without a lease.
+pub fn ipv4_up(stack: &Stack<NetDriver<'static>>) -> bool {
+    stack.config_v4().is_some()
+}
+
+pub async fn wait_ipv4_up(stack: &Stack<NetDriver<'static>>) {
+    while !ipv4_up(stack) {
+        Timer::after_millis(100).await;
+    }
+}
+
+/// The stored network and the ones from `sign.toml`, without duplicates
+fn known_networks(config: &Config) -> heapless::Vec<Network<'_>, { MAX_NETWORKS + 1 }> {
+    let mut networks = heapless::Vec::new();
+    if !config.wifi_ssid.is_empty() {
+        let security = if config.wifi_password.is_empty() {
+            Security::Open
+        } else {
+            Security::Wpa2
+        };
+        networks
+            .push(Network {
+                ssid: &config.wifi_ssid,
+                password: &config.wifi_password,
+                security,
+                priority: 0,
+            })
+            .ok();
+    }
+    for network in CONFIG.wifi.networks {
+        if networks.iter().all(|known| known.ssid != network.ssid) {
+            networks.push(*network).ok();
+        }
+    }
+    networks
+}
+
+/// Whether there is any network to join, stored or from `sign.toml`
+pub fn has_networks(config: &Config) -> bool {
+    !known_networks(config).is_empty()
+}
+
+/// Known networks in the order to try them, with their signal strength:
+/// those seen by a scan by priority and then signal strength, or all of
+/// them by priority when the scan finds none, in case they are hidden
+async fn candidates<'a>(
+    control: &SharedControl,
+    known: &[Network<'a>],
+) -> heapless::Vec<(Network<'a>, i16), { MAX_NETWORKS + 1 }> {
+    let mut seen: heapless::Vec<(Network, i16), { MAX_NETWORKS + 1 }> = heapless::Vec::new();
+    {
+        let mut control = control.lock().await;
+        let mut scanner = control.scan(Default::default()).await;
+        while let Some(bss) = scanner.next().await {
+            let Ok(ssid) = core::str::from_utf8(&bss.ssid[..bss.ssid_len as usize]) else {
+                continue;
+            };
+            let Some(network) = known.iter().find(|network| network.ssid == ssid) else {
+                continue;
+            };
+            match seen.iter_mut().find(|(other, _)| other.ssid == ssid) {
+                Some((_, rssi)) => *rssi = (*rssi).max(bss.rssi),
+                None => {
+                    info!("found {} at {} dBm", ssid, bss.rssi);
+                    seen.push((*network, bss.rssi)).ok();
+                }
+            }
+        }
+    }
+
+    if seen.is_empty() {
+        info!("no known network found by scan, trying them all");
+        seen = known.iter().map(|network| (*network, i16::MIN)).collect();
+    }
+    seen.sort_unstable_by(|(a, a_rssi), (b, b_rssi)| {
+        b.priority.cmp(&a.priority).then(b_rssi.cmp(a_rssi))
+    });
+    seen
+}
+
+/// Scan for the joined network and record the signal strength of its
+/// strongest access point, for the console and `/metrics`
+pub async fn refresh_rssi(control: &SharedControl) {
+    let ssid = STATUS.lock().await.ssid.clone();
+    let mut strongest = None;
+    {
+        let mut control = control.lock().await;
+        let mut scanner = control.scan(Default::default()).await;
+        while let Some(bss) = scanner.next().await {
+            if bss.ssid[..bss.ssid_len as usize] == *ssid.as_bytes() {
+                strongest = strongest.max(Some(bss.rssi));
+            }
+        }
+    }
+
+    STATUS.lock().await.rssi = strongest;
+    match strongest {
+        Some(rssi) => metrics::WIFI_RSSI.set(rssi as i32),
+        None => debug!("{} not seen by the signal scan", ssid),
+    }
+}
+
+async fn join(control: &SharedControl, network: &Network<'_>) -> Result<(), u32> {
+    let mut control = control.lock().await;
+    let result = match network.security {
+        Security::Open => control.join_open(network.ssid).await,
+        Security::Wpa2 => control.join_wpa2(network.ssid, network.password).await,
+        Security::Wpa3 => control.join_wpa3(network.ssid, network.password).await,
+    };
+    result.map_err(|err| err.status)
+}
+
+/// Join the best known network in range and wait for DHCP, failing over to
+/// the next one after repeated failures. Returns false if there are no
+/// known networks or none can be joined. `control` is only locked for each
+/// scan or join, not while waiting between them.
+pub async fn join_wifi(
+    stack: &'static Stack<NetDriver<'static>>,
+    control: &SharedControl,
+    config: &Config,
+) -> bool {
+    let known = known_networks(config);
+    if known.is_empty() {
+        info!("no wifi credentials stored");
+        return false;
+    }
+
+    for (network, rssi) in candidates(control, &known).await {
+        if join_network(stack, control, &network).await {
+            STATUS.lock().await.rssi = (rssi != i16::MIN).then_some(rssi);
+            if rssi != i16::MIN {
+                metrics::WIFI_RSSI.set(rssi as i32);
+            }
+            return true;
+        }
+        info!("giving up on {}", network.ssid);
+    }
+    false
+}
+
+async fn join_network(
+    stack: &'static Stack<NetDriver<'static>>,
+    control: &SharedControl,
+    network: &Network<'_>,
+) -> bool {
+    let mut joined = false;
+    for attempt in 1..=JOIN_ATTEMPTS {
+        match join(control, network).await {
+            Ok(()) => {
+                info!("connected to {} ({:?})", network.ssid, network.security);
+                joined = true;
+                break;
+            }
+            Err(status) => {
+                info!(
+                    "join attempt {} on {} failed with status={}",
+                    attempt, network.ssid, status
+                );
+                Timer::after(Duration::from_secs(10)).await;
+            }
+        }
+    }
+    if !joined {
+        return false;
+    }
+
+    info!("waiting for DHCP...");
+    if with_timeout(Duration::from_secs(30), wait_ipv4_up(stack))
+        .await
+        .is_err()
+    {
+        info!("no DHCP lease from {}", network.ssid);
+        control.lock().await.leave().await;
+        return false;
+    }
+    info!("DHCP is now up!");
+
+    info!("waiting for link up...");
+    while !stack.is_link_up() {
+        Timer::after_millis(500).await;
+    }
+    info!("Link is up!");
+
+    STATUS.lock().await.ssid = heapless::String::try_from(network.ssid).unwrap_or_default();
+    true
+}